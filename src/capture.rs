@@ -0,0 +1,1038 @@
+//! Sources the frames are captured from: monitors, windows, the screen cast portal and the test
+//! pattern, and the thread that captures them ahead of their use
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ::x11::xlib;
+use log::{debug, info, warn};
+use x11cap::{CaptureError, CaptureSource, Capturer, Image};
+
+use crate::config::{
+    copy_region, CaptureTarget, Rect, Settings, SourceLostAction, CAPTURED_PACKING,
+};
+use crate::desktop::screen_size;
+use crate::pacing::FramePacer;
+
+/// Number of frames in a row that may fail to be captured before we give up
+///
+/// Single frames regularly fail to be captured, e.g. while switching virtual desktops or when the
+/// screen gets locked. These failures are not worth stopping the mirroring for.
+pub const MAX_CONSECUTIVE_CAPTURE_FAILURES: u32 = 30;
+
+/// How long to wait between the tries to open a lost capture source again
+const SOURCE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reasons why capturing a frame failed
+#[derive(Debug)]
+pub enum FrameError {
+    /// Capturing failed, but might work again for the next frame
+    Failed(&'static str),
+    /// The captured window does not exist anymore
+    SourceClosed,
+}
+
+/// Captures the contents of a single X11 window
+///
+/// x11cap can only capture regions of the root window, which would include any windows
+/// covering the one we are interested in. Capturing the window itself avoids that.
+struct WindowCapturer {
+    display: *mut xlib::Display,
+    window: xlib::Window,
+    region: Rect,
+}
+
+impl WindowCapturer {
+    pub fn new(window: xlib::Window) -> Result<WindowCapturer, Box<dyn Error>> {
+        unsafe {
+            let display = xlib::XOpenDisplay(ptr::null());
+            if display.is_null() {
+                return Err("Unable to connect to the X server".into());
+            }
+
+            let mut attributes = mem::zeroed();
+            if xlib::XGetWindowAttributes(display, window, &mut attributes) == 0 {
+                xlib::XCloseDisplay(display);
+                return Err(format!("Window 0x{:x} does not exist", window).into());
+            }
+
+            Ok(WindowCapturer {
+                display,
+                window,
+                region: Rect {
+                    x: 0,
+                    y: 0,
+                    width: attributes.width as u32,
+                    height: attributes.height as u32,
+                },
+            })
+        }
+    }
+
+    /// Check whether the window still exists
+    pub fn exists(&self) -> bool {
+        unsafe {
+            let mut attributes = mem::zeroed();
+            xlib::XGetWindowAttributes(self.display, self.window, &mut attributes) != 0
+        }
+    }
+}
+
+impl FrameSource for WindowCapturer {
+    fn size(&self) -> (u32, u32) {
+        (self.region.width, self.region.height)
+    }
+
+    fn crop(&mut self, crop: Rect) -> Result<(), Box<dyn Error>> {
+        self.region = Rect {
+            x: self.region.x + crop.x,
+            y: self.region.y + crop.y,
+            ..crop
+        };
+        Ok(())
+    }
+
+    /// Windows can be moved, so their position is looked up every time
+    fn origin(&self) -> Option<(i32, i32)> {
+        unsafe {
+            let (mut x, mut y, mut child) = (0, 0, 0);
+            let translated = xlib::XTranslateCoordinates(
+                self.display,
+                self.window,
+                xlib::XDefaultRootWindow(self.display),
+                self.region.x as i32,
+                self.region.y as i32,
+                &mut x,
+                &mut y,
+                &mut child,
+            );
+            if translated == 0 {
+                None
+            } else {
+                Some((x, y))
+            }
+        }
+    }
+
+    fn capture_frame(&mut self) -> Result<Frame, FrameError> {
+        let image = unsafe {
+            xlib::XGetImage(
+                self.display,
+                self.window,
+                self.region.x as i32,
+                self.region.y as i32,
+                self.region.width,
+                self.region.height,
+                xlib::XAllPlanes(),
+                xlib::ZPixmap,
+            )
+        };
+
+        if image.is_null() {
+            return Err(if self.exists() {
+                FrameError::Failed("XGetImage returned null pointer")
+            } else {
+                FrameError::SourceClosed
+            });
+        }
+
+        // Make sure the layout matches what we get from x11cap
+        unsafe {
+            let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel() as i32;
+            if (*image).bits_per_pixel != bytes_per_pixel * 8
+                || (*image).bytes_per_line != (*image).width * bytes_per_pixel
+            {
+                xlib::XDestroyImage(image);
+                return Err(FrameError::Failed("Wrong layout"));
+            }
+        }
+
+        Ok(Frame::Window(WindowImage { image }))
+    }
+}
+
+impl Drop for WindowCapturer {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
+
+/// An image captured by a `WindowCapturer`
+pub struct WindowImage {
+    image: *mut xlib::XImage,
+}
+
+impl Drop for WindowImage {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XDestroyImage(self.image);
+        }
+    }
+}
+
+/// Something the mirrored image can be captured from
+///
+/// On X11 everything is captured directly, on Wayland only the screen cast portal can capture.
+pub trait FrameSource {
+    /// Size of the captured frames
+    fn size(&self) -> (u32, u32);
+
+    /// Only capture the given region, relative to the area captured so far
+    fn crop(&mut self, crop: Rect) -> Result<(), Box<dyn Error>>;
+
+    /// Position of the top left corner of the captured area on the root window
+    fn origin(&self) -> Option<(i32, i32)>;
+
+    /// The frame to show next, nothing if no new frame is ready yet
+    ///
+    /// Capturing happens right away, unless the source captures on a thread of its own.
+    fn latest_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        Some(self.capture_frame())
+    }
+
+    /// Change the frame rate of sources that capture at a pace of their own
+    fn set_target_fps(&mut self, _target_fps: u32) {}
+
+    /// Regions of the captured frames that show the individual monitors
+    fn monitor_regions(&self) -> Vec<Rect> {
+        let (width, height) = self.size();
+        vec![Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }]
+    }
+
+    fn capture_frame(&mut self) -> Result<Frame, FrameError>;
+}
+
+/// Open the source for the capture target
+pub fn open_source(target: CaptureTarget) -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+    match target {
+        CaptureTarget::Monitor(monitor_id) => {
+            let capturer = Capturer::new(CaptureSource::Monitor(monitor_id))
+                .map_err(|_| "Unable to create screen capturer")?;
+            Ok(Box::new(capturer))
+        }
+        CaptureTarget::Monitors(monitor_ids) => {
+            let sources = monitor_ids
+                .into_iter()
+                .map(|monitor_id| open_source(CaptureTarget::Monitor(monitor_id)))
+                .collect::<Result<_, _>>()?;
+            Ok(Box::new(CompositeSource::new(sources)))
+        }
+        CaptureTarget::Window(window) => Ok(Box::new(WindowCapturer::new(window)?)),
+        CaptureTarget::TestPattern => Ok(Box::new(TestPattern::new())),
+        #[cfg(feature = "wayland")]
+        CaptureTarget::Portal => Ok(Box::new(crate::portal::open()?)),
+        #[cfg(not(feature = "wayland"))]
+        CaptureTarget::Portal => {
+            Err("Capturing through the screen cast portal needs the wayland feature".into())
+        }
+        CaptureTarget::Screen => {
+            let (width, height) = screen_size()?;
+            let capturer = Capturer::new(CaptureSource::Region {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            })
+            .map_err(|_| "Unable to create screen capturer")?;
+            Ok(Box::new(capturer))
+        }
+    }
+}
+
+/// Combines the frames of several sources into one, placed side by side from left to right
+///
+/// Sources that are less high than the highest one are padded with black at the bottom.
+struct CompositeSource {
+    sources: Vec<Box<dyn FrameSource>>,
+    /// Size of all frames next to each other
+    size: (u32, u32),
+    /// Region of the combined frame that is captured
+    region: Rect,
+    /// The combined frame before it is cropped
+    combined: Vec<u8>,
+    buffers: FrameBuffers,
+}
+
+impl CompositeSource {
+    pub fn new(sources: Vec<Box<dyn FrameSource>>) -> CompositeSource {
+        let width = sources.iter().map(|source| source.size().0).sum();
+        let height = sources
+            .iter()
+            .map(|source| source.size().1)
+            .max()
+            .unwrap_or(0);
+
+        CompositeSource {
+            sources,
+            size: (width, height),
+            region: Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            combined: Vec::new(),
+            buffers: FrameBuffers::default(),
+        }
+    }
+}
+
+impl FrameSource for CompositeSource {
+    fn size(&self) -> (u32, u32) {
+        (self.region.width, self.region.height)
+    }
+
+    fn crop(&mut self, crop: Rect) -> Result<(), Box<dyn Error>> {
+        self.region = Rect {
+            x: self.region.x + crop.x,
+            y: self.region.y + crop.y,
+            ..crop
+        };
+        Ok(())
+    }
+
+    /// The sources are not necessarily next to each other on the screen, so the cursor position
+    /// can't be translated into the combined frame
+    fn origin(&self) -> Option<(i32, i32)> {
+        None
+    }
+
+    /// The frames of the sources as they are placed in the combined frame, ignoring the crop
+    fn monitor_regions(&self) -> Vec<Rect> {
+        let mut left = 0;
+        self.sources
+            .iter()
+            .flat_map(|source| {
+                let regions: Vec<Rect> = source
+                    .monitor_regions()
+                    .into_iter()
+                    .map(|region| Rect {
+                        x: region.x + left,
+                        ..region
+                    })
+                    .collect();
+                left += source.size().0;
+                regions
+            })
+            .collect()
+    }
+
+    fn capture_frame(&mut self) -> Result<Frame, FrameError> {
+        let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+        let (width, height) = self.size;
+        let stride = width as usize * bytes_per_pixel;
+        let cropped = self.region.width != width || self.region.height != height;
+
+        // Without a crop the frames are put together right in the buffer of the frame
+        let mut data = self.buffers.take();
+        let combined = if cropped {
+            &mut self.combined
+        } else {
+            &mut *data
+        };
+        combined.clear();
+        combined.resize(stride * height as usize, 0);
+
+        let mut left = 0;
+        for source in &mut self.sources {
+            let frame = source.capture_frame()?;
+            let (frame_width, frame_height) = frame.dimensions();
+            let row_length = frame_width as usize * bytes_per_pixel;
+
+            for (row, pixels) in frame
+                .as_bytes()
+                .chunks_exact(row_length)
+                .take(frame_height as usize)
+                .enumerate()
+            {
+                let start = row * stride + left * bytes_per_pixel;
+                combined[start..start + row_length].copy_from_slice(pixels);
+            }
+            left += frame_width as usize;
+        }
+
+        if cropped {
+            copy_region(&self.combined, width, self.region, &mut data);
+        }
+
+        Ok(Frame::Composite {
+            data,
+            width: self.region.width,
+            height: self.region.height,
+        })
+    }
+}
+
+/// Captures on a thread of its own, so slow captures don't hold up drawing and vice versa
+///
+/// Up to `--frame-queue` frames are queued, the oldest ones are dropped when drawing does not keep
+/// up.
+struct QueuedSource {
+    queue: Arc<FrameQueue>,
+    size: (u32, u32),
+    monitor_regions: Vec<Rect>,
+    /// Origin of the captured area at the time the most recent frame was captured
+    origin: Option<(i32, i32)>,
+}
+
+/// A captured frame on its way from the capture thread, with its own copy of the pixels
+struct QueuedFrame {
+    data: FrameBuffer,
+    width: u32,
+    height: u32,
+    origin: Option<(i32, i32)>,
+}
+
+/// State shared between a `QueuedSource` and its capture thread
+#[derive(Default)]
+struct FrameQueue {
+    /// The queued capture results, and whether the capture thread is done
+    frames: Mutex<(VecDeque<Result<QueuedFrame, FrameError>>, bool)>,
+    queued: Condvar,
+    /// Frame rate the capture thread captures at, which can be changed while it runs
+    target_fps: AtomicU32,
+    /// Asks the capture thread to stop
+    stopped: AtomicBool,
+}
+
+/// A source on its way to the capture thread
+///
+/// Every X11 source has a connection to the X server of its own, which Xlib lets any thread use as
+/// long as only one does at a time. Once moved, the source is only used by the capture thread.
+struct MovedSource(Box<dyn FrameSource>);
+
+unsafe impl Send for MovedSource {}
+
+impl QueuedSource {
+    /// Move capturing from the source onto a thread of its own
+    ///
+    /// Gives the source back if the thread can't be started.
+    pub fn start(
+        config: &Settings,
+        source: Box<dyn FrameSource>,
+    ) -> Result<QueuedSource, (io::Error, Box<dyn FrameSource>)> {
+        let queue = Arc::new(FrameQueue {
+            target_fps: AtomicU32::new(config.target_fps),
+            ..FrameQueue::default()
+        });
+        let (sender, receiver) = mpsc::channel();
+        let thread_queue = Arc::clone(&queue);
+        let frame_queue = config.frame_queue;
+        let spawned = thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || {
+                if let Ok(MovedSource(mut source)) = receiver.recv() {
+                    capture_into_queue(source.as_mut(), &thread_queue, frame_queue);
+                }
+            });
+        if let Err(error) = spawned {
+            return Err((error, source));
+        }
+
+        let queued = QueuedSource {
+            queue,
+            size: source.size(),
+            monitor_regions: source.monitor_regions(),
+            origin: source.origin(),
+        };
+        sender
+            .send(MovedSource(source))
+            .expect("The capture thread stopped before it got its source");
+        Ok(queued)
+    }
+
+    /// Turn a result taken from the queue into a frame
+    fn unpack(&mut self, result: Result<QueuedFrame, FrameError>) -> Result<Frame, FrameError> {
+        result.map(|frame| {
+            self.origin = frame.origin;
+            Frame::Composite {
+                data: frame.data,
+                width: frame.width,
+                height: frame.height,
+            }
+        })
+    }
+}
+
+/// Keep capturing frames into the queue until the source is closed or the queue stopped
+///
+/// The pixels are copied into the buffers of frames that were dropped, once there are any.
+fn capture_into_queue(source: &mut dyn FrameSource, queue: &FrameQueue, frame_queue: usize) {
+    let mut target_fps = queue.target_fps.load(Ordering::Relaxed);
+    let mut pacer = FramePacer::new(target_fps);
+    let buffers = FrameBuffers::default();
+    while !queue.stopped.load(Ordering::SeqCst) {
+        let new_target_fps = queue.target_fps.load(Ordering::Relaxed);
+        if new_target_fps != target_fps {
+            target_fps = new_target_fps;
+            pacer.set_target_fps(target_fps);
+        }
+
+        pacer.sleep();
+        let start_time = pacer.start_frame();
+        let result = source.capture_frame().map(|frame| {
+            let (width, height) = frame.dimensions();
+            let mut data = buffers.take();
+            data.extend_from_slice(frame.as_bytes());
+            QueuedFrame {
+                data,
+                width,
+                height,
+                origin: source.origin(),
+            }
+        });
+        let closed = matches!(result, Err(FrameError::SourceClosed));
+
+        let mut frames = queue.frames.lock().unwrap();
+        if frames.0.len() >= frame_queue {
+            frames.0.pop_front();
+        }
+        frames.0.push_back(result);
+        queue.queued.notify_all();
+        drop(frames);
+
+        if closed {
+            break;
+        }
+        pacer.finish_frame(start_time);
+    }
+
+    queue.frames.lock().unwrap().1 = true;
+    queue.queued.notify_all();
+}
+
+impl FrameSource for QueuedSource {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// The source of the capture thread is already cropped when it is opened
+    fn crop(&mut self, _crop: Rect) -> Result<(), Box<dyn Error>> {
+        Err("Sources that capture on their own thread can't be cropped".into())
+    }
+
+    fn origin(&self) -> Option<(i32, i32)> {
+        self.origin
+    }
+
+    fn monitor_regions(&self) -> Vec<Rect> {
+        self.monitor_regions.clone()
+    }
+
+    fn set_target_fps(&mut self, target_fps: u32) {
+        self.queue.target_fps.store(target_fps, Ordering::Relaxed);
+    }
+
+    /// Take the most recent frame, waiting for one if nothing was captured since the last call
+    fn capture_frame(&mut self) -> Result<Frame, FrameError> {
+        let result = {
+            let mut frames = self.queue.frames.lock().unwrap();
+            while frames.0.is_empty() && !frames.1 {
+                frames = self.queue.queued.wait(frames).unwrap();
+            }
+            take_latest(&mut frames.0)
+        };
+        match result {
+            Some(result) => self.unpack(result),
+            None => Err(FrameError::SourceClosed),
+        }
+    }
+
+    fn latest_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        let result = take_latest(&mut self.queue.frames.lock().unwrap().0);
+        result.map(|result| self.unpack(result))
+    }
+}
+
+/// Take the most recent entry out of the queue, dropping the older ones
+fn take_latest<T>(queue: &mut VecDeque<T>) -> Option<T> {
+    let latest = queue.pop_back();
+    queue.clear();
+    latest
+}
+
+impl Drop for QueuedSource {
+    fn drop(&mut self) {
+        self.queue.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Move capturing onto a thread of its own if frames should be queued
+///
+/// If the thread can't be started the source keeps capturing on the calling thread.
+pub fn queue_frames(config: &Settings, source: Box<dyn FrameSource>) -> Box<dyn FrameSource> {
+    if config.frame_queue == 0 {
+        return source;
+    }
+
+    match QueuedSource::start(config, source) {
+        Ok(queued) => Box::new(queued),
+        Err((error, source)) => {
+            warn!("Unable to capture on a separate thread: {}", error);
+            source
+        }
+    }
+}
+
+/// Size of the frames of the test pattern
+const TEST_PATTERN_SIZE: (u32, u32) = (1280, 720);
+
+/// Side length of the box moving across the test pattern, and how far it moves per frame
+const TEST_PATTERN_BOX: (u32, u32) = (120, 8);
+
+/// Colors of the bars of the test pattern from left to right, as RGB
+const TEST_PATTERN_BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+/// Generates an image instead of capturing it, to try out everything after capturing without an
+/// X server
+///
+/// The image shows vertical color bars with a gray box moving across them. It only depends on the
+/// number of frames generated so far, so every run produces exactly the same frames.
+pub struct TestPattern {
+    /// Region of the pattern that is generated
+    region: Rect,
+    frame_count: u32,
+    buffers: FrameBuffers,
+}
+
+impl TestPattern {
+    pub fn new() -> TestPattern {
+        let (width, height) = TEST_PATTERN_SIZE;
+        TestPattern {
+            region: Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            frame_count: 0,
+            buffers: FrameBuffers::default(),
+        }
+    }
+
+    /// Color of a pixel of the pattern, as RGB
+    pub fn color(&self, x: u32, y: u32) -> [u8; 3] {
+        let (width, height) = TEST_PATTERN_SIZE;
+        let (size, step) = TEST_PATTERN_BOX;
+        let box_x = self.frame_count * step % (width - size);
+        let box_y = (height - size) / 2;
+        if (box_x..box_x + size).contains(&x) && (box_y..box_y + size).contains(&y) {
+            [128, 128, 128]
+        } else {
+            TEST_PATTERN_BARS[(x * TEST_PATTERN_BARS.len() as u32 / width) as usize]
+        }
+    }
+}
+
+impl FrameSource for TestPattern {
+    fn size(&self) -> (u32, u32) {
+        (self.region.width, self.region.height)
+    }
+
+    fn crop(&mut self, crop: Rect) -> Result<(), Box<dyn Error>> {
+        self.region = Rect {
+            x: self.region.x + crop.x,
+            y: self.region.y + crop.y,
+            ..crop
+        };
+        Ok(())
+    }
+
+    /// The pattern is not on the screen, so there is no cursor to show on it
+    fn origin(&self) -> Option<(i32, i32)> {
+        None
+    }
+
+    fn capture_frame(&mut self) -> Result<Frame, FrameError> {
+        let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = self.region;
+        let mut data = self.buffers.take();
+        data.reserve(CAPTURED_PACKING.byte_len((width * height) as usize));
+        for row in y..y + height {
+            for column in x..x + width {
+                // Captured pixels come in X11's byte order, blue first
+                let [red, green, blue] = self.color(column, row);
+                data.extend_from_slice(&[blue, green, red, 0][..bytes_per_pixel]);
+            }
+        }
+        self.frame_count += 1;
+
+        Ok(Frame::Composite {
+            data,
+            width,
+            height,
+        })
+    }
+}
+
+/// Capture a monitor or a region of the screen
+impl FrameSource for Capturer {
+    fn size(&self) -> (u32, u32) {
+        let geo = self.get_geometry();
+        (geo.width, geo.height)
+    }
+
+    /// X11 can capture arbitrary regions of the screen, so instead of cutting the region out of
+    /// every frame we let the X server do it.
+    fn crop(&mut self, crop: Rect) -> Result<(), Box<dyn Error>> {
+        let geo = self.get_geometry();
+        *self = Capturer::new(CaptureSource::Region {
+            x: geo.x + crop.x as i32,
+            y: geo.y + crop.y as i32,
+            width: crop.width,
+            height: crop.height,
+        })
+        .map_err(|_| "Unable to create screen capturer")?;
+        Ok(())
+    }
+
+    fn origin(&self) -> Option<(i32, i32)> {
+        let geo = self.get_geometry();
+        Some((geo.x, geo.y))
+    }
+
+    fn capture_frame(&mut self) -> Result<Frame, FrameError> {
+        match Capturer::capture_frame(self) {
+            Ok(image) => Ok(Frame::Screen(image)),
+            Err(CaptureError::Fail(reason)) => Err(FrameError::Failed(reason)),
+        }
+    }
+}
+
+/// A captured frame, with the pixels packed as described by `CAPTURED_PACKING`
+pub enum Frame {
+    Screen(Image),
+    Window(WindowImage),
+    Composite {
+        data: FrameBuffer,
+        width: u32,
+        height: u32,
+    },
+}
+
+/// Buffers of frames that were dropped, to be reused by sources that put frames together
+/// themselves so they don't allocate for every frame
+#[derive(Clone, Default)]
+pub struct FrameBuffers(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl FrameBuffers {
+    /// An empty buffer, which goes back to the others once it is dropped
+    pub fn take(&self) -> FrameBuffer {
+        let mut data = self.0.lock().unwrap().pop().unwrap_or_default();
+        data.clear();
+        FrameBuffer {
+            data,
+            buffers: Some(self.clone()),
+        }
+    }
+}
+
+/// The pixels of a frame, taken from `FrameBuffers` unless they were allocated for this frame
+pub struct FrameBuffer {
+    data: Vec<u8>,
+    buffers: Option<FrameBuffers>,
+}
+
+impl From<Vec<u8>> for FrameBuffer {
+    fn from(data: Vec<u8>) -> Self {
+        FrameBuffer {
+            data,
+            buffers: None,
+        }
+    }
+}
+
+impl std::ops::Deref for FrameBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+impl std::ops::DerefMut for FrameBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+impl Drop for FrameBuffer {
+    fn drop(&mut self) {
+        if let Some(buffers) = &self.buffers {
+            buffers.0.lock().unwrap().push(mem::take(&mut self.data));
+        }
+    }
+}
+
+impl Frame {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Frame::Screen(image) => image.get_dimensions(),
+            Frame::Window(image) => unsafe {
+                ((*image.image).width as u32, (*image.image).height as u32)
+            },
+            Frame::Composite { width, height, .. } => (*width, *height),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Frame::Screen(image) => unsafe {
+                // The size of `Bgr8` is checked against the packing when compiling
+                let slice = image.as_slice();
+                std::slice::from_raw_parts(
+                    slice.as_ptr() as *const u8,
+                    CAPTURED_PACKING.byte_len(slice.len()),
+                )
+            },
+            Frame::Window(image) => unsafe {
+                let image = &*image.image;
+                std::slice::from_raw_parts(
+                    image.data as *const u8,
+                    (image.bytes_per_line * image.height) as usize,
+                )
+            },
+            Frame::Composite { data, .. } => &data[..],
+        }
+    }
+}
+
+/// Reopen the source if the size of the capture target is no longer `size`
+///
+/// This happens when the resolution of a monitor is switched or a captured window is resized.
+/// Returns the new source, already cropped, and updates `size` to the new size of the target.
+pub fn refresh_source(
+    config: &Settings,
+    target: &CaptureTarget,
+    size: &mut (u32, u32),
+) -> Result<Option<Box<dyn FrameSource>>, Box<dyn Error>> {
+    let source = open_source(target.clone())?;
+    let new_size = source.size();
+    if new_size == *size {
+        return Ok(None);
+    }
+
+    info!(
+        "Size of the capture target changed from {}x{} to {}x{}",
+        size.0, size.1, new_size.0, new_size.1
+    );
+    *size = new_size;
+    crop_source(config, source).map(Some)
+}
+
+/// Tries to open a capture source again after it was lost, see `SourceLostAction`
+pub struct SourceRecovery {
+    /// When opening the source was tried the last time
+    last_attempt: Instant,
+}
+
+impl SourceRecovery {
+    /// Start recovering, returns the frame to show until the source is back if there is one
+    ///
+    /// A black frame takes the place of the lost image, otherwise the last image stays.
+    pub fn start(config: &Settings, size: (u32, u32)) -> (SourceRecovery, Option<Frame>) {
+        let placeholder = match config.on_source_lost {
+            SourceLostAction::Blank => {
+                let (width, height) = size;
+                Some(Frame::Composite {
+                    data: vec![0; CAPTURED_PACKING.byte_len((width * height) as usize)].into(),
+                    width,
+                    height,
+                })
+            }
+            SourceLostAction::Exit | SourceLostAction::Wait => None,
+        };
+        warn!("Lost the capture source, waiting for it to come back");
+
+        let recovery = SourceRecovery {
+            last_attempt: Instant::now(),
+        };
+        (recovery, placeholder)
+    }
+
+    /// Open the source again once it is time for another try, returns it with its first frame
+    ///
+    /// The outputs were set up for the size of the lost source, so it has to come back with the
+    /// same size.
+    pub fn try_reopen(
+        &mut self,
+        config: &Settings,
+        target: &CaptureTarget,
+        size: (u32, u32),
+    ) -> Option<(Box<dyn FrameSource>, Frame)> {
+        if self.last_attempt.elapsed() < SOURCE_RETRY_INTERVAL {
+            return None;
+        }
+        self.last_attempt = Instant::now();
+
+        let result = open_source(target.clone())
+            .and_then(|source| crop_source(config, source))
+            .and_then(|mut source| {
+                if source.size() != size {
+                    let (width, height) = source.size();
+                    return Err(format!(
+                        "The source is back with a different size of {}x{}",
+                        width, height
+                    )
+                    .into());
+                }
+                match source.capture_frame() {
+                    Ok(frame) => Ok((source, frame)),
+                    Err(error) => Err(format!("Capturing still fails: {:?}", error).into()),
+                }
+            });
+        match result {
+            Ok(recovered) => {
+                info!("The capture source is back");
+                Some(recovered)
+            }
+            Err(error) => {
+                debug!("Unable to open the lost capture source again: {}", error);
+                None
+            }
+        }
+    }
+}
+
+/// Open the source for the capture target, restricted to the crop region
+pub fn open_cropped_source(
+    config: &Settings,
+    target: CaptureTarget,
+) -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+    let description = format!("{:?}", target);
+    let source = open_source(target)?;
+    let (width, height) = source.size();
+    info!(
+        "Capturing {} with a size of {}x{}",
+        description, width, height
+    );
+
+    crop_source(config, source)
+}
+
+/// Restrict a source to the crop region, if there is one
+pub fn crop_source(
+    config: &Settings,
+    mut source: Box<dyn FrameSource>,
+) -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+    if let Some(crop) = config.crop {
+        let (width, height) = source.size();
+        if !crop.fits_within(width, height) {
+            return Err(format!(
+                "Crop region {}x{}+{}+{} does not fit within the captured area of {}x{}",
+                crop.width, crop.height, crop.x, crop.y, width, height
+            )
+            .into());
+        }
+
+        source.crop(crop)?;
+    }
+
+    Ok(source)
+}
+
+/// Cheap hash of the pixel data, used to find out whether a frame changed
+///
+/// This is called for every captured frame, so it processes the data a word at a time rather
+/// than using a hasher from the standard library, which would be far too slow for large monitors.
+pub fn frame_hash(pixels: &[u8]) -> u64 {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    let chunks = pixels.chunks_exact(8);
+    let mut hash = chunks.remainder().iter().fold(0, |hash: u64, &byte| {
+        (hash.rotate_left(5) ^ u64::from(byte)).wrapping_mul(SEED)
+    });
+    for chunk in chunks {
+        let mut word = [0; 8];
+        word.copy_from_slice(chunk);
+        hash = (hash.rotate_left(5) ^ u64::from_ne_bytes(word)).wrapping_mul(SEED);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composite_frames_reuse_their_buffers() {
+        let patterns: Vec<Box<dyn FrameSource>> =
+            vec![Box::new(TestPattern::new()), Box::new(TestPattern::new())];
+        let mut source = CompositeSource::new(patterns);
+        assert_eq!(
+            source.size(),
+            (2 * TEST_PATTERN_SIZE.0, TEST_PATTERN_SIZE.1)
+        );
+
+        let first = source.capture_frame().unwrap();
+        let pixels = first.as_bytes().as_ptr();
+        drop(first);
+        assert_eq!(source.capture_frame().unwrap().as_bytes().as_ptr(), pixels);
+
+        // The crop of the second pattern starts with its first bar, which is white
+        source
+            .crop(Rect {
+                x: TEST_PATTERN_SIZE.0,
+                y: 0,
+                width: 10,
+                height: 10,
+            })
+            .unwrap();
+        let frame = source.capture_frame().unwrap();
+        assert_eq!(frame.dimensions(), (10, 10));
+        assert_eq!(&frame.as_bytes()[..3], &[255, 255, 255]);
+    }
+
+    #[test]
+    fn queued_source_captures_the_opened_source() {
+        let config = Settings {
+            frame_queue: 2,
+            ..Settings::default()
+        };
+        let mut pattern = TestPattern::new();
+        pattern
+            .crop(Rect {
+                x: 0,
+                y: 0,
+                width: 16,
+                height: 8,
+            })
+            .unwrap();
+        let mut source = QueuedSource::start(&config, Box::new(pattern))
+            .map_err(|(error, _)| error)
+            .unwrap();
+        assert_eq!(source.size(), (16, 8));
+        assert_eq!(source.capture_frame().unwrap().dimensions(), (16, 8));
+
+        source.set_target_fps(60);
+        assert_eq!(source.queue.target_fps.load(Ordering::Relaxed), 60);
+    }
+}
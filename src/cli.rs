@@ -0,0 +1,846 @@
+//! Command line arguments and how they override the settings
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use ::x11::xlib;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::Parser;
+
+use crate::config::{
+    profile_path, ChromaKey, Color, Corner, Effect, Filter, GlProfile, GlVersion, Grid, Key,
+    OffscreenPlacement, PictureInPicture, PixelFormat, Point, Rect, Settings, SourceLostAction,
+    VideoFormat, Watermark,
+};
+
+/// A monitor given on the command line, either by its ID or by the name of its RandR output
+///
+/// Names stay the same when monitors are plugged in in a different order, IDs don't.
+#[derive(Clone, Debug)]
+pub enum MonitorSelector {
+    Id(usize),
+    Name(String),
+}
+
+impl FromStr for MonitorSelector {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("expected a monitor ID or name");
+        }
+
+        Ok(s.parse()
+            .map(MonitorSelector::Id)
+            .unwrap_or_else(|_| MonitorSelector::Name(s.to_string())))
+    }
+}
+
+/// Parse an X11 window ID, given either in hexadecimal with a `0x` prefix or in decimal
+fn parse_window_id(s: &str) -> Result<xlib::Window, &'static str> {
+    let parsed = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        xlib::Window::from_str_radix(hex, 16)
+    } else {
+        s.parse::<xlib::Window>()
+    };
+    parsed.map_err(|_| "expected an integer, optionally in hexadecimal with 0x prefix")
+}
+
+/// Command line arguments
+///
+/// Everything that is also part of `Settings` is optional here, so that only the arguments that
+/// were actually given override the configuration file.
+#[derive(Parser)]
+#[command(
+    name = "Screen splitter",
+    version = "0.1",
+    about = "Allows the user to share a single monitor in a video call",
+    long_about = None
+)]
+pub struct Cli {
+    /// The ID or RandR output name of the monitor to mirror, several comma separated monitors are
+    /// shown side by side
+    #[arg(
+        value_delimiter = ',',
+        required_unless_present_any = [
+            "list_monitors",
+            "list_profiles",
+            "doctor",
+            "window_id",
+            "window_title",
+            "screen",
+            "all_monitors",
+            "test_pattern",
+            "portal",
+            "config",
+            "profile",
+        ],
+        conflicts_with_all = ["window_id", "window_title", "screen"]
+    )]
+    pub monitor_id: Vec<MonitorSelector>,
+
+    /// Mirror the X11 window with the given ID instead of a monitor
+    #[arg(long, value_name = "ID", value_parser = parse_window_id)]
+    pub window_id: Option<xlib::Window>,
+
+    /// Mirror the visible X11 window whose title contains the given text
+    #[arg(long, value_name = "TEXT", conflicts_with = "window_id")]
+    pub window_title: Option<String>,
+
+    /// Mirror the whole virtual screen spanning all monitors, combine with --crop to share a
+    /// region that crosses the border between two monitors
+    #[arg(long, conflicts_with_all = ["window_id", "window_title"])]
+    pub screen: bool,
+
+    /// Mirror every monitor, each in its own window that is named after the monitor
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "monitor_id",
+            "window_id",
+            "window_title",
+            "screen",
+            "grid",
+            "crop",
+            "select",
+            "autocrop",
+            "zoom",
+            "pip",
+            "fullscreen",
+            "v4l2",
+            "http_stream",
+            "raw_stdout",
+            "screenshot",
+        ]
+    )]
+    pub all_monitors: bool,
+
+    /// Share generated color bars with a moving box instead of capturing anything, to test the
+    /// processing of the frames without an X server
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "monitor_id",
+            "window_id",
+            "window_title",
+            "screen",
+            "all_monitors",
+        ]
+    )]
+    pub test_pattern: bool,
+
+    /// Share the monitor or window picked in the dialog of the screen cast portal, which is what
+    /// is shared on Wayland sessions anyway. Needs a build with the wayland feature
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "monitor_id",
+            "window_id",
+            "window_title",
+            "screen",
+            "all_monitors",
+            "test_pattern",
+        ]
+    )]
+    pub portal: bool,
+
+    /// Title of the shared window, {monitor}, {width}, {height}, {fps} and {pid} are replaced by
+    /// the captured monitor or window ID, the captured size, the target frame rate and the process
+    /// ID. Given multiple times, one window is created per title
+    #[arg(long, value_name = "TEMPLATE")]
+    title: Vec<String>,
+
+    /// Append a name to the window titles to tell several instances apart when picking a window
+    /// to share. {pid} is replaced by the process ID, which is used without a name
+    #[arg(
+        long,
+        value_name = "NAME",
+        num_args = 0..=1,
+        default_missing_value = "{pid}"
+    )]
+    instance_name: Option<String>,
+
+    /// Target frames per second, the + and - keys change it while the window shown with
+    /// --onscreen or the control window of offscreen windows has the focus. The [keys] section of
+    /// the configuration file binds other keys [default: 30]
+    #[arg(long)]
+    fps: Option<u32>,
+
+    /// Match the target frames per second to the refresh rate of the captured monitor, falls back
+    /// to --fps if it can't be determined
+    #[arg(long, conflicts_with = "fps")]
+    fps_match_display: bool,
+
+    /// Exit after capturing this many frames, 0 runs until the window is closed [default: 0]
+    #[arg(long, value_name = "N")]
+    max_frames: Option<u32>,
+
+    /// Only capture every Nth frame and repeat the last captured one in between, which saves
+    /// capture work for static content like slides [default: 1]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    capture_every: Option<u32>,
+
+    /// Capture on a separate thread that queues up to DEPTH frames for drawing, dropping the
+    /// oldest ones when drawing falls behind. 0 captures and draws on the same thread
+    /// [default: 0]
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        conflicts_with_all = ["v4l2", "http_stream", "raw_stdout", "screenshot"]
+    )]
+    frame_queue: Option<usize>,
+
+    /// Try again this many times, waiting longer each time, if the X server or the monitors are
+    /// not ready yet, e.g. when started by a service at login [default: 0]
+    #[arg(long, value_name = "N")]
+    startup_retries: Option<u32>,
+
+    /// What to do when the captured monitor or window disappears: exit, show a black image or
+    /// keep the last image, the latter two until the source is back [default: exit]
+    #[arg(long, value_enum, value_name = "ACTION")]
+    on_source_lost: Option<SourceLostAction>,
+
+    /// Stop writing frames to --v4l2, --http-stream or --raw-stdout once the image did not change
+    /// for this many seconds, until it changes again. Readers keep showing the last frame.
+    /// [default: 0]
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u32>,
+
+    /// Show the capture window on screen. Without it a small control window takes the keys while
+    /// it has the focus instead, and escape or q quit in either window. The quit key of the [keys]
+    /// section of the configuration file binds other keys
+    #[arg(long)]
+    onscreen: bool,
+
+    /// Share the offscreen window and show the same image in an onscreen preview window
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "onscreen",
+            "fullscreen",
+            "v4l2",
+            "http_stream",
+            "raw_stdout",
+            "screenshot",
+        ]
+    )]
+    preview_and_share: bool,
+
+    /// How to hide the shared window: move it right of all monitors, to negative coordinates like
+    /// older versions did or to the bottom right corner with one pixel visible, or minimize it.
+    /// Try another one if the window picker shows a black thumbnail [default: outside]
+    #[arg(
+        long,
+        visible_alias = "offscreen-strategy",
+        value_enum,
+        value_name = "PLACEMENT",
+        conflicts_with = "onscreen"
+    )]
+    offscreen_placement: Option<OffscreenPlacement>,
+
+    /// Keep the window shown with --onscreen above all other windows
+    #[arg(long)]
+    always_on_top: bool,
+
+    /// Opacity of the window shown with --onscreen, from 0 to 1. Needs a compositing window
+    /// manager [default: 1]
+    #[arg(long)]
+    opacity: Option<f32>,
+
+    /// Key that pauses and resumes mirroring while the window shown with --onscreen or the control
+    /// window of offscreen windows has the focus, instead of the pause keys of the configuration
+    /// file. The image stays frozen while paused [default: p]
+    #[arg(long, value_name = "KEY")]
+    pause_key: Option<Key>,
+
+    /// Save a PNG image of what the shared windows show, overlays and cursor included, to this
+    /// directory whenever --snapshot-key is pressed while the window shown with --onscreen or the
+    /// control window has the focus, named after the current time
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["v4l2", "http_stream", "raw_stdout", "screenshot"]
+    )]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Key that saves a snapshot to the directory given with --snapshot-dir [default: s]
+    #[arg(long, value_name = "KEY", requires = "snapshot_dir")]
+    snapshot_key: Option<Key>,
+
+    /// Only mirror the given region of the monitor, window or screen
+    #[arg(long, value_name = "X,Y,W,H")]
+    crop: Option<Rect>,
+
+    /// Drag a rectangle over the captured area to pick the region to mirror before starting
+    #[arg(long, conflicts_with = "crop")]
+    pub select: bool,
+
+    /// Crop away the black borders around the content of the first captured frame
+    #[arg(long, conflicts_with_all = ["crop", "select"])]
+    pub autocrop: bool,
+
+    /// Brightest color channel value that --autocrop still treats as black [default: 16]
+    #[arg(long, value_name = "VALUE")]
+    autocrop_threshold: Option<u8>,
+
+    /// Split the monitor into a grid of tiles, each shared as its own window
+    #[arg(long, value_name = "COLSxROWS")]
+    grid: Option<Grid>,
+
+    /// Order of the color channels in the captured image [default: bgr]
+    #[arg(long, value_enum)]
+    pixel_format: Option<PixelFormat>,
+
+    /// Show the captured pixels exactly as they are, without swapping red and blue or adjusting
+    /// the colors. If the colors look right, the captured image is already RGB.
+    #[arg(long, conflicts_with_all = ["v4l2", "screenshot"])]
+    no_color_correction: bool,
+
+    /// Texture filtering used when the image is drawn at a different size [default: linear]
+    #[arg(long, value_enum)]
+    filter: Option<Filter>,
+
+    /// Scale the shared window relative to the captured area, e.g. 0.5
+    #[arg(long, value_name = "FACTOR")]
+    scale: Option<f64>,
+
+    /// Scale regions larger than N pixels in either direction down before uploading them to the
+    /// GPU, which bounds the memory used for textures on constrained hardware
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_texture_dim: Option<u32>,
+
+    /// Magnify the shared image by this factor, the arrow keys pan it while the window shown with
+    /// --onscreen or the control window of offscreen windows has the keyboard focus [default: 1]
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        conflicts_with_all = ["grid", "v4l2", "screenshot"]
+    )]
+    zoom: Option<f32>,
+
+    /// Center of the magnified part in pixels of the captured area [default: the middle]
+    #[arg(long, value_name = "X,Y")]
+    pan: Option<Point>,
+
+    /// Share a grayscale version of the image, the same as --filter-effect grayscale
+    #[arg(long, conflicts_with = "filter_effect")]
+    grayscale: bool,
+
+    /// Color effect applied after the color correction [default: none]
+    #[arg(long, value_enum, value_name = "EFFECT")]
+    filter_effect: Option<Effect>,
+
+    /// Value added to every color channel, between -1 and 1 [default: 0]
+    #[arg(long, allow_negative_numbers = true)]
+    brightness: Option<f32>,
+
+    /// Factor by which the contrast is increased [default: 1]
+    #[arg(long)]
+    contrast: Option<f32>,
+
+    /// Gamma correction applied after brightness and contrast [default: 1]
+    #[arg(long)]
+    gamma: Option<f32>,
+
+    /// Shift the colors to a white of this temperature: lower values like 3000 are warmer,
+    /// higher ones like 9000 cooler [default: 6500]
+    #[arg(long, value_name = "KELVIN")]
+    color_temp: Option<u32>,
+
+    /// Sharpen the image to keep small text readable when it's scaled down, around 0.5 is a good
+    /// start [default: 0]
+    #[arg(long, value_name = "AMOUNT")]
+    sharpen: Option<f32>,
+
+    /// Show a high-contrast line drawing of the edges in the image instead of the image itself
+    #[arg(long)]
+    edges: bool,
+
+    /// How strong a change in brightness has to be to count as an edge, from 0 to 1 [default: 0.2]
+    #[arg(long, value_name = "VALUE")]
+    edge_threshold: Option<f32>,
+
+    /// Draw the edges found with --edges on top of the image instead of on black
+    #[arg(long)]
+    edges_overlay: bool,
+
+    /// Dither the shown image to hide the bands that smooth gradients get, especially after
+    /// adjusting the brightness, contrast or gamma
+    #[arg(long, conflicts_with_all = ["v4l2", "http_stream", "raw_stdout", "screenshot"])]
+    dither: bool,
+
+    /// Rotate the shared image clockwise [default: 0]
+    #[arg(
+        long,
+        value_name = "DEGREES",
+        value_parser = PossibleValuesParser::new(["0", "90", "180", "270"])
+            .map(|degrees| degrees.parse::<u32>().unwrap())
+    )]
+    rotate: Option<u32>,
+
+    /// Mirror the shared image horizontally
+    #[arg(long)]
+    flip_h: bool,
+
+    /// Mirror the shared image vertically
+    #[arg(long)]
+    flip_v: bool,
+
+    /// Blur a region of the shared image, can be given multiple times
+    #[arg(long, value_name = "X,Y,W,H")]
+    blur: Vec<Rect>,
+
+    /// Paint over a region of the shared image, can be given multiple times
+    #[arg(long, value_name = "X,Y,W,H")]
+    mask: Vec<Rect>,
+
+    /// Color the masked regions are painted with [default: 000000]
+    #[arg(long, value_name = "RRGGBB")]
+    mask_color: Option<Color>,
+
+    /// Make the pixels of the captured image that have this color transparent, so tools like OBS
+    /// can composite the window over a scene. Each channel may differ by up to the tolerance,
+    /// from 0 to 1. Needs a compositing window manager
+    #[arg(
+        long,
+        value_name = "RRGGBB:TOLERANCE",
+        conflicts_with_all = ["v4l2", "http_stream", "raw_stdout", "screenshot"]
+    )]
+    chroma_key: Option<ChromaKey>,
+
+    /// Draw the mouse cursor into the shared window
+    #[arg(long, conflicts_with_all = ["v4l2", "screenshot"])]
+    show_cursor: bool,
+
+    /// Draw a pulsing ring around the mouse cursor, to make it easier to follow
+    #[arg(long, conflicts_with_all = ["v4l2", "screenshot"])]
+    cursor_highlight: bool,
+
+    /// Show another monitor as an inset in a corner, e.g. 1:bottom-right:0.25
+    #[arg(
+        long,
+        value_name = "MONITOR_ID:CORNER:SIZE",
+        conflicts_with_all = ["grid", "v4l2", "screenshot"]
+    )]
+    pip: Option<PictureInPicture>,
+
+    /// Distance between the inset and the edges of the window in pixels [default: 16]
+    #[arg(long, value_name = "PIXELS")]
+    pip_margin: Option<u32>,
+
+    /// Width of the border around the inset in pixels [default: 2]
+    #[arg(long, value_name = "PIXELS")]
+    pip_border: Option<u32>,
+
+    /// Draw an image in a corner of the shared windows with the given opacity, e.g.
+    /// logo.png:bottom-right:0.8 (can be repeated)
+    #[arg(
+        long = "watermark",
+        value_name = "PATH:CORNER:OPACITY",
+        conflicts_with_all = ["v4l2", "screenshot"]
+    )]
+    watermarks: Vec<Watermark>,
+
+    /// Synchronize buffer swaps with the monitor refresh rate, on top of --fps
+    #[arg(long)]
+    vsync: bool,
+
+    /// Ask for this version of OpenGL, e.g. 3.3, if the driver picks one the shaders don't compile
+    /// with. The shaders are adjusted to the version.
+    #[arg(long, value_name = "MAJOR.MINOR")]
+    gl_version: Option<GlVersion>,
+
+    /// Ask for a core or a compatibility profile of OpenGL
+    #[arg(long, value_enum, value_name = "PROFILE")]
+    gl_profile: Option<GlProfile>,
+
+    /// Samples per pixel used for anti-aliasing, only matters with --scale or a letterboxed
+    /// window [default: 0]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = PossibleValuesParser::new(["0", "2", "4", "8"])
+            .map(|samples| samples.parse::<u16>().unwrap())
+    )]
+    msaa: Option<u16>,
+
+    /// Draw every frame, even if the captured image did not change
+    #[arg(long)]
+    always_redraw: bool,
+
+    /// Follow changes of the monitor resolution or window size, checked once per second
+    #[arg(long)]
+    refresh_geometry: bool,
+
+    /// Draw the achieved frames per second in the corner of the shared window
+    #[arg(long)]
+    show_fps: bool,
+
+    /// Draw the current time into the shared image, also into recordings and streams
+    #[arg(long)]
+    overlay_clock: bool,
+
+    /// Format of the clock as used by strftime, e.g. %Y-%m-%d %H:%M [default: %H:%M:%S]
+    #[arg(long, value_name = "FORMAT")]
+    clock_format: Option<String>,
+
+    /// Corner the clock is drawn in, one of top-left, top-right, bottom-left and bottom-right
+    /// [default: top-right]
+    #[arg(long, value_name = "CORNER")]
+    clock_corner: Option<Corner>,
+
+    /// Print the shortest, longest and average frame interval and the number of late frames on exit
+    #[arg(long)]
+    stats: bool,
+
+    /// Log when something starts and stops moving in the captured image, shown with --verbose
+    #[arg(long)]
+    detect_motion: bool,
+
+    /// Fraction of the image that has to change to count as motion, from 0 to 1 [default: 0.02]
+    #[arg(long, value_name = "FRACTION")]
+    motion_threshold: Option<f32>,
+
+    /// Print the average time per frame spent capturing, uploading and drawing on exit, as
+    /// key=value pairs on stdout
+    #[arg(long, conflicts_with_all = ["v4l2", "screenshot"])]
+    benchmark: bool,
+
+    /// Print the average time the GPU takes to draw the image every 300 frames, to tell how
+    /// expensive effects like --blur or --sharpen are
+    #[arg(long, conflicts_with_all = ["v4l2", "screenshot"])]
+    profile_gpu: bool,
+
+    /// Keep the aspect ratio of the image when resizing a window, adding black bars
+    #[arg(long)]
+    keep_aspect: bool,
+
+    /// Color of the bars added to keep the aspect ratio [default: 000000]
+    #[arg(long, value_name = "RRGGBB")]
+    border_color: Option<Color>,
+
+    /// Show the window borderless fullscreen on the monitor with the given ID instead of sharing it,
+    /// keeping the aspect ratio of the image
+    #[arg(
+        long,
+        value_name = "DISPLAY_INDEX",
+        conflicts_with_all = ["grid", "v4l2", "screenshot"]
+    )]
+    fullscreen: Option<usize>,
+
+    /// Write the frames to a v4l2loopback device instead of showing a window
+    #[arg(long, value_name = "DEVICE", conflicts_with_all = ["grid", "onscreen"])]
+    pub v4l2: Option<String>,
+
+    /// Pixel format of the frames written to the video device [default: rgb24]
+    #[arg(long, value_enum, value_name = "FORMAT", requires = "v4l2")]
+    v4l2_format: Option<VideoFormat>,
+
+    /// Serve the frames as an MJPEG stream over HTTP instead of showing a window, e.g. 0.0.0.0:8080
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        conflicts_with_all = [
+            "grid",
+            "onscreen",
+            "fullscreen",
+            "v4l2",
+            "screenshot",
+            "show_cursor",
+            "cursor_highlight",
+            "pip",
+            "watermarks",
+            "zoom",
+            "no_color_correction",
+            "benchmark",
+            "profile_gpu",
+        ]
+    )]
+    pub http_stream: Option<String>,
+
+    /// Write the frames to stdout as raw RGB24 data instead of showing a window, e.g. for ffmpeg
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "grid",
+            "onscreen",
+            "fullscreen",
+            "v4l2",
+            "http_stream",
+            "screenshot",
+            "show_cursor",
+            "cursor_highlight",
+            "pip",
+            "watermarks",
+            "zoom",
+            "no_color_correction",
+            "benchmark",
+            "profile_gpu",
+        ]
+    )]
+    pub raw_stdout: bool,
+
+    /// Additionally record the captured image to a video file using ffmpeg
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Save a single frame as a PNG image and exit
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["grid", "onscreen", "v4l2", "record"]
+    )]
+    pub screenshot: Option<PathBuf>,
+
+    /// Load the settings from a TOML file, arguments given here take precedence
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Load the settings from a saved profile, arguments given here take precedence
+    #[arg(long, value_name = "NAME", conflicts_with = "config")]
+    profile: Option<String>,
+
+    /// Save the resulting settings and capture target as a profile
+    #[arg(long, value_name = "NAME")]
+    pub save_profile: Option<String>,
+
+    /// Print the names of all saved profiles and exit
+    #[arg(long)]
+    pub list_profiles: bool,
+
+    /// Print the ID, name and geometry of every monitor and exit
+    #[arg(long)]
+    pub list_monitors: bool,
+
+    /// Check the X11 session, the monitors, OpenGL and WM_STATE, print the outcome of each check
+    /// and exit
+    #[arg(long)]
+    pub doctor: bool,
+
+    /// Print the resolved settings, capture target and captured geometry as JSON and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Log what is going on, RUST_LOG takes precedence if it is set
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Only print errors, e.g. when running from a script. RUST_LOG takes precedence if it is set
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+}
+
+impl Cli {
+    /// Configuration file selected with `--config` or `--profile`, if any
+    pub fn config_path(&self) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        if let Some(path) = &self.config {
+            Ok(Some(path.clone()))
+        } else if let Some(name) = &self.profile {
+            let path = profile_path(name)?;
+            if !path.exists() {
+                return Err(format!("There is no profile named {}", name).into());
+            }
+            Ok(Some(path))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Override the settings with the arguments that were given
+    pub fn apply(self, config: &mut Settings) {
+        if !self.title.is_empty() {
+            config.titles = self.title;
+        }
+        config.instance_name = self.instance_name.or(config.instance_name.take());
+        config.target_fps = self.fps.unwrap_or(config.target_fps);
+        // An explicit frame rate also overrides matching the display in the configuration file
+        config.fps_match_display =
+            self.fps.is_none() && (self.fps_match_display || config.fps_match_display);
+        config.max_frames = self.max_frames.unwrap_or(config.max_frames);
+        config.capture_every = self.capture_every.unwrap_or(config.capture_every);
+        config.frame_queue = self.frame_queue.unwrap_or(config.frame_queue);
+        config.on_source_lost = self.on_source_lost.unwrap_or(config.on_source_lost);
+        config.startup_retries = self.startup_retries.unwrap_or(config.startup_retries);
+        config.idle_timeout = self.idle_timeout.unwrap_or(config.idle_timeout);
+        config.offscreen &= !self.onscreen;
+        config.preview |= self.preview_and_share;
+        config.offscreen_placement = self
+            .offscreen_placement
+            .unwrap_or(config.offscreen_placement);
+        config.always_on_top |= self.always_on_top;
+        config.opacity = self.opacity.unwrap_or(config.opacity);
+        if let Some(key) = self.pause_key {
+            config.keys.pause = key.into();
+        }
+        config.snapshot_dir = self.snapshot_dir.or(config.snapshot_dir.take());
+        if let Some(key) = self.snapshot_key {
+            config.keys.snapshot = key.into();
+        }
+        config.crop = self.crop.or(config.crop);
+        config.autocrop_threshold = self.autocrop_threshold.unwrap_or(config.autocrop_threshold);
+        config.grid = self.grid.or(config.grid);
+        config.split_monitors |= self.all_monitors;
+        config.pixel_format = self.pixel_format.unwrap_or(config.pixel_format);
+        config.color_correction &= !self.no_color_correction;
+        config.filter = self.filter.unwrap_or(config.filter);
+        config.scale = self.scale.unwrap_or(config.scale);
+        config.max_texture_dim = self.max_texture_dim.or(config.max_texture_dim);
+        config.zoom = self.zoom.unwrap_or(config.zoom);
+        config.pan = self.pan.or(config.pan);
+        config.effect = if self.grayscale {
+            Effect::Grayscale
+        } else {
+            self.filter_effect.unwrap_or(config.effect)
+        };
+        config.brightness = self.brightness.unwrap_or(config.brightness);
+        config.contrast = self.contrast.unwrap_or(config.contrast);
+        config.gamma = self.gamma.unwrap_or(config.gamma);
+        config.color_temp = self.color_temp.unwrap_or(config.color_temp);
+        config.sharpen = self.sharpen.unwrap_or(config.sharpen);
+        config.edges |= self.edges;
+        config.edge_threshold = self.edge_threshold.unwrap_or(config.edge_threshold);
+        config.edges_overlay |= self.edges_overlay;
+        config.dither |= self.dither;
+        config.rotation = self.rotate.unwrap_or(config.rotation);
+        config.flip_horizontal |= self.flip_h;
+        config.flip_vertical |= self.flip_v;
+        if !self.blur.is_empty() {
+            config.blur = self.blur;
+        }
+        if !self.mask.is_empty() {
+            config.masks = self.mask;
+        }
+        config.mask_color = self.mask_color.unwrap_or(config.mask_color);
+        config.chroma_key = self.chroma_key.or(config.chroma_key);
+        config.show_cursor |= self.show_cursor;
+        config.cursor_highlight |= self.cursor_highlight;
+        config.pip = self.pip.or(config.pip);
+        config.pip_margin = self.pip_margin.unwrap_or(config.pip_margin);
+        config.pip_border = self.pip_border.unwrap_or(config.pip_border);
+        if !self.watermarks.is_empty() {
+            config.watermarks = self.watermarks;
+        }
+        config.vsync |= self.vsync;
+        config.gl_version = self.gl_version.or(config.gl_version);
+        config.gl_profile = self.gl_profile.or(config.gl_profile);
+        config.msaa = self.msaa.unwrap_or(config.msaa);
+        config.always_redraw |= self.always_redraw;
+        config.refresh_geometry |= self.refresh_geometry;
+        config.show_fps |= self.show_fps;
+        config.overlay_clock |= self.overlay_clock;
+        if let Some(format) = self.clock_format {
+            config.clock_format = format;
+        }
+        config.clock_corner = self.clock_corner.unwrap_or(config.clock_corner);
+        config.stats |= self.stats;
+        config.quiet |= self.quiet;
+        config.detect_motion |= self.detect_motion;
+        config.motion_threshold = self.motion_threshold.unwrap_or(config.motion_threshold);
+        config.benchmark |= self.benchmark;
+        config.profile_gpu |= self.profile_gpu;
+        config.keep_aspect |= self.keep_aspect;
+        config.border_color = self.border_color.unwrap_or(config.border_color);
+        config.fullscreen = self.fullscreen.or(config.fullscreen);
+        config.record = self.record.or(config.record.take());
+        config.v4l2_format = self.v4l2_format.unwrap_or(config.v4l2_format);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// The settings after applying the arguments to `config`, as if it was read from a file
+    fn applied(args: &[&str], mut config: Settings) -> Settings {
+        let cli = Cli::try_parse_from(["screensplitter", "0"].iter().chain(args))
+            .expect("Arguments were rejected");
+        cli.apply(&mut config);
+        config
+    }
+
+    #[test]
+    fn arguments_are_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn window_ids_parse_in_hex_and_decimal() {
+        assert_eq!(parse_window_id("0x2a"), Ok(42));
+        assert_eq!(parse_window_id("0X2A"), Ok(42));
+        assert_eq!(parse_window_id("42"), Ok(42));
+        assert!(parse_window_id("2a").is_err());
+        assert!(parse_window_id("0x").is_err());
+    }
+
+    #[test]
+    fn monitors_are_selected_by_id_or_name() {
+        assert!(matches!("1".parse(), Ok(MonitorSelector::Id(1))));
+        assert!(matches!("HDMI-1".parse(), Ok(MonitorSelector::Name(name)) if name == "HDMI-1"));
+        assert!("".parse::<MonitorSelector>().is_err());
+    }
+
+    #[test]
+    fn arguments_override_the_configuration_file() {
+        let file = Settings {
+            target_fps: 10,
+            crop: Some(Rect {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+            }),
+            ..Settings::default()
+        };
+
+        let config = applied(&["--fps", "30"], file.clone());
+        assert_eq!(config.target_fps, 30);
+        assert_eq!(config.crop, file.crop);
+
+        let config = applied(&[], file);
+        assert_eq!(config.target_fps, 10);
+    }
+
+    #[test]
+    fn explicit_fps_overrides_matching_the_display() {
+        let file = Settings {
+            fps_match_display: true,
+            ..Settings::default()
+        };
+        assert!(applied(&[], file.clone()).fps_match_display);
+        assert!(!applied(&["--fps", "30"], file).fps_match_display);
+    }
+
+    #[test]
+    fn flags_only_switch_on() {
+        let file = Settings {
+            show_cursor: true,
+            ..Settings::default()
+        };
+        let config = applied(&["--onscreen"], file);
+        assert!(config.show_cursor);
+        assert!(!config.offscreen);
+    }
+
+    #[test]
+    fn grayscale_is_an_effect() {
+        let file = Settings {
+            effect: Effect::Sepia,
+            ..Settings::default()
+        };
+        assert_eq!(
+            applied(&["--grayscale"], file.clone()).effect,
+            Effect::Grayscale
+        );
+        assert_eq!(applied(&[], file).effect, Effect::Sepia);
+    }
+
+    #[test]
+    fn conflicting_arguments_are_rejected() {
+        let parse = |args: &[&str]| Cli::try_parse_from(["screensplitter"].iter().chain(args));
+        assert!(parse(&["--all-monitors", "--crop", "0,0,10,10"]).is_err());
+        assert!(parse(&["0", "--window-id", "0x2a"]).is_err());
+        assert!(parse(&[]).is_err());
+    }
+}
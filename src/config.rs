@@ -0,0 +1,1886 @@
+//! Settings that control the shared image, the configuration files they are stored in and the
+//! geometry calculations based on them
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use ::x11::xlib;
+use clap::ValueEnum;
+use directories::ProjectDirs;
+use glium::glutin;
+use glium::glutin::event::VirtualKeyCode;
+use glium::texture::ClientFormat;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter};
+use serde::{Deserialize, Serialize};
+use x11cap::Bgr8;
+
+use crate::render::MAX_REGIONS;
+
+/// Highest supported target frames per second
+///
+/// Hardly any monitor refreshes faster than this, so higher rates would only burn CPU time.
+pub const MAX_TARGET_FPS: u32 = 240;
+
+/// Highest supported magnification, beyond it single pixels cover most of the window
+pub const MAX_ZOOM: f32 = 32.0;
+
+/// Largest number of frames the capture thread queues, each one is a full copy of the pixels
+pub const MAX_FRAME_QUEUE: usize = 16;
+
+/// Everything that controls how the captured image is shared
+///
+/// These can also be loaded from a configuration file, so the field names double as its keys.
+/// Missing keys keep their default values.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Settings {
+    /// Derived from the capture target
+    #[serde(skip)]
+    pub window_title: String,
+    /// Templates the window titles are built from, see `expand_title`
+    ///
+    /// Every title gets its own window, all showing the same image.
+    #[serde(rename = "title")]
+    pub titles: Vec<String>,
+    /// Appended to every window title to tell several running instances apart, see
+    /// `append_instance_name`
+    pub instance_name: Option<String>,
+    #[serde(rename = "fps")]
+    pub target_fps: u32,
+    /// Use the refresh rate of the captured monitors as the target frame rate instead
+    pub fps_match_display: bool,
+    /// Number of frames to capture before exiting, 0 to run until stopped
+    pub max_frames: u32,
+    /// Only capture every this many frames and show the last captured one in between
+    pub capture_every: u32,
+    /// Number of frames a separate capture thread queues for drawing, 0 to capture on the thread
+    /// that draws
+    pub frame_queue: usize,
+    /// How often to try again if the X server is not ready yet when starting, e.g. at login
+    pub startup_retries: u32,
+    /// Seconds without changes after which no more frames are written to a video device, stream
+    /// or stdout until the image changes again, 0 to always write them
+    pub idle_timeout: u32,
+    /// What to do when the captured monitor or window disappears, e.g. when it is unplugged
+    pub on_source_lost: SourceLostAction,
+    pub offscreen: bool,
+    /// Show an onscreen preview of every shared window next to the offscreen ones
+    pub preview: bool,
+    /// Where the offscreen windows are moved to
+    pub offscreen_placement: OffscreenPlacement,
+    /// Keep the onscreen window above all other windows
+    pub always_on_top: bool,
+    /// Opacity of the onscreen window between 0 and 1, applied by the compositor
+    pub opacity: f32,
+    /// Keys that control the shared image while one of the windows has the keyboard focus
+    pub keys: Keymap,
+    /// Directory the snapshots are saved to, no snapshots are taken without one
+    pub snapshot_dir: Option<PathBuf>,
+    pub crop: Option<Rect>,
+    /// Brightest value of a color channel that still counts as black when cropping automatically
+    pub autocrop_threshold: u8,
+    pub grid: Option<Grid>,
+    /// Show each of several monitors in its own window instead of side by side in one
+    pub split_monitors: bool,
+    pub pixel_format: PixelFormat,
+    /// Swap the color channels and apply the color adjustments, without it the captured pixels are
+    /// shown exactly as they are
+    pub color_correction: bool,
+    pub filter: Filter,
+    /// Factor between the size of the shared window and the size of the captured area
+    pub scale: f64,
+    /// Largest width and height of the textures, larger regions are scaled down before uploading
+    pub max_texture_dim: Option<u32>,
+    /// Magnification of the shared image, 1 shows all of it
+    pub zoom: f32,
+    /// Center of the magnified part in pixels of the captured area, the middle if not set
+    pub pan: Option<Point>,
+    pub effect: Effect,
+    pub brightness: f32,
+    pub contrast: f32,
+    /// Gamma the image is corrected with, values above 1 brighten the dark parts
+    pub gamma: f32,
+    /// Color temperature of white in Kelvin, lower values make the image warmer and higher ones
+    /// cooler, see `color_temp_factors`
+    pub color_temp: u32,
+    /// Strength of the unsharp mask that restores the edges lost when downscaling, 0 to disable
+    pub sharpen: f32,
+    /// Replace the image with a line drawing of its edges, for viewers with low vision
+    pub edges: bool,
+    /// Strength of the Sobel gradient at which a pixel counts as an edge, 1 being black next to white
+    pub edge_threshold: f32,
+    /// Draw the edges on top of the image instead of on black
+    pub edges_overlay: bool,
+    /// Add a faint pattern to the colors drawn into the windows, so gradients don't show bands
+    pub dither: bool,
+    /// Synchronize buffer swaps with the refresh rate of the monitor
+    ///
+    /// The frame limiter still applies on top of this, so the lower of the target frame rate and
+    /// the refresh rate wins. Since every window waits for its own swap, mirroring into several
+    /// windows with vsync enabled can lower the frame rate further.
+    pub vsync: bool,
+    /// OpenGL version to ask for instead of leaving the choice to the driver
+    pub gl_version: Option<GlVersion>,
+    pub gl_profile: Option<GlProfile>,
+    /// Number of samples per pixel of the windows, smoothing edges when the image is scaled or
+    /// letterboxed. 0 disables multisampling.
+    pub msaa: u16,
+    /// Draw every frame, even if nothing changed since the last one
+    pub always_redraw: bool,
+    /// Check once per second whether the size of the capture target changed and follow it
+    pub refresh_geometry: bool,
+    /// Video file the captured frames are recorded to
+    pub record: Option<PathBuf>,
+    /// Pixel format of the frames written to a v4l2loopback device
+    pub v4l2_format: VideoFormat,
+    /// Draw the achieved frame rate in the corner of the shared windows
+    pub show_fps: bool,
+    /// Draw the current time into the shared image, including recordings
+    pub overlay_clock: bool,
+    /// strftime format of the clock
+    pub clock_format: String,
+    /// Corner the clock is drawn in
+    pub clock_corner: Corner,
+    /// Print detailed frame timing statistics on exit
+    pub stats: bool,
+    /// Only print errors, and the statistics if they were asked for
+    pub quiet: bool,
+    /// Log when something starts and stops moving in the captured image
+    pub detect_motion: bool,
+    /// Fraction of the image that has to change to count as motion
+    pub motion_threshold: f32,
+    /// Print the time spent in each stage of the frame loop on exit
+    pub benchmark: bool,
+    /// Periodically print how long the GPU takes to draw the image, measured with timer queries
+    pub profile_gpu: bool,
+    /// Letterbox the image instead of stretching it when a window doesn't match its aspect ratio
+    pub keep_aspect: bool,
+    /// Color of the bars added by `keep_aspect`
+    pub border_color: Color,
+    /// ID of the monitor the window is shown fullscreen on, see `list_monitors`
+    pub fullscreen: Option<usize>,
+    /// Clockwise rotation of the shared image in degrees, one of 0, 90, 180 and 270
+    #[serde(rename = "rotate")]
+    pub rotation: u32,
+    /// Mirror the shared image left to right, after rotating it
+    #[serde(rename = "flip-h")]
+    pub flip_horizontal: bool,
+    /// Mirror the shared image top to bottom, after rotating it
+    #[serde(rename = "flip-v")]
+    pub flip_vertical: bool,
+    /// Regions of the shared area that are blurred, to hide sensitive content
+    pub blur: Vec<Rect>,
+    /// Regions of the shared area that are painted over with `mask_color`
+    #[serde(rename = "mask")]
+    pub masks: Vec<Rect>,
+    pub mask_color: Color,
+    /// Make the pixels of the shared windows that have this color transparent, for compositing
+    pub chroma_key: Option<ChromaKey>,
+    /// Draw the mouse cursor into the shared windows, X11 leaves it out of captured images
+    pub show_cursor: bool,
+    /// Draw a pulsing ring around the mouse cursor
+    pub cursor_highlight: bool,
+    /// Second monitor shown as an inset on top of the shared image
+    pub pip: Option<PictureInPicture>,
+    /// Distance between the inset and the edges of the window, in pixels
+    pub pip_margin: u32,
+    /// Width of the white border around the inset, in pixels
+    pub pip_border: u32,
+    /// Images drawn on top of the shared image, e.g. a logo
+    #[serde(rename = "watermark")]
+    pub watermarks: Vec<Watermark>,
+}
+
+impl Default for Settings {
+    /// The defaults of the command line arguments
+    fn default() -> Self {
+        Settings {
+            window_title: String::new(),
+            titles: Vec::new(),
+            instance_name: None,
+            target_fps: 30,
+            fps_match_display: false,
+            max_frames: 0,
+            capture_every: 1,
+            frame_queue: 0,
+            startup_retries: 0,
+            idle_timeout: 0,
+            on_source_lost: SourceLostAction::Exit,
+            offscreen: true,
+            preview: false,
+            offscreen_placement: OffscreenPlacement::Outside,
+            always_on_top: false,
+            opacity: 1.0,
+            keys: Keymap::default(),
+            snapshot_dir: None,
+            crop: None,
+            autocrop_threshold: 16,
+            grid: None,
+            split_monitors: false,
+            pixel_format: PixelFormat::Bgr,
+            color_correction: true,
+            filter: Filter::Linear,
+            scale: 1.0,
+            max_texture_dim: None,
+            zoom: 1.0,
+            pan: None,
+            effect: Effect::None,
+            brightness: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+            color_temp: NEUTRAL_COLOR_TEMP,
+            sharpen: 0.0,
+            dither: false,
+            edges: false,
+            edge_threshold: 0.2,
+            edges_overlay: false,
+            vsync: false,
+            gl_version: None,
+            gl_profile: None,
+            msaa: 0,
+            always_redraw: false,
+            refresh_geometry: false,
+            record: None,
+            v4l2_format: VideoFormat::Rgb24,
+            show_fps: false,
+            overlay_clock: false,
+            clock_format: "%H:%M:%S".to_string(),
+            clock_corner: Corner::TopRight,
+            stats: false,
+            quiet: false,
+            detect_motion: false,
+            motion_threshold: 0.02,
+            benchmark: false,
+            profile_gpu: false,
+            keep_aspect: false,
+            border_color: Color {
+                red: 0,
+                green: 0,
+                blue: 0,
+            },
+            fullscreen: None,
+            rotation: 0,
+            flip_horizontal: false,
+            flip_vertical: false,
+            blur: Vec::new(),
+            masks: Vec::new(),
+            chroma_key: None,
+            mask_color: Color {
+                red: 0,
+                green: 0,
+                blue: 0,
+            },
+            show_cursor: false,
+            cursor_highlight: false,
+            pip: None,
+            pip_margin: 16,
+            pip_border: 2,
+            watermarks: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Check the values clap can't check, including everything read from a configuration file
+    pub fn validate(&self) -> Result<(), String> {
+        if self.target_fps == 0 {
+            return Err("Target frames per second must be at least 1".into());
+        }
+
+        if self.capture_every == 0 {
+            return Err("Frames can be captured at most every frame".into());
+        }
+
+        if self.preview && (!self.offscreen || self.fullscreen.is_some()) {
+            return Err("The preview is shown next to offscreen windows, not onscreen ones".into());
+        }
+
+        if self.max_texture_dim == Some(0) {
+            return Err("Textures must be at least 1 pixel wide and high".into());
+        }
+
+        if self.frame_queue > MAX_FRAME_QUEUE {
+            return Err(format!(
+                "At most {} frames can be queued by the capture thread",
+                MAX_FRAME_QUEUE
+            ));
+        }
+
+        if !self.scale.is_finite() || self.scale <= 0.0 {
+            return Err("Scale must be a positive number".into());
+        }
+
+        if !(self.zoom >= 1.0 && self.zoom <= MAX_ZOOM) {
+            return Err(format!("Zoom must be between 1 and {}", MAX_ZOOM));
+        }
+
+        if !self.brightness.is_finite() {
+            return Err("Brightness must be a number".into());
+        }
+
+        if !self.contrast.is_finite() {
+            return Err("Contrast must be a number".into());
+        }
+
+        if !self.gamma.is_finite() || self.gamma <= 0.0 {
+            return Err("Gamma must be a positive number".into());
+        }
+
+        if !COLOR_TEMP_RANGE.contains(&self.color_temp) {
+            return Err(format!(
+                "Color temperature must be between {} and {} Kelvin",
+                COLOR_TEMP_RANGE.start(),
+                COLOR_TEMP_RANGE.end()
+            ));
+        }
+
+        if !self.sharpen.is_finite() || self.sharpen < 0.0 {
+            return Err("Sharpen amount must not be negative".into());
+        }
+
+        if self
+            .gl_version
+            .is_some_and(|version| version < GlVersion::MINIMUM)
+        {
+            return Err(format!(
+                "The shaders need at least OpenGL {}",
+                GlVersion::MINIMUM
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.motion_threshold) {
+            return Err("The motion threshold must be between 0 and 1".into());
+        }
+
+        if !self.edge_threshold.is_finite() || self.edge_threshold < 0.0 {
+            return Err("Edge threshold must not be negative".into());
+        }
+
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err("Opacity must be between 0 and 1".into());
+        }
+
+        if !self.rotation.is_multiple_of(90) || self.rotation >= 360 {
+            return Err("Rotation must be one of 0, 90, 180 and 270 degrees".into());
+        }
+
+        if ![0, 2, 4, 8].contains(&self.msaa) {
+            return Err("Multisampling must use 0, 2, 4 or 8 samples".into());
+        }
+
+        if self
+            .grid
+            .is_some_and(|grid| grid.columns == 0 || grid.rows == 0)
+        {
+            return Err("Grid must be given as COLSxROWS with at least one column and row".into());
+        }
+
+        if self.split_monitors && (self.grid.is_some() || self.crop.is_some()) {
+            return Err(
+                "Monitors shown in their own windows can't be cropped or split into a grid".into(),
+            );
+        }
+
+        if self.blur.len() > MAX_REGIONS {
+            return Err(format!(
+                "At most {} blur regions are supported",
+                MAX_REGIONS
+            ));
+        }
+
+        if self.clock_format.contains('\0') {
+            return Err("The clock format must not contain null characters".into());
+        }
+
+        if !self.color_correction && (!self.blur.is_empty() || !self.masks.is_empty()) {
+            return Err("Blurred and masked regions need the color correction".into());
+        }
+
+        let bindings: Vec<(Action, Key)> = self
+            .key_bindings()
+            .flat_map(|(action, keys)| keys.0.iter().map(move |&key| (action, key)))
+            .collect();
+        for (index, &(action, key)) in bindings.iter().enumerate() {
+            let taken = bindings[..index]
+                .iter()
+                .find(|&&(other, other_key)| other_key == key && other != action);
+            if let Some((other, _)) = taken {
+                return Err(format!(
+                    "The {} key can't be bound to both {} and {}",
+                    String::from(key),
+                    other.name(),
+                    action.name()
+                ));
+            }
+        }
+
+        if !self.color_correction && self.dither {
+            return Err("Dithering needs the color correction".into());
+        }
+
+        if !self.color_correction && self.chroma_key.is_some() {
+            return Err("The chroma key needs the color correction".into());
+        }
+
+        if self.masks.len() > MAX_REGIONS {
+            return Err(format!(
+                "At most {} mask regions are supported",
+                MAX_REGIONS
+            ));
+        }
+
+        if self
+            .pip
+            .is_some_and(|pip| !(pip.size > 0.0 && pip.size <= 1.0))
+        {
+            return Err("The size of the picture-in-picture inset must be between 0 and 1".into());
+        }
+
+        if self
+            .watermarks
+            .iter()
+            .any(|watermark| !(0.0..=1.0).contains(&watermark.opacity))
+        {
+            return Err("The opacity of watermarks must be between 0 and 1".into());
+        }
+
+        Ok(())
+    }
+
+    /// Factors the red, green and blue channels are multiplied with to shift the white point to
+    /// `color_temp`, all 1 for `NEUTRAL_COLOR_TEMP`
+    ///
+    /// The color of a black body at the temperature is approximated with Tanner Helland's fit of
+    /// the CIE data. The factors apply to sRGB encoded values, the largest one is always 1 so
+    /// nothing gets brighter.
+    pub fn color_temp_factors(&self) -> [f32; 3] {
+        let white = black_body_color(self.color_temp);
+        let neutral = black_body_color(NEUTRAL_COLOR_TEMP);
+        let factors = [
+            white[0] / neutral[0],
+            white[1] / neutral[1],
+            white[2] / neutral[2],
+        ];
+        let largest = factors.iter().cloned().fold(0.0, f32::max);
+        factors.map(|factor| factor / largest)
+    }
+
+    /// The keys of the actions that are available, snapshots can only be taken with a directory
+    /// to save them to
+    pub fn key_bindings(&self) -> impl Iterator<Item = (Action, &Keys)> {
+        let snapshots = self.snapshot_dir.is_some();
+        IntoIterator::into_iter(self.keys.bindings())
+            .filter(move |&(action, _)| action != Action::Snapshot || snapshots)
+    }
+
+    /// What a key does, nothing if it is not bound to an available action
+    pub fn key_action(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.key_bindings()
+            .find(|(_, keys)| keys.contains(key))
+            .map(|(action, _)| action)
+    }
+
+    /// Center of the magnified part in pixels of an area of the given size
+    ///
+    /// The center is kept far enough from the edges that the magnified part stays inside the area.
+    pub fn zoom_center(&self, width: u32, height: u32) -> (f32, f32) {
+        let clamp = |pan: Option<u32>, size: u32| {
+            let size = size as f32;
+            let half = size / self.zoom / 2.0;
+            pan.map_or(size / 2.0, |pan| pan as f32)
+                .clamp(half, size - half)
+        };
+        (
+            clamp(self.pan.map(|pan| pan.x), width),
+            clamp(self.pan.map(|pan| pan.y), height),
+        )
+    }
+}
+
+/// Contents of a configuration file given with `--config`, or of a named profile
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigFile {
+    pub source: Option<CaptureTarget>,
+    #[serde(flatten)]
+    pub settings: Settings,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| format!("Unable to read {}: {}", path.display(), error))?;
+        toml::from_str(&contents).map_err(|error| {
+            format!("Invalid configuration in {}: {}", path.display(), error).into()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)
+                .map_err(|error| format!("Unable to create {}: {}", directory.display(), error))?;
+        }
+
+        let contents = toml::to_string_pretty(self)?;
+        fs::write(path, contents)
+            .map_err(|error| format!("Unable to write {}: {}", path.display(), error).into())
+    }
+}
+
+/// Directory the named profiles are stored in, usually `~/.config/screensplitter`
+fn profile_directory() -> Result<PathBuf, Box<dyn Error>> {
+    ProjectDirs::from("", "", "screensplitter")
+        .map(|directories| directories.config_dir().to_path_buf())
+        .ok_or_else(|| "Unable to find the configuration directory".into())
+}
+
+/// Path of the file a named profile is stored in
+pub fn profile_path(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if name.is_empty() || name.starts_with('.') || name.contains(std::path::is_separator) {
+        return Err(format!("Invalid profile name \"{}\"", name).into());
+    }
+
+    Ok(profile_directory()?.join(format!("{}.toml", name)))
+}
+
+/// Names of all saved profiles, sorted alphabetically
+pub fn list_profiles() -> Result<Vec<String>, Box<dyn Error>> {
+    let directory = profile_directory()?;
+    let entries = match fs::read_dir(&directory) {
+        Ok(entries) => entries,
+        // Nothing was saved yet
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => {
+            return Err(format!("Unable to read {}: {}", directory.display(), error).into())
+        }
+    };
+
+    let mut profiles = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                profiles.push(name.to_owned());
+            }
+        }
+    }
+    profiles.sort();
+
+    Ok(profiles)
+}
+
+/// Order of the color channels in the captured frames
+///
+/// x11cap does not expose the channel order of the images it captures, so we cannot detect it and
+/// let the user choose instead.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    Bgr,
+    Rgb,
+}
+
+/// How the color channels of a captured pixel are packed into bytes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelPacking {
+    /// Three bytes followed by a padding byte, which OpenGL takes for alpha
+    Padded,
+    /// Three bytes without padding
+    Packed,
+}
+
+impl PixelPacking {
+    pub const fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelPacking::Padded => 4,
+            PixelPacking::Packed => 3,
+        }
+    }
+
+    /// Format to upload pixels with this packing to OpenGL in, one byte per channel
+    pub const fn client_format(self) -> ClientFormat {
+        match self {
+            PixelPacking::Padded => ClientFormat::U8U8U8U8,
+            PixelPacking::Packed => ClientFormat::U8U8U8,
+        }
+    }
+
+    /// Size of `pixels` pixels in bytes
+    pub const fn byte_len(self, pixels: usize) -> usize {
+        pixels * self.bytes_per_pixel()
+    }
+}
+
+/// Packing of the pixels in the frames captured by x11cap, and by XGetImage for windows
+///
+/// Everything that measures, copies or uploads frames goes by this instead of the size of x11cap's
+/// `Bgr8`. Any other size than the ones of the packings fails to compile.
+pub const CAPTURED_PACKING: PixelPacking = match mem::size_of::<Bgr8>() {
+    4 => PixelPacking::Padded,
+    3 => PixelPacking::Packed,
+    _ => panic!("x11cap uses pixels of an unsupported size"),
+};
+
+/// Pixel format of the frames written to a video device
+///
+/// Many video conferencing applications only accept the YUV formats. Both of them store the
+/// colors at a lower resolution, so the width and for NV12 also the height are rounded down to
+/// an even number of pixels.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoFormat {
+    Rgb24,
+    Yuyv,
+    Nv12,
+}
+
+/// Where offscreen windows are moved to, so that none of their pixels are visible
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OffscreenPlacement {
+    /// Right of the rightmost edge of all monitors, so no monitor can show any part of the window
+    Outside,
+    /// Above and left of the screen origin, which some layouts with monitors left of or above
+    /// the primary one still show a part of
+    Negative,
+    /// Below and right of the bottom right corner of the screen, leaving a single pixel visible
+    /// for compositors that don't render windows entirely outside of the screen
+    Corner,
+    /// Managed by the window manager and minimized instead of moved, for compositors that only
+    /// render thumbnails of managed windows
+    Minimized,
+}
+
+/// What happens when the capture source stops delivering frames for good
+///
+/// A source counts as lost when the captured window was closed or when capturing keeps failing,
+/// which is what happens when the captured monitor is unplugged.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceLostAction {
+    /// Stop sharing
+    Exit,
+    /// Show a black image and keep trying to open the source again
+    Blank,
+    /// Keep showing the last image and keep trying to open the source again
+    Wait,
+}
+
+/// How the captured image is sampled when it is drawn at a different size
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    /// Keeps pixels sharp, best for drawing the image at its original size
+    Nearest,
+    /// Interpolates between pixels, best for scaling the image down
+    Linear,
+}
+
+impl Filter {
+    pub fn magnify(self) -> MagnifySamplerFilter {
+        match self {
+            Filter::Nearest => MagnifySamplerFilter::Nearest,
+            Filter::Linear => MagnifySamplerFilter::Linear,
+        }
+    }
+
+    pub fn minify(self) -> MinifySamplerFilter {
+        match self {
+            Filter::Nearest => MinifySamplerFilter::Nearest,
+            Filter::Linear => MinifySamplerFilter::Linear,
+        }
+    }
+}
+
+/// Version of the OpenGL context, written as `MAJOR.MINOR` in configuration files
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct GlVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl GlVersion {
+    /// Oldest version with everything the shaders use, e.g. `in` and `out` variables
+    pub const MINIMUM: GlVersion = GlVersion { major: 3, minor: 0 };
+
+    /// Version of GLSL that comes with this version of OpenGL, as given to `#version`
+    pub fn glsl_version(self) -> u32 {
+        match (self.major, self.minor) {
+            (3, 0) => 130,
+            (3, 1) => 140,
+            (3, 2) => 150,
+            (major, minor) => u32::from(major) * 100 + u32::from(minor) * 10,
+        }
+    }
+}
+
+impl fmt::Display for GlVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for GlVersion {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected MAJOR.MINOR, e.g. 3.3";
+
+        let (major, minor) = s.split_once('.').ok_or(ERROR)?;
+        Ok(GlVersion {
+            major: major.parse().map_err(|_| ERROR)?,
+            minor: minor.parse().map_err(|_| ERROR)?,
+        })
+    }
+}
+
+impl TryFrom<String> for GlVersion {
+    type Error = &'static str;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<GlVersion> for String {
+    fn from(version: GlVersion) -> Self {
+        version.to_string()
+    }
+}
+
+/// Profile of the OpenGL context
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GlProfile {
+    /// Only the functionality of the requested version, without the deprecated parts
+    Core,
+    /// Everything the driver supports, including the deprecated parts
+    Compatibility,
+}
+
+impl GlProfile {
+    pub fn glutin(self) -> glutin::GlProfile {
+        match self {
+            GlProfile::Core => glutin::GlProfile::Core,
+            GlProfile::Compatibility => glutin::GlProfile::Compatibility,
+        }
+    }
+}
+
+/// Color effect applied to the shared image
+///
+/// The values are passed to the fragment shader as they are.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    None = 0,
+    Invert = 1,
+    Sepia = 2,
+    Grayscale = 3,
+}
+
+/// An sRGB color with 8 bits per channel, written as `RRGGBB` in configuration files
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    /// The color in linear RGB, as expected by the fragment shader
+    pub fn to_linear(self) -> [f32; 3] {
+        let linear = |channel: u8| srgb_to_linear(f32::from(channel) / 255.0);
+        [linear(self.red), linear(self.green), linear(self.blue)]
+    }
+}
+
+/// Decode an sRGB channel value between 0 and 1 into linear RGB
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode a linear RGB channel value between 0 and 1 into sRGB
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Color temperature of daylight, which leaves the colors as they are
+pub const NEUTRAL_COLOR_TEMP: u32 = 6500;
+
+/// Color temperatures in Kelvin the approximation in `black_body_color` is good for
+pub const COLOR_TEMP_RANGE: RangeInclusive<u32> = 1000..=40000;
+
+/// sRGB color of a black body at the temperature in Kelvin, with channels from 0 to 255
+fn black_body_color(kelvin: u32) -> [f32; 3] {
+    let t = kelvin as f32 / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let green = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12217 * (t - 60.0).powf(-0.075514846)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.0448
+    };
+    [red, green, blue].map(|channel| channel.clamp(0.0, 255.0))
+}
+
+impl FromStr for Color {
+    type Err = &'static str;
+
+    /// Parse a color given in hexadecimal as `RRGGBB`, optionally prefixed with `#`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected a color in hexadecimal as RRGGBB";
+
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ERROR);
+        }
+
+        let channel =
+            |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).map_err(|_| ERROR);
+        Ok(Color {
+            red: channel(0)?,
+            green: channel(2)?,
+            blue: channel(4)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = &'static str;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        format!("{:02x}{:02x}{:02x}", color.red, color.green, color.blue)
+    }
+}
+
+/// A key on the keyboard, written as its lowercase name in configuration files
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Key(pub VirtualKeyCode);
+
+/// Names of the keys that can be bound
+const KEY_NAMES: [(&str, VirtualKeyCode); 59] = [
+    ("a", VirtualKeyCode::A),
+    ("b", VirtualKeyCode::B),
+    ("c", VirtualKeyCode::C),
+    ("d", VirtualKeyCode::D),
+    ("e", VirtualKeyCode::E),
+    ("f", VirtualKeyCode::F),
+    ("g", VirtualKeyCode::G),
+    ("h", VirtualKeyCode::H),
+    ("i", VirtualKeyCode::I),
+    ("j", VirtualKeyCode::J),
+    ("k", VirtualKeyCode::K),
+    ("l", VirtualKeyCode::L),
+    ("m", VirtualKeyCode::M),
+    ("n", VirtualKeyCode::N),
+    ("o", VirtualKeyCode::O),
+    ("p", VirtualKeyCode::P),
+    ("q", VirtualKeyCode::Q),
+    ("r", VirtualKeyCode::R),
+    ("s", VirtualKeyCode::S),
+    ("t", VirtualKeyCode::T),
+    ("u", VirtualKeyCode::U),
+    ("v", VirtualKeyCode::V),
+    ("w", VirtualKeyCode::W),
+    ("x", VirtualKeyCode::X),
+    ("y", VirtualKeyCode::Y),
+    ("z", VirtualKeyCode::Z),
+    ("0", VirtualKeyCode::Key0),
+    ("1", VirtualKeyCode::Key1),
+    ("2", VirtualKeyCode::Key2),
+    ("3", VirtualKeyCode::Key3),
+    ("4", VirtualKeyCode::Key4),
+    ("5", VirtualKeyCode::Key5),
+    ("6", VirtualKeyCode::Key6),
+    ("7", VirtualKeyCode::Key7),
+    ("8", VirtualKeyCode::Key8),
+    ("9", VirtualKeyCode::Key9),
+    ("f1", VirtualKeyCode::F1),
+    ("f2", VirtualKeyCode::F2),
+    ("f3", VirtualKeyCode::F3),
+    ("f4", VirtualKeyCode::F4),
+    ("f5", VirtualKeyCode::F5),
+    ("f6", VirtualKeyCode::F6),
+    ("f7", VirtualKeyCode::F7),
+    ("f8", VirtualKeyCode::F8),
+    ("f9", VirtualKeyCode::F9),
+    ("f10", VirtualKeyCode::F10),
+    ("f11", VirtualKeyCode::F11),
+    ("f12", VirtualKeyCode::F12),
+    ("space", VirtualKeyCode::Space),
+    ("pause", VirtualKeyCode::Pause),
+    ("escape", VirtualKeyCode::Escape),
+    ("left", VirtualKeyCode::Left),
+    ("right", VirtualKeyCode::Right),
+    ("up", VirtualKeyCode::Up),
+    ("down", VirtualKeyCode::Down),
+    ("equals", VirtualKeyCode::Equals),
+    ("minus", VirtualKeyCode::Minus),
+    ("add", VirtualKeyCode::Add),
+    ("subtract", VirtualKeyCode::Subtract),
+];
+
+impl FromStr for Key {
+    type Err = &'static str;
+
+    /// Parse a letter, a digit, one of f1 to f12, space, pause, escape, an arrow key or one of
+    /// equals, minus, add and subtract, the last two being on the keypad
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KEY_NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|&(_, code)| Key(code))
+            .ok_or(
+                "expected a letter, a digit, f1 to f12, space, pause, escape, left, right, up, \
+                 down, equals, minus, add or subtract",
+            )
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = &'static str;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> Self {
+        KEY_NAMES
+            .iter()
+            .find(|&&(_, code)| code == key.0)
+            .map(|(name, _)| name.to_string())
+            .expect("Key without a name")
+    }
+}
+
+/// Any number of keys that do the same, written as a single key name or a list of them
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "KeyNames")]
+pub struct Keys(pub Vec<Key>);
+
+impl Keys {
+    pub fn contains(&self, key: VirtualKeyCode) -> bool {
+        self.0.contains(&Key(key))
+    }
+}
+
+impl From<Key> for Keys {
+    fn from(key: Key) -> Self {
+        Keys(vec![key])
+    }
+}
+
+/// The key names of `Keys` as written in configuration files
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyNames {
+    One(String),
+    Several(Vec<String>),
+}
+
+impl TryFrom<KeyNames> for Keys {
+    type Error = &'static str;
+
+    fn try_from(names: KeyNames) -> Result<Self, Self::Error> {
+        let names = match names {
+            KeyNames::One(name) => vec![name],
+            KeyNames::Several(names) => names,
+        };
+        names
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<_, _>>()
+            .map(Keys)
+    }
+}
+
+/// What the keys do while one of the shared windows has the keyboard focus, the `[keys]` section
+/// of configuration files
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Keymap {
+    /// Ends the program, like closing the shared window does
+    pub quit: Keys,
+    /// Freezes the shared image, or lets it continue
+    pub pause: Keys,
+    /// Saves a snapshot of what the shared windows show to `snapshot_dir`
+    pub snapshot: Keys,
+    /// Raises the target frame rate
+    pub faster: Keys,
+    /// Lowers the target frame rate
+    pub slower: Keys,
+    /// Move the magnified part while zoomed in
+    pub pan_left: Keys,
+    pub pan_right: Keys,
+    pub pan_up: Keys,
+    pub pan_down: Keys,
+}
+
+impl Keymap {
+    /// The keys of every action
+    pub fn bindings(&self) -> [(Action, &Keys); 9] {
+        [
+            (Action::Quit, &self.quit),
+            (Action::Pause, &self.pause),
+            (Action::Snapshot, &self.snapshot),
+            (Action::Faster, &self.faster),
+            (Action::Slower, &self.slower),
+            (Action::PanLeft, &self.pan_left),
+            (Action::PanRight, &self.pan_right),
+            (Action::PanUp, &self.pan_up),
+            (Action::PanDown, &self.pan_down),
+        ]
+    }
+}
+
+/// Something a key of the `Keymap` does
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    Quit,
+    Pause,
+    Snapshot,
+    Faster,
+    Slower,
+    PanLeft,
+    PanRight,
+    PanUp,
+    PanDown,
+}
+
+impl Action {
+    /// Name of the action in configuration files
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::Pause => "pause",
+            Action::Snapshot => "snapshot",
+            Action::Faster => "faster",
+            Action::Slower => "slower",
+            Action::PanLeft => "pan-left",
+            Action::PanRight => "pan-right",
+            Action::PanUp => "pan-up",
+            Action::PanDown => "pan-down",
+        }
+    }
+
+    /// Whether the action moves the magnified part
+    pub fn pans(self) -> bool {
+        matches!(
+            self,
+            Action::PanLeft | Action::PanRight | Action::PanUp | Action::PanDown
+        )
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            quit: Keys(vec![Key(VirtualKeyCode::Escape), Key(VirtualKeyCode::Q)]),
+            pause: Key(VirtualKeyCode::P).into(),
+            snapshot: Key(VirtualKeyCode::S).into(),
+            // + is on the same key as = on many layouts
+            faster: Keys(vec![Key(VirtualKeyCode::Equals), Key(VirtualKeyCode::Add)]),
+            slower: Keys(vec![
+                Key(VirtualKeyCode::Minus),
+                Key(VirtualKeyCode::Subtract),
+            ]),
+            pan_left: Key(VirtualKeyCode::Left).into(),
+            pan_right: Key(VirtualKeyCode::Right).into(),
+            pan_up: Key(VirtualKeyCode::Up).into(),
+            pan_down: Key(VirtualKeyCode::Down).into(),
+        }
+    }
+}
+
+/// Corner of the shared window something is placed in
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for Corner {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top-left" => Ok(Corner::TopLeft),
+            "top-right" => Ok(Corner::TopRight),
+            "bottom-left" => Ok(Corner::BottomLeft),
+            "bottom-right" => Ok(Corner::BottomRight),
+            _ => Err("expected top-left, top-right, bottom-left or bottom-right"),
+        }
+    }
+}
+
+impl Corner {
+    /// Position of the top left corner of an area of the given size in this corner of a
+    /// surrounding area, keeping `margin` to its edges
+    pub fn place(self, (width, height): (u32, u32), outer: (u32, u32), margin: u32) -> (u32, u32) {
+        let left = match self {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight => outer.0.saturating_sub(margin + width),
+        };
+        let top = match self {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight => outer.1.saturating_sub(margin + height),
+        };
+        (left, top)
+    }
+}
+
+/// A monitor shown as a small inset in a corner of the shared image
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PictureInPicture {
+    pub monitor: usize,
+    pub corner: Corner,
+    /// Width of the inset relative to the width of the window
+    pub size: f64,
+}
+
+impl FromStr for PictureInPicture {
+    type Err = &'static str;
+
+    /// Parse an inset given as `MONITOR_ID:CORNER:SIZE`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected MONITOR_ID:CORNER:SIZE, e.g. 1:bottom-right:0.25";
+
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            &[monitor, corner, size] => {
+                let size = size.parse::<f64>().map_err(|_| ERROR)?;
+                if !(size > 0.0 && size <= 1.0) {
+                    return Err("the size of the inset must be between 0 and 1");
+                }
+
+                Ok(PictureInPicture {
+                    monitor: monitor.parse().map_err(|_| ERROR)?,
+                    corner: corner.parse()?,
+                    size,
+                })
+            }
+            _ => Err(ERROR),
+        }
+    }
+}
+
+/// An image drawn in a corner of the shared image
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Watermark {
+    pub path: PathBuf,
+    pub corner: Corner,
+    pub opacity: f32,
+}
+
+impl FromStr for Watermark {
+    type Err = &'static str;
+
+    /// Parse a watermark given as `PATH:CORNER:OPACITY`
+    ///
+    /// The path is everything before the last two colons, so it may contain colons itself.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected PATH:CORNER:OPACITY, e.g. logo.png:bottom-right:0.8";
+
+        let parts: Vec<&str> = s.rsplitn(3, ':').collect();
+        match parts.as_slice() {
+            &[opacity, corner, path] if !path.is_empty() => {
+                let opacity = opacity.parse::<f32>().map_err(|_| ERROR)?;
+                if !(0.0..=1.0).contains(&opacity) {
+                    return Err("the opacity of the watermark must be between 0 and 1");
+                }
+
+                Ok(Watermark {
+                    path: PathBuf::from(path),
+                    corner: corner.parse()?,
+                    opacity,
+                })
+            }
+            _ => Err(ERROR),
+        }
+    }
+}
+
+/// A color that is made transparent in the shared windows, so compositing tools can key it out
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct ChromaKey {
+    pub color: Color,
+    /// Largest difference of a color channel from the key color that still matches, from 0 to 1
+    pub tolerance: f32,
+}
+
+impl FromStr for ChromaKey {
+    type Err = &'static str;
+
+    /// Parse a chroma key given as `RRGGBB:TOLERANCE`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected RRGGBB:TOLERANCE, e.g. 00ff00:0.1";
+
+        match s.split_once(':') {
+            Some((color, tolerance)) => {
+                let tolerance = tolerance.parse::<f32>().map_err(|_| ERROR)?;
+                if !(0.0..=1.0).contains(&tolerance) {
+                    return Err("the tolerance of the chroma key must be between 0 and 1");
+                }
+
+                Ok(ChromaKey {
+                    color: color.parse()?,
+                    tolerance,
+                })
+            }
+            None => Err(ERROR),
+        }
+    }
+}
+
+/// A rectangle in pixels, relative to the top left corner of the capture source
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// Check whether the rectangle lies completely inside an area of the given size
+    pub fn fits_within(&self, width: u32, height: u32) -> bool {
+        self.width > 0
+            && self.height > 0
+            && u64::from(self.x) + u64::from(self.width) <= u64::from(width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(height)
+    }
+
+    /// The rectangle with two opposite corners at the given points
+    pub fn spanning((x1, y1): (u32, u32), (x2, y2): (u32, u32)) -> Rect {
+        Rect {
+            x: x1.min(x2),
+            y: y1.min(y2),
+            width: x1.max(x2) - x1.min(x2),
+            height: y1.max(y2) - y1.min(y2),
+        }
+    }
+}
+
+impl FromStr for Rect {
+    type Err = &'static str;
+
+    /// Parse a rectangle given as `X,Y,W,H`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected X,Y,W,H";
+
+        let parts = s
+            .split(',')
+            .map(|part| part.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ERROR)?;
+
+        match parts.as_slice() {
+            &[x, y, width, height] => Ok(Rect {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(ERROR),
+        }
+    }
+}
+
+/// A point in pixels, relative to the top left corner of the capture source
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Point {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl FromStr for Point {
+    type Err = &'static str;
+
+    /// Parse a point given as `X,Y`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected X,Y";
+
+        match s.split_once(',') {
+            Some((x, y)) => Ok(Point {
+                x: x.trim().parse().map_err(|_| ERROR)?,
+                y: y.trim().parse().map_err(|_| ERROR)?,
+            }),
+            None => Err(ERROR),
+        }
+    }
+}
+
+/// Layout used to divide the captured area into equally sized tiles
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct Grid {
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl Grid {
+    /// Divide an area of the given size into tiles, listed row by row
+    ///
+    /// If the size is not divisible by the number of columns or rows the tiles differ by at most
+    /// one pixel, so no part of the area is lost.
+    fn tiles(&self, width: u32, height: u32) -> Vec<Rect> {
+        let edge = |index: u32, count: u32, size: u32| {
+            (u64::from(index) * u64::from(size) / u64::from(count)) as u32
+        };
+
+        let mut tiles = Vec::with_capacity((self.columns * self.rows) as usize);
+        for row in 0..self.rows {
+            let top = edge(row, self.rows, height);
+            let bottom = edge(row + 1, self.rows, height);
+            for column in 0..self.columns {
+                let left = edge(column, self.columns, width);
+                let right = edge(column + 1, self.columns, width);
+                tiles.push(Rect {
+                    x: left,
+                    y: top,
+                    width: right - left,
+                    height: bottom - top,
+                });
+            }
+        }
+        tiles
+    }
+}
+
+impl FromStr for Grid {
+    type Err = &'static str;
+
+    /// Parse a grid given as `COLSxROWS`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "expected COLSxROWS with at least one column and row";
+
+        let (columns, rows) = s.split_once('x').ok_or(ERROR)?;
+        let columns = columns.trim().parse::<u32>().map_err(|_| ERROR)?;
+        let rows = rows.trim().parse::<u32>().map_err(|_| ERROR)?;
+
+        if columns == 0 || rows == 0 {
+            return Err(ERROR);
+        }
+
+        Ok(Grid { columns, rows })
+    }
+}
+
+/// What the user asked us to mirror
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureTarget {
+    Monitor(usize),
+    /// Several monitors combined side by side into one image
+    Monitors(Vec<usize>),
+    Window(xlib::Window),
+    /// The whole virtual screen spanning all monitors, positions are relative to its top left
+    Screen,
+    /// A generated image that changes every frame, for testing without an X server
+    #[serde(rename = "test-pattern")]
+    TestPattern,
+    /// Whatever the user picks in the dialog of the screen cast portal, which works on Wayland
+    Portal,
+}
+
+impl CaptureTarget {
+    /// The monitor IDs joined by `+`, the window ID in hexadecimal, `screen`, `test-pattern` or
+    /// `portal`
+    pub fn id(&self) -> String {
+        match self {
+            CaptureTarget::Monitor(monitor_id) => monitor_id.to_string(),
+            CaptureTarget::Monitors(monitor_ids) => monitor_ids
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join("+"),
+            CaptureTarget::Window(window_id) => format!("0x{:x}", window_id),
+            CaptureTarget::Screen => "screen".to_string(),
+            CaptureTarget::TestPattern => "test-pattern".to_string(),
+            CaptureTarget::Portal => "portal".to_string(),
+        }
+    }
+}
+
+/// Build a window title from a template given with `--title`
+///
+/// `{monitor}` is replaced by the ID of the capture target, `{width}` and `{height}` by the size of
+/// the captured area, `{fps}` by the target frame rate and `{pid}` by the ID of the process.
+pub fn expand_title(
+    template: &str,
+    target: &CaptureTarget,
+    width: u32,
+    height: u32,
+    target_fps: u32,
+) -> String {
+    template
+        .replace("{monitor}", &target.id())
+        .replace("{width}", &width.to_string())
+        .replace("{height}", &height.to_string())
+        .replace("{fps}", &target_fps.to_string())
+        .replace("{pid}", &process::id().to_string())
+}
+
+/// Append the instance name to a window title, if there is one
+///
+/// `{pid}` in the name is replaced by the ID of the process, which is unique among all running
+/// instances.
+pub fn append_instance_name(title: &str, instance_name: Option<&str>) -> String {
+    match instance_name {
+        Some(name) => format!(
+            "{} [{}]",
+            title,
+            name.replace("{pid}", &process::id().to_string())
+        ),
+        None => title.to_string(),
+    }
+}
+
+/// Split the captured area into the regions shown by the tiles, a single one without a grid
+pub fn tile_regions(
+    grid: Option<Grid>,
+    width: u32,
+    height: u32,
+) -> Result<Vec<Rect>, Box<dyn Error>> {
+    match grid {
+        Some(grid) if grid.columns > width || grid.rows > height => Err(format!(
+            "Grid {}x{} has more tiles than the captured area of {}x{} has pixels",
+            grid.columns, grid.rows, width, height
+        )
+        .into()),
+        Some(grid) => Ok(grid.tiles(width, height)),
+        None => Ok(vec![Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }]),
+    }
+}
+
+/// Size of the shared image along one axis, given the size of the captured area
+pub fn scaled_size(size: u32, scale: f64) -> u32 {
+    ((f64::from(size) * scale).round() as u32).max(1)
+}
+
+/// Size of an image after rotating it by the given number of degrees
+pub fn rotated_size((width, height): (u32, u32), rotation: u32) -> (u32, u32) {
+    if rotation % 180 == 90 {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
+
+/// Size of an image shrunk to fit within `max_dim` pixels in both directions, keeping the aspect
+/// ratio
+pub fn capped_size((width, height): (u32, u32), max_dim: Option<u32>) -> (u32, u32) {
+    let longer = width.max(height);
+    match max_dim {
+        Some(max_dim) if longer > max_dim => {
+            let factor = f64::from(max_dim) / f64::from(longer);
+            (
+                scaled_size(width, factor).min(max_dim),
+                scaled_size(height, factor).min(max_dim),
+            )
+        }
+        _ => (width, height),
+    }
+}
+
+/// Shrink an image to a smaller size, averaging the pixels each of the new pixels covers
+///
+/// Like with `copy_region`, the previous contents of `data` are replaced and its allocation is
+/// kept.
+pub fn downscale(pixels: &[u8], size: (u32, u32), new_size: (u32, u32), data: &mut Vec<u8>) {
+    let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+    let (width, height) = (size.0 as usize, size.1 as usize);
+    let (new_width, new_height) = (new_size.0 as usize, new_size.1 as usize);
+
+    data.clear();
+    data.resize(new_width * new_height * bytes_per_pixel, 0);
+    for y in 0..new_height {
+        let (top, bottom) = (y * height / new_height, (y + 1) * height / new_height);
+        for x in 0..new_width {
+            let (left, right) = (x * width / new_width, (x + 1) * width / new_width);
+            let mut sums = [0u32; 4];
+            for row in top..bottom {
+                let start = (row * width + left) * bytes_per_pixel;
+                let end = (row * width + right) * bytes_per_pixel;
+                for pixel in pixels[start..end].chunks_exact(bytes_per_pixel) {
+                    for (sum, &value) in sums.iter_mut().zip(pixel) {
+                        *sum += u32::from(value);
+                    }
+                }
+            }
+
+            let count = ((bottom - top) * (right - left)) as u32;
+            let start = (y * new_width + x) * bytes_per_pixel;
+            for (value, sum) in data[start..start + bytes_per_pixel].iter_mut().zip(&sums) {
+                *value = (sum / count) as u8;
+            }
+        }
+    }
+}
+
+/// Copy the pixels inside `region` out of a frame that is `frame_width` pixels wide
+///
+/// The previous contents of `data` are replaced. Its allocation is kept, so reusing the same
+/// buffer for every frame does not cause any allocations.
+pub fn copy_region(pixels: &[u8], frame_width: u32, region: Rect, data: &mut Vec<u8>) {
+    let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+    let stride = frame_width as usize * bytes_per_pixel;
+    let row_length = region.width as usize * bytes_per_pixel;
+
+    data.clear();
+    for row in region.y..region.y + region.height {
+        let start = row as usize * stride + region.x as usize * bytes_per_pixel;
+        data.extend_from_slice(&pixels[start..start + row_length]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn rect_parses_with_spaces() {
+        assert_eq!("10, 20,300 ,400".parse(), Ok(rect(10, 20, 300, 400)));
+        assert!("10,20,300".parse::<Rect>().is_err());
+        assert!("10,20,300,400,5".parse::<Rect>().is_err());
+        assert!("-10,20,300,400".parse::<Rect>().is_err());
+    }
+
+    #[test]
+    fn rect_fits_within() {
+        assert!(rect(0, 0, 1920, 1080).fits_within(1920, 1080));
+        assert!(!rect(1, 0, 1920, 1080).fits_within(1920, 1080));
+        assert!(!rect(0, 0, 0, 1080).fits_within(1920, 1080));
+        // Large offsets must not overflow
+        assert!(!rect(u32::MAX, 0, 1, 1).fits_within(1920, 1080));
+    }
+
+    #[test]
+    fn rect_spans_corners_in_any_order() {
+        assert_eq!(Rect::spanning((30, 5), (10, 25)), rect(10, 5, 20, 20));
+    }
+
+    #[test]
+    fn point_parses() {
+        assert_eq!("3, 4".parse(), Ok(Point { x: 3, y: 4 }));
+        assert!("3".parse::<Point>().is_err());
+        assert!("3,y".parse::<Point>().is_err());
+    }
+
+    #[test]
+    fn grid_parses() {
+        let grid: Grid = "3x2".parse().unwrap();
+        assert_eq!((grid.columns, grid.rows), (3, 2));
+        assert!("0x2".parse::<Grid>().is_err());
+        assert!("3".parse::<Grid>().is_err());
+    }
+
+    #[test]
+    fn grid_tiles_cover_the_whole_area() {
+        let tiles = tile_regions(
+            Some(Grid {
+                columns: 3,
+                rows: 2,
+            }),
+            100,
+            51,
+        )
+        .unwrap();
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(tiles[0], rect(0, 0, 33, 25));
+        assert_eq!(tiles[1], rect(33, 0, 33, 25));
+        assert_eq!(tiles[5], rect(66, 25, 34, 26));
+        let area: u32 = tiles.iter().map(|tile| tile.width * tile.height).sum();
+        assert_eq!(area, 100 * 51);
+    }
+
+    #[test]
+    fn tile_regions_without_grid() {
+        assert_eq!(
+            tile_regions(None, 640, 480).unwrap(),
+            [rect(0, 0, 640, 480)]
+        );
+        assert!(tile_regions(
+            Some(Grid {
+                columns: 5,
+                rows: 1
+            }),
+            4,
+            4
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn color_parses_hex() {
+        let color = Color {
+            red: 0x12,
+            green: 0xab,
+            blue: 0xff,
+        };
+        assert_eq!("12abff".parse(), Ok(color));
+        assert_eq!("#12ABFF".parse(), Ok(color));
+        assert_eq!(String::from(color), "12abff");
+        assert!("12abf".parse::<Color>().is_err());
+        assert!("12abfg".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn chroma_key_parses() {
+        let key: ChromaKey = "00ff00:0.1".parse().unwrap();
+        assert_eq!(key.color, "00ff00".parse().unwrap());
+        assert_eq!(key.tolerance, 0.1);
+        assert!("00ff00".parse::<ChromaKey>().is_err());
+        assert!("00ff00:1.5".parse::<ChromaKey>().is_err());
+    }
+
+    #[test]
+    fn picture_in_picture_parses() {
+        let pip: PictureInPicture = "1:bottom-right:0.25".parse().unwrap();
+        assert_eq!(pip.monitor, 1);
+        assert_eq!(pip.corner, Corner::BottomRight);
+        assert_eq!(pip.size, 0.25);
+        assert!("1:middle:0.25".parse::<PictureInPicture>().is_err());
+        assert!("1:top-left:0".parse::<PictureInPicture>().is_err());
+        assert!("1:top-left".parse::<PictureInPicture>().is_err());
+    }
+
+    #[test]
+    fn watermark_path_may_contain_colons() {
+        let watermark: Watermark = "C:/logo:v2.png:top-left:0.5".parse().unwrap();
+        assert_eq!(watermark.path, PathBuf::from("C:/logo:v2.png"));
+        assert_eq!(watermark.corner, Corner::TopLeft);
+        assert_eq!(watermark.opacity, 0.5);
+        assert!(":top-left:0.5".parse::<Watermark>().is_err());
+        assert!("logo.png:top-left:2".parse::<Watermark>().is_err());
+    }
+
+    #[test]
+    fn corner_places_inside_the_margin() {
+        assert_eq!(Corner::TopLeft.place((10, 10), (100, 50), 4), (4, 4));
+        assert_eq!(Corner::BottomRight.place((10, 10), (100, 50), 4), (86, 36));
+        // Too large to fit, kept at the edge
+        assert_eq!(Corner::BottomRight.place((200, 10), (100, 50), 4), (0, 36));
+    }
+
+    #[test]
+    fn keys_parse_by_name() {
+        assert_eq!("F5".parse(), Ok(Key(VirtualKeyCode::F5)));
+        assert_eq!(String::from(Key(VirtualKeyCode::Subtract)), "subtract");
+        assert_eq!("Escape".parse(), Ok(Key(VirtualKeyCode::Escape)));
+        assert!("escape-key".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn keymap_takes_one_key_or_a_list() {
+        let file: ConfigFile = toml::from_str(
+            "[keys]\n\
+             pause = \"space\"\n\
+             faster = [\"f\", \"up\"]\n",
+        )
+        .unwrap();
+        let keys = file.settings.keys;
+        assert_eq!(keys.pause, Key(VirtualKeyCode::Space).into());
+        assert!(keys.faster.contains(VirtualKeyCode::F));
+        assert!(keys.faster.contains(VirtualKeyCode::Up));
+        // Actions that are not mentioned keep their default keys
+        assert_eq!(keys.snapshot, Keymap::default().snapshot);
+
+        assert!(toml::from_str::<ConfigFile>("[keys]\npause = \"nope\"\n").is_err());
+    }
+
+    #[test]
+    fn keys_bound_twice_are_rejected() {
+        let mut config = Settings::default();
+        config.keys.pan_up = Key(VirtualKeyCode::P).into();
+        assert!(config.validate().is_err());
+
+        let mut config = Settings::default();
+        config.keys.pause = Key(VirtualKeyCode::Q).into();
+        assert!(config.validate().is_err());
+
+        // Binding a key twice to the same action is fine
+        let mut config = Settings::default();
+        config.keys.pause = Keys(vec![Key(VirtualKeyCode::P), Key(VirtualKeyCode::P)]);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn keys_look_up_their_action() {
+        let mut config = Settings::default();
+        assert_eq!(
+            config.key_action(VirtualKeyCode::Escape),
+            Some(Action::Quit)
+        );
+        assert_eq!(config.key_action(VirtualKeyCode::Add), Some(Action::Faster));
+        assert_eq!(config.key_action(VirtualKeyCode::S), None);
+        config.snapshot_dir = Some(PathBuf::from("snapshots"));
+        assert_eq!(config.key_action(VirtualKeyCode::S), Some(Action::Snapshot));
+    }
+
+    #[test]
+    fn snapshot_key_is_free_without_snapshot_dir() {
+        let mut config = Settings::default();
+        config.keys.pause = Key(VirtualKeyCode::S).into();
+        assert_eq!(config.validate(), Ok(()));
+        config.snapshot_dir = Some(PathBuf::from("snapshots"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn default_settings_are_valid() {
+        assert_eq!(Settings::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let invalid = [
+            Settings {
+                target_fps: 0,
+                ..Settings::default()
+            },
+            Settings {
+                rotation: 45,
+                ..Settings::default()
+            },
+            Settings {
+                gamma: 0.0,
+                ..Settings::default()
+            },
+            Settings {
+                zoom: 0.5,
+                ..Settings::default()
+            },
+            Settings {
+                color_temp: 500,
+                ..Settings::default()
+            },
+            Settings {
+                split_monitors: true,
+                crop: Some(rect(0, 0, 10, 10)),
+                ..Settings::default()
+            },
+        ];
+        for config in &invalid {
+            assert!(config.validate().is_err(), "{:?} was accepted", config);
+        }
+    }
+
+    #[test]
+    fn scaled_size_rounds_and_stays_positive() {
+        assert_eq!(scaled_size(1920, 0.5), 960);
+        assert_eq!(scaled_size(3, 0.5), 2);
+        assert_eq!(scaled_size(1, 0.01), 1);
+    }
+
+    #[test]
+    fn rotated_size_swaps_for_quarter_turns() {
+        assert_eq!(rotated_size((1920, 1080), 90), (1080, 1920));
+        assert_eq!(rotated_size((1920, 1080), 180), (1920, 1080));
+        assert_eq!(rotated_size((1920, 1080), 270), (1080, 1920));
+    }
+
+    #[test]
+    fn capped_size_keeps_the_aspect_ratio() {
+        assert_eq!(capped_size((4000, 1000), Some(1000)), (1000, 250));
+        assert_eq!(capped_size((1000, 4001), Some(1000)), (250, 1000));
+        assert_eq!(capped_size((800, 600), Some(1000)), (800, 600));
+        assert_eq!(capped_size((4000, 1000), None), (4000, 1000));
+    }
+
+    #[test]
+    fn downscale_averages_the_covered_pixels() {
+        let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+        // Three by two pixels, with the same value in every channel
+        let pixels: Vec<u8> = [0, 100, 50, 200, 100, 50]
+            .iter()
+            .flat_map(|&value| vec![value; bytes_per_pixel])
+            .collect();
+        let mut data = vec![1; 100];
+        downscale(&pixels, (3, 2), (1, 1), &mut data);
+        assert_eq!(data, vec![83; bytes_per_pixel]);
+        downscale(&pixels, (3, 2), (3, 1), &mut data);
+        assert_eq!(data[..bytes_per_pixel], vec![100; bytes_per_pixel][..]);
+    }
+
+    #[test]
+    fn titles_are_expanded() {
+        let target = CaptureTarget::Monitors(vec![0, 2]);
+        assert_eq!(
+            expand_title("{monitor} {width}x{height} @{fps}", &target, 1920, 1080, 30),
+            "0+2 1920x1080 @30"
+        );
+        assert_eq!(
+            expand_title("{pid}", &CaptureTarget::Window(0x2a), 1, 1, 1),
+            process::id().to_string()
+        );
+        assert_eq!(CaptureTarget::Window(0x2a).id(), "0x2a");
+    }
+
+    #[test]
+    fn instance_name_is_appended() {
+        assert_eq!(append_instance_name("Monitor 0", None), "Monitor 0");
+        assert_eq!(
+            append_instance_name("Monitor 0", Some("left-{pid}")),
+            format!("Monitor 0 [left-{}]", process::id())
+        );
+    }
+}
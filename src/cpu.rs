@@ -0,0 +1,868 @@
+//! Processing of the captured frames on the CPU, for all outputs that don't draw them with
+//! OpenGL
+//!
+//! The results look the same as the shared windows, so the lookup tables and filters follow the
+//! fragment shader step by step.
+
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+
+use crate::capture::Frame;
+use crate::config::{
+    linear_to_srgb, rotated_size, scaled_size, srgb_to_linear, Corner, Effect, Filter, PixelFormat,
+    Rect, Settings, CAPTURED_PACKING,
+};
+use crate::render::{render_text, BLUR_RADIUS, OVERLAY_MARGIN};
+
+/// Number of evenly spaced linear values `ColorTable` has the sRGB encoding of
+///
+/// The steps have to be small enough for the darkest colors, where the encoding is the steepest.
+const ENCODING_STEPS: usize = 1 << 14;
+
+/// The color corrections of the fragment shader, looked up per channel instead of calculated for
+/// every pixel
+///
+/// Like the shader, which reads the frames from an sRGB texture and draws into an sRGB
+/// framebuffer, the color temperature, brightness, contrast, gamma and effect are applied to
+/// linear values.
+pub struct ColorTable {
+    /// Linear value of every value of the red, green and blue channel after the corrections
+    linear: [[f32; 256]; 3],
+    /// The corrected values encoded as sRGB again, with the effect applied if it works per
+    /// channel
+    encoded: [[u8; 256]; 3],
+    /// sRGB encoding of the linear values from 0 to 1 in `ENCODING_STEPS` steps
+    encoding: Vec<u8>,
+    /// Linear value of every channel value before the corrections, as read from the texture
+    decoding: [f32; 256],
+}
+
+impl ColorTable {
+    /// Encode a linear value the way the framebuffer does, which clamps it to the range from 0 to 1
+    fn encode(&self, linear: f32) -> u8 {
+        let step = (linear.clamp(0.0, 1.0) * (ENCODING_STEPS - 1) as f32).round();
+        self.encoding[step as usize]
+    }
+}
+
+/// Build the tables mapping every value of the red, green and blue channel to its value after the
+/// color corrections
+pub fn color_lookup_table(config: &Settings) -> ColorTable {
+    let mut linear = [[0.0; 256]; 3];
+    let mut encoded = [[0; 256]; 3];
+    let white_balance = config.color_temp_factors().map(srgb_to_linear);
+    for ((linear, encoded), factor) in linear.iter_mut().zip(&mut encoded).zip(white_balance) {
+        for (value, (linear, encoded)) in linear.iter_mut().zip(encoded.iter_mut()).enumerate() {
+            let color = srgb_to_linear(value as f32 / 255.0) * factor;
+            let adjusted = (color - 0.5) * config.contrast + 0.5 + config.brightness;
+            *linear = adjusted.clamp(0.0, 1.0).powf(1.0 / config.gamma);
+            let shown = match config.effect {
+                Effect::Invert => 1.0 - *linear,
+                _ => *linear,
+            };
+            *encoded = (linear_to_srgb(shown) * 255.0).round() as u8;
+        }
+    }
+    let encoding = (0..ENCODING_STEPS)
+        .map(|step| {
+            let linear = step as f32 / (ENCODING_STEPS - 1) as f32;
+            (linear_to_srgb(linear) * 255.0).round() as u8
+        })
+        .collect();
+
+    let mut decoding = [0.0; 256];
+    for (value, linear) in decoding.iter_mut().enumerate() {
+        *linear = srgb_to_linear(value as f32 / 255.0);
+    }
+
+    ColorTable {
+        linear,
+        encoded,
+        encoding,
+        decoding,
+    }
+}
+
+/// Convert a captured frame to packed RGB with 3 bytes per pixel, applying the same color
+/// corrections as the fragment shader
+///
+/// The previous contents of `data` are replaced, its allocation is reused.
+fn convert_to_rgb24(
+    pixels: &[u8],
+    config: &Settings,
+    lookup_table: &ColorTable,
+    data: &mut Vec<u8>,
+) {
+    let (red, blue) = match config.pixel_format {
+        PixelFormat::Bgr => (2, 0),
+        PixelFormat::Rgb => (0, 2),
+    };
+
+    data.clear();
+    for pixel in pixels.chunks_exact(CAPTURED_PACKING.bytes_per_pixel()) {
+        let (r, g, b) = (pixel[red] as usize, pixel[1] as usize, pixel[blue] as usize);
+        let mixed = |weights: [f32; 3]| {
+            let [linear_r, linear_g, linear_b] = lookup_table.linear;
+            lookup_table.encode(
+                weights[0] * linear_r[r] + weights[1] * linear_g[g] + weights[2] * linear_b[b],
+            )
+        };
+        match config.effect {
+            Effect::None | Effect::Invert => {
+                let [encoded_r, encoded_g, encoded_b] = &lookup_table.encoded;
+                data.extend_from_slice(&[encoded_r[r], encoded_g[g], encoded_b[b]]);
+            }
+            Effect::Sepia => data.extend_from_slice(&[
+                mixed([0.393, 0.769, 0.189]),
+                mixed([0.349, 0.686, 0.168]),
+                mixed([0.272, 0.534, 0.131]),
+            ]),
+            Effect::Grayscale => {
+                // Rec. 709 luma weights
+                let luma = mixed([0.2126, 0.7152, 0.0722]);
+                data.extend_from_slice(&[luma, luma, luma]);
+            }
+        }
+    }
+}
+
+/// Blur regions of RGB24 pixel data in place, to match the blur done by the fragment shader
+fn blur_rgb24(data: &mut [u8], width: u32, height: u32, regions: &[Rect]) {
+    const PIXEL_SIZE: usize = 3;
+    let (width, height) = (width as usize, height as usize);
+    let offset = |x: usize, y: usize| (y * width + x) * PIXEL_SIZE;
+
+    for region in regions {
+        let left = (region.x as usize).min(width);
+        let right = (region.x as usize + region.width as usize).min(width);
+        let top = (region.y as usize).min(height);
+        let bottom = (region.y as usize + region.height as usize).min(height);
+        if left == right || top == bottom {
+            continue;
+        }
+
+        // Blur horizontally first, including the rows above and below that the vertical pass reads
+        let first_row = top.saturating_sub(BLUR_RADIUS);
+        let last_row = (bottom + BLUR_RADIUS).min(height);
+        let columns = right - left;
+        let mut horizontal = vec![0; (last_row - first_row) * columns * PIXEL_SIZE];
+        for y in first_row..last_row {
+            for x in left..right {
+                let samples = x.saturating_sub(BLUR_RADIUS)..(x + BLUR_RADIUS + 1).min(width);
+                let count = samples.len() as u32;
+                for channel in 0..PIXEL_SIZE {
+                    let sum: u32 = samples
+                        .clone()
+                        .map(|sample| u32::from(data[offset(sample, y) + channel]))
+                        .sum();
+                    horizontal[((y - first_row) * columns + x - left) * PIXEL_SIZE + channel] =
+                        (sum / count) as u8;
+                }
+            }
+        }
+
+        for y in top..bottom {
+            let samples = y.saturating_sub(BLUR_RADIUS)..(y + BLUR_RADIUS + 1).min(height);
+            let count = samples.len() as u32;
+            for x in left..right {
+                for channel in 0..PIXEL_SIZE {
+                    let sum: u32 = samples
+                        .clone()
+                        .map(|sample| {
+                            let index = (sample - first_row) * columns + x - left;
+                            u32::from(horizontal[index * PIXEL_SIZE + channel])
+                        })
+                        .sum();
+                    data[offset(x, y) + channel] = (sum / count) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Apply the same unsharp mask as the fragment shader to captured pixels, on their linear values
+///
+/// The pixels at the edges of the image are left as they are. The previous contents of
+/// `sharpened` are replaced, its allocation is reused.
+fn sharpen_pixels(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    amount: f32,
+    lookup_table: &ColorTable,
+    sharpened: &mut Vec<u8>,
+) {
+    let pixel_size = CAPTURED_PACKING.bytes_per_pixel();
+    let (width, height) = (width as usize, height as usize);
+    sharpened.clear();
+    sharpened.extend_from_slice(pixels);
+    if width < 3 || height < 3 {
+        return;
+    }
+
+    let offset = |x: usize, y: usize| (y * width + x) * pixel_size;
+    let linear = |x: usize, y: usize, channel: usize| {
+        lookup_table.decoding[usize::from(pixels[offset(x, y) + channel])]
+    };
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            // The padding of packed pixels is left alone
+            for channel in 0..3 {
+                let center = linear(x, y, channel);
+                let mut sum = -center;
+                for sample_y in y - 1..=y + 1 {
+                    for sample_x in x - 1..=x + 1 {
+                        sum += linear(sample_x, sample_y, channel);
+                    }
+                }
+                let sharpened_value = center + amount * (center - sum / 8.0);
+                sharpened[offset(x, y) + channel] = lookup_table.encode(sharpened_value);
+            }
+        }
+    }
+}
+
+/// Find the pixels on edges like the fragment shader does, from the linear luma of the captured
+/// pixels before they are sharpened or their colors are corrected
+///
+/// The pixels at the edges of the image have no neighbors on all sides and never count as edges.
+fn find_edges(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    config: &Settings,
+    lookup_table: &ColorTable,
+) -> Vec<bool> {
+    let (width, height) = (width as usize, height as usize);
+    let (red, blue) = match config.pixel_format {
+        PixelFormat::Bgr => (2, 0),
+        PixelFormat::Rgb => (0, 2),
+    };
+
+    let luma: Vec<f32> = pixels
+        .chunks_exact(CAPTURED_PACKING.bytes_per_pixel())
+        .map(|pixel| {
+            let linear = |value: u8| lookup_table.decoding[usize::from(value)];
+            0.2126 * linear(pixel[red]) + 0.7152 * linear(pixel[1]) + 0.0722 * linear(pixel[blue])
+        })
+        .collect();
+    let at = |x: usize, y: usize| luma[y * width + x];
+
+    let mut edges = vec![false; width * height];
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let gradient_x = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x - 1, y)
+                - at(x - 1, y + 1);
+            let gradient_y = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x, y - 1)
+                - at(x + 1, y - 1);
+            edges[y * width + x] = gradient_x.hypot(gradient_y) / 4.0 > config.edge_threshold;
+        }
+    }
+    edges
+}
+
+/// Draw the edges found by `find_edges` into RGB24 pixel data as white lines, on black unless
+/// they are drawn on top of the image
+///
+/// Like in the fragment shader, blurred regions stay as they are.
+fn draw_edges_rgb24(data: &mut [u8], width: u32, edges: &[bool], config: &Settings) {
+    const PIXEL_SIZE: usize = 3;
+    let blurred = |x: u32, y: u32| {
+        config.blur.iter().any(|region| {
+            (region.x..region.x + region.width).contains(&x)
+                && (region.y..region.y + region.height).contains(&y)
+        })
+    };
+
+    for (index, (pixel, &on_edge)) in data.chunks_exact_mut(PIXEL_SIZE).zip(edges).enumerate() {
+        let (x, y) = (index as u32 % width, index as u32 / width);
+        if blurred(x, y) {
+            continue;
+        }
+        if on_edge {
+            pixel.fill(255);
+        } else if !config.edges_overlay {
+            pixel.fill(0);
+        }
+    }
+}
+
+/// Draw text into a corner of RGB24 pixel data, looking like the overlays drawn into the windows
+fn overlay_rgb24(data: &mut [u8], width: u32, height: u32, text: &str, corner: Corner) {
+    const PIXEL_SIZE: usize = 3;
+    let (overlay, overlay_width, overlay_height) = render_text(text);
+    let (left, top) = corner.place(
+        (overlay_width, overlay_height),
+        (width, height),
+        OVERLAY_MARGIN,
+    );
+
+    for y in 0..overlay_height.min(height.saturating_sub(top)) {
+        for x in 0..overlay_width.min(width.saturating_sub(left)) {
+            let source = &overlay[((y * overlay_width + x) * 4) as usize..][..4];
+            let alpha = u32::from(source[3]);
+            let start = (((top + y) * width + left + x) as usize) * PIXEL_SIZE;
+            for (channel, value) in data[start..start + PIXEL_SIZE].iter_mut().enumerate() {
+                let blended =
+                    u32::from(source[channel]) * alpha + u32::from(*value) * (255 - alpha);
+                *value = (blended / 255) as u8;
+            }
+        }
+    }
+}
+
+/// Current local time in the strftime format of the clock, if the clock is enabled
+pub fn clock_text(config: &Settings) -> Option<String> {
+    if !config.overlay_clock {
+        return None;
+    }
+
+    // Null characters are rejected when the settings are validated
+    let format = CString::new(config.clock_format.as_str()).ok()?;
+    Some(local_time(&format))
+}
+
+/// Current local time in the given strftime format
+pub fn local_time(format: &CStr) -> String {
+    let mut buffer = [0u8; 256];
+    let length = unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut time = mem::zeroed();
+        libc::localtime_r(&now, &mut time);
+        libc::strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            &time,
+        )
+    };
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+/// Paint the masked regions of RGB24 pixel data with the mask color
+fn mask_rgb24(data: &mut [u8], width: u32, height: u32, config: &Settings) {
+    let color = [
+        config.mask_color.red,
+        config.mask_color.green,
+        config.mask_color.blue,
+    ];
+    let row_length = width as usize * color.len();
+
+    for mask in &config.masks {
+        let left = (mask.x as usize).min(width as usize);
+        let right = (mask.x as usize + mask.width as usize).min(width as usize);
+        let top = (mask.y as usize).min(height as usize);
+        let bottom = (mask.y as usize + mask.height as usize).min(height as usize);
+
+        for row in data.chunks_exact_mut(row_length).take(bottom).skip(top) {
+            for pixel in row[left * color.len()..right * color.len()].chunks_exact_mut(color.len())
+            {
+                pixel.copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+/// Mirror RGB24 pixel data in place as configured by `--flip-h` and `--flip-v`
+fn flip_rgb24(data: &mut [u8], width: u32, config: &Settings) {
+    const PIXEL_SIZE: usize = 3;
+    let row_length = width as usize * PIXEL_SIZE;
+
+    if config.flip_horizontal {
+        for row in data.chunks_exact_mut(row_length) {
+            for left in 0..width as usize / 2 {
+                let right = width as usize - 1 - left;
+                let (start, end) = row.split_at_mut(right * PIXEL_SIZE);
+                start[left * PIXEL_SIZE..][..PIXEL_SIZE].swap_with_slice(&mut end[..PIXEL_SIZE]);
+            }
+        }
+    }
+
+    if config.flip_vertical {
+        let rows = data.len() / row_length;
+        for top in 0..rows / 2 {
+            let bottom = rows - 1 - top;
+            let (start, end) = data.split_at_mut(bottom * row_length);
+            start[top * row_length..][..row_length].swap_with_slice(&mut end[..row_length]);
+        }
+    }
+}
+
+/// Convert a captured frame to an image that looks like the shared window
+pub fn rgb_image(
+    frame: &Frame,
+    config: &Settings,
+    lookup_table: &ColorTable,
+) -> Result<image::RgbImage, Box<dyn Error>> {
+    let (width, height) = frame.dimensions();
+    let mut buffer = Vec::new();
+    let (width, height) = process_rgb24(
+        frame.as_bytes(),
+        width,
+        height,
+        config,
+        lookup_table,
+        &mut buffer,
+    )?;
+    Ok(
+        image::RgbImage::from_raw(width, height, buffer)
+            .expect("Processed frame of the wrong size"),
+    )
+}
+
+/// Size of the frames of the given size once `process_rgb24` rotated and scaled them
+pub fn processed_size(size: (u32, u32), config: &Settings) -> (u32, u32) {
+    let (width, height) = rotated_size(size, config.rotation);
+    (
+        scaled_size(width, config.scale),
+        scaled_size(height, config.scale),
+    )
+}
+
+/// Process a captured frame on the CPU the way the fragment shader does, for the outputs that
+/// don't draw into a window
+///
+/// All of them get the image the shared window shows: the frame is converted to RGB24 with the
+/// color corrections, effects, blurred regions and masks applied, rotated, mirrored and scaled
+/// like the window, and the clock is drawn on top. The previous contents of `data` are replaced,
+/// its allocation is reused. Returns the size of the processed frame.
+pub fn process_rgb24(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    config: &Settings,
+    lookup_table: &ColorTable,
+    data: &mut Vec<u8>,
+) -> Result<(u32, u32), Box<dyn Error>> {
+    if pixels.len() != CAPTURED_PACKING.byte_len(width as usize * height as usize) {
+        return Err("Captured frame has an unexpected size".into());
+    }
+
+    // Like in the shader the edges are found in the captured image, and the image is sharpened
+    // before its colors are corrected
+    let edges = if config.edges {
+        find_edges(pixels, width, height, config, lookup_table)
+    } else {
+        Vec::new()
+    };
+    if config.sharpen > 0.0 {
+        let mut sharpened = Vec::new();
+        sharpen_pixels(
+            pixels,
+            width,
+            height,
+            config.sharpen,
+            lookup_table,
+            &mut sharpened,
+        );
+        convert_to_rgb24(&sharpened, config, lookup_table, data);
+    } else {
+        convert_to_rgb24(pixels, config, lookup_table, data);
+    }
+    if config.edges {
+        draw_edges_rgb24(data, width, &edges, config);
+    }
+    blur_rgb24(data, width, height, &config.blur);
+    mask_rgb24(data, width, height, config);
+
+    let (mut width, mut height) = (width, height);
+    if config.rotation != 0 {
+        let image = image::RgbImage::from_raw(width, height, mem::take(data))
+            .expect("RGB24 frame of the wrong size");
+        let rotated = match config.rotation {
+            90 => image::imageops::rotate90(&image),
+            180 => image::imageops::rotate180(&image),
+            _ => image::imageops::rotate270(&image),
+        };
+        (width, height) = rotated.dimensions();
+        *data = rotated.into_raw();
+    }
+    flip_rgb24(data, width, config);
+    if (config.scale - 1.0).abs() > f64::EPSILON {
+        let image = image::RgbImage::from_raw(width, height, mem::take(data))
+            .expect("RGB24 frame of the wrong size");
+        let scaled = image::imageops::resize(
+            &image,
+            scaled_size(width, config.scale),
+            scaled_size(height, config.scale),
+            match config.filter {
+                Filter::Nearest => image::imageops::FilterType::Nearest,
+                Filter::Linear => image::imageops::FilterType::Triangle,
+            },
+        );
+        (width, height) = scaled.dimensions();
+        *data = scaled.into_raw();
+    }
+
+    if let Some(time) = clock_text(config) {
+        overlay_rgb24(data, width, height, &time, config.clock_corner);
+    }
+    Ok((width, height))
+}
+
+/// Studio range BT.709 luma of an 8 bit RGB color
+fn luma_bt709([r, g, b]: [f32; 3]) -> u8 {
+    (16.0 + 0.1826 * r + 0.6142 * g + 0.0620 * b).round() as u8
+}
+
+/// Studio range BT.709 blue and red difference of an 8 bit RGB color
+fn chroma_bt709([r, g, b]: [f32; 3]) -> (u8, u8) {
+    let u = 128.0 - 0.1006 * r - 0.3386 * g + 0.4392 * b;
+    let v = 128.0 + 0.4392 * r - 0.3989 * g - 0.0403 * b;
+    (u.round() as u8, v.round() as u8)
+}
+
+/// Average color of some RGB24 pixels
+fn average_rgb(pixels: &[&[u8]]) -> [f32; 3] {
+    let mut sum = [0.0; 3];
+    for pixel in pixels {
+        for (total, &value) in sum.iter_mut().zip(pixel.iter()) {
+            *total += f32::from(value);
+        }
+    }
+    sum.map(|total| total / pixels.len() as f32)
+}
+
+/// Convert RGB24 pixel data to YUYV, two pixels sharing their colors
+///
+/// An odd last column is left out.
+pub fn rgb24_to_yuyv(rgb: &[u8], width: u32, height: u32, yuyv: &mut Vec<u8>) {
+    const PIXEL_SIZE: usize = 3;
+    let (width, height) = (width as usize, height as usize);
+
+    yuyv.clear();
+    for row in rgb.chunks_exact(width * PIXEL_SIZE).take(height) {
+        for pair in row.chunks_exact(2 * PIXEL_SIZE) {
+            let (left, right) = pair.split_at(PIXEL_SIZE);
+            let luma = |pixel: &[u8]| luma_bt709(average_rgb(&[pixel]));
+            let (u, v) = chroma_bt709(average_rgb(&[left, right]));
+            yuyv.extend_from_slice(&[luma(left), u, luma(right), v]);
+        }
+    }
+}
+
+/// Convert RGB24 pixel data to NV12, a plane of luma followed by a plane of interleaved colors
+/// shared by blocks of two by two pixels
+///
+/// An odd last column and row are left out.
+pub fn rgb24_to_nv12(rgb: &[u8], width: u32, height: u32, nv12: &mut Vec<u8>) {
+    const PIXEL_SIZE: usize = 3;
+    let (width, height) = (width as usize, height as usize);
+    let (even_width, even_height) = (width & !1, height & !1);
+    let pixel = |x: usize, y: usize| &rgb[(y * width + x) * PIXEL_SIZE..][..PIXEL_SIZE];
+
+    nv12.clear();
+    for y in 0..even_height {
+        for x in 0..even_width {
+            nv12.push(luma_bt709(average_rgb(&[pixel(x, y)])));
+        }
+    }
+    for y in (0..even_height).step_by(2) {
+        for x in (0..even_width).step_by(2) {
+            let block = [
+                pixel(x, y),
+                pixel(x + 1, y),
+                pixel(x, y + 1),
+                pixel(x + 1, y + 1),
+            ];
+            let (u, v) = chroma_bt709(average_rgb(&block));
+            nv12.extend_from_slice(&[u, v]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The color the fragment shader draws for an sRGB encoded color, calculated step by step
+    fn shader_color(config: &Settings, rgb: [u8; 3]) -> [f32; 3] {
+        corrected_by_shader(
+            config,
+            rgb.map(|value| srgb_to_linear(f32::from(value) / 255.0)),
+        )
+    }
+
+    /// The color the fragment shader draws for a color read from the texture
+    fn corrected_by_shader(config: &Settings, texture_color: [f32; 3]) -> [f32; 3] {
+        let white_balance = config.color_temp_factors().map(srgb_to_linear);
+        let mut color = [0.0; 3];
+        for ((color, value), factor) in color.iter_mut().zip(texture_color).zip(white_balance) {
+            let linear = value * factor;
+            let adjusted = (linear - 0.5) * config.contrast + 0.5 + config.brightness;
+            *color = adjusted.clamp(0.0, 1.0).powf(1.0 / config.gamma);
+        }
+
+        let [r, g, b] = color;
+        let weighted = |weights: [f32; 3]| weights[0] * r + weights[1] * g + weights[2] * b;
+        let color = match config.effect {
+            Effect::None => color,
+            Effect::Invert => color.map(|value| 1.0 - value),
+            Effect::Sepia => [
+                weighted([0.393, 0.769, 0.189]),
+                weighted([0.349, 0.686, 0.168]),
+                weighted([0.272, 0.534, 0.131]),
+            ],
+            Effect::Grayscale => [weighted([0.2126, 0.7152, 0.0722]); 3],
+        };
+        // The sRGB framebuffer clamps and encodes the color
+        color.map(|value| linear_to_srgb(value.clamp(0.0, 1.0)) * 255.0)
+    }
+
+    /// The color the fragment shader draws for a pixel of an image given as RGB, including the
+    /// unsharp mask and the edges
+    fn shader_pixel(
+        config: &Settings,
+        image: &[[u8; 3]],
+        width: usize,
+        x: usize,
+        y: usize,
+    ) -> [f32; 3] {
+        let texel = |x: usize, y: usize, channel: usize| {
+            srgb_to_linear(f32::from(image[y * width + x][channel]) / 255.0)
+        };
+        let neighbors = |channel: usize| {
+            let mut sum = 0.0;
+            for sample_y in y - 1..=y + 1 {
+                for sample_x in x - 1..=x + 1 {
+                    sum += texel(sample_x, sample_y, channel);
+                }
+            }
+            (sum - texel(x, y, channel)) / 8.0
+        };
+        let texture_color = [0, 1, 2].map(|channel| {
+            let center = texel(x, y, channel);
+            if config.sharpen > 0.0 {
+                (center + config.sharpen * (center - neighbors(channel))).clamp(0.0, 1.0)
+            } else {
+                center
+            }
+        });
+        let color = corrected_by_shader(config, texture_color);
+        if !config.edges {
+            return color;
+        }
+
+        let luma = |x: usize, y: usize| {
+            0.2126 * texel(x, y, 0) + 0.7152 * texel(x, y, 1) + 0.0722 * texel(x, y, 2)
+        };
+        let gradient_x = luma(x + 1, y - 1) + 2.0 * luma(x + 1, y) + luma(x + 1, y + 1)
+            - luma(x - 1, y - 1)
+            - 2.0 * luma(x - 1, y)
+            - luma(x - 1, y + 1);
+        let gradient_y = luma(x - 1, y + 1) + 2.0 * luma(x, y + 1) + luma(x + 1, y + 1)
+            - luma(x - 1, y - 1)
+            - 2.0 * luma(x, y - 1)
+            - luma(x + 1, y - 1);
+        if gradient_x.hypot(gradient_y) / 4.0 > config.edge_threshold {
+            [255.0; 3]
+        } else if config.edges_overlay {
+            color
+        } else {
+            [0.0; 3]
+        }
+    }
+
+    /// Convert pixels given as RGB the way frames are converted for the outputs besides the window
+    fn converted(config: &Settings, colors: &[[u8; 3]]) -> Vec<u8> {
+        let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+        let pixels: Vec<u8> = colors
+            .iter()
+            .flat_map(|&[r, g, b]| [r, g, b, 0][..bytes_per_pixel].to_vec())
+            .collect();
+        let rgb_config = Settings {
+            pixel_format: PixelFormat::Rgb,
+            ..config.clone()
+        };
+        let mut data = Vec::new();
+        convert_to_rgb24(
+            &pixels,
+            &rgb_config,
+            &color_lookup_table(&rgb_config),
+            &mut data,
+        );
+        data
+    }
+
+    #[test]
+    fn effects_match_the_shader() {
+        // Dark and light halves with a colored square across them
+        let (width, height) = (8, 6);
+        let image: Vec<[u8; 3]> = (0..width * height)
+            .map(|index| match (index % width, index / width) {
+                (3..=4, 2..=3) => [200, 30, 90],
+                (x, _) if x < width / 2 => [40, 45, 50],
+                _ => [190, 180, 170],
+            })
+            .collect();
+        let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+        let pixels: Vec<u8> = image
+            .iter()
+            .flat_map(|&[r, g, b]| [r, g, b, 0][..bytes_per_pixel].to_vec())
+            .collect();
+
+        let configs = [
+            // Found in the corrected image everything would be white and there would be no edges
+            Settings {
+                edges: true,
+                brightness: 0.8,
+                ..Settings::default()
+            },
+            Settings {
+                sharpen: 0.8,
+                contrast: 1.5,
+                ..Settings::default()
+            },
+            Settings {
+                sharpen: 0.5,
+                edges: true,
+                edges_overlay: true,
+                gamma: 1.4,
+                ..Settings::default()
+            },
+        ];
+        for (number, config) in configs.iter().enumerate() {
+            let config = Settings {
+                pixel_format: PixelFormat::Rgb,
+                ..config.clone()
+            };
+            let mut data = Vec::new();
+            let lookup_table = color_lookup_table(&config);
+            process_rgb24(
+                &pixels,
+                width as u32,
+                height as u32,
+                &config,
+                &lookup_table,
+                &mut data,
+            )
+            .unwrap();
+
+            // The shader samples outside of the image at the borders, which the CPU does not
+            for y in 1..height - 1 {
+                for x in 1..width - 1 {
+                    let expected = shader_pixel(&config, &image, width, x, y);
+                    let actual = &data[(y * width + x) * 3..][..3];
+                    for (&actual, expected) in actual.iter().zip(expected) {
+                        assert!(
+                            (f32::from(actual) - expected).abs() <= 1.0,
+                            "Settings {} at {},{}: {} instead of {}",
+                            number,
+                            x,
+                            y,
+                            actual,
+                            expected
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn processed_frames_are_turned_like_the_window() {
+        let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+        let (red, blue) = ([255, 0, 0, 0], [0, 0, 255, 0]);
+        let pixels: Vec<u8> = [red; 4]
+            .iter()
+            .chain(&[blue; 4])
+            .flat_map(|pixel| pixel[..bytes_per_pixel].to_vec())
+            .collect();
+        let mut config = Settings {
+            pixel_format: PixelFormat::Rgb,
+            rotation: 90,
+            flip_horizontal: true,
+            ..Settings::default()
+        };
+
+        // Turned clockwise the red top row is on the right, and mirrored on the left again
+        let mut data = Vec::new();
+        let lookup_table = color_lookup_table(&config);
+        let size = process_rgb24(&pixels, 4, 2, &config, &lookup_table, &mut data).unwrap();
+        assert_eq!(size, (2, 4));
+        assert_eq!(data, [255, 0, 0, 0, 0, 255].repeat(4));
+
+        config.scale = 0.5;
+        let size = process_rgb24(&pixels, 4, 2, &config, &lookup_table, &mut data).unwrap();
+        assert_eq!(size, (1, 2));
+        assert_eq!(size, processed_size((4, 2), &config));
+        assert_eq!(data.len(), 6);
+    }
+
+    #[test]
+    fn yuyv_pairs_share_their_colors() {
+        let rgb = [[255, 255, 255], [0, 0, 0], [255, 0, 0]].concat();
+        let mut yuyv = Vec::new();
+        rgb24_to_yuyv(&rgb, 3, 1, &mut yuyv);
+        // The odd last column is left out
+        assert_eq!(yuyv, [235, 128, 16, 128]);
+    }
+
+    #[test]
+    fn nv12_has_a_luma_and_a_color_plane() {
+        let rgb = [255, 0, 0].repeat(3 * 3);
+        let mut nv12 = Vec::new();
+        rgb24_to_nv12(&rgb, 3, 3, &mut nv12);
+        // The odd last column and row are left out
+        assert_eq!(nv12, [63, 63, 63, 63, 102, 240]);
+    }
+
+    fn sample_colors() -> Vec<[u8; 3]> {
+        (0..=255u8)
+            .step_by(5)
+            .map(|value| [value, value / 2, 255 - value])
+            .collect()
+    }
+
+    #[test]
+    fn default_corrections_keep_the_colors() {
+        let colors: Vec<[u8; 3]> = (0..=255).map(|value| [value, value, value]).collect();
+        assert_eq!(converted(&Settings::default(), &colors), colors.concat());
+    }
+
+    #[test]
+    fn corrections_match_the_shader() {
+        let adjusted = Settings {
+            brightness: 0.05,
+            contrast: 1.4,
+            gamma: 1.8,
+            color_temp: 4000,
+            ..Settings::default()
+        };
+        let effects = [
+            Effect::None,
+            Effect::Invert,
+            Effect::Sepia,
+            Effect::Grayscale,
+        ];
+        for effect in effects.iter().copied() {
+            let config = Settings {
+                effect,
+                ..adjusted.clone()
+            };
+            // Mixed channels are encoded with the table of evenly spaced steps, which may round
+            // to the neighbouring value
+            let tolerance = match effect {
+                Effect::None | Effect::Invert => 0.5,
+                Effect::Sepia | Effect::Grayscale => 1.0,
+            };
+            let colors = sample_colors();
+            let expected = colors.iter().flat_map(|&rgb| shader_color(&config, rgb));
+            for (value, expected) in converted(&config, &colors).into_iter().zip(expected) {
+                assert!(
+                    (f32::from(value) - expected).abs() <= tolerance + 1e-3,
+                    "{:?} gives {} instead of {}",
+                    effect,
+                    value,
+                    expected
+                );
+            }
+        }
+    }
+}
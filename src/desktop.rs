@@ -0,0 +1,463 @@
+//! Everything we need to know about the X11 desktop: the monitors, windows and the session
+
+use std::env;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::os::raw::c_int;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+use ::x11::{xlib, xrandr};
+use glium::glutin::platform::unix::x11;
+use log::warn;
+
+use crate::capture::open_source;
+use crate::cli::MonitorSelector;
+use crate::config::CaptureTarget;
+
+/// A monitor as reported by XRandR
+///
+/// The index in the list returned by `list_monitors` is the monitor ID x11cap expects.
+pub struct Monitor {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Ask the X server for all active monitors, in the same order x11cap uses
+pub fn list_monitors() -> Result<Vec<Monitor>, Box<dyn Error>> {
+    unsafe {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return Err("Unable to connect to the X server".into());
+        }
+
+        let mut monitor_count = 0;
+        let root = xlib::XDefaultRootWindow(display);
+        let infos = xrandr::XRRGetMonitors(display, root, xlib::True, &mut monitor_count);
+
+        let mut monitors = Vec::new();
+        if !infos.is_null() {
+            for info in std::slice::from_raw_parts(infos, monitor_count as usize) {
+                let name_ptr = xlib::XGetAtomName(display, info.name);
+                let name = if name_ptr.is_null() {
+                    String::new()
+                } else {
+                    let name = CStr::from_ptr(name_ptr).to_string_lossy().into_owned();
+                    xlib::XFree(name_ptr as *mut _);
+                    name
+                };
+
+                monitors.push(Monitor {
+                    name,
+                    x: info.x,
+                    y: info.y,
+                    width: info.width as u32,
+                    height: info.height as u32,
+                });
+            }
+            xrandr::XRRFreeMonitors(infos);
+        }
+
+        xlib::XCloseDisplay(display);
+        Ok(monitors)
+    }
+}
+
+/// Size of the virtual screen, which covers all monitors
+pub fn screen_size() -> Result<(u32, u32), Box<dyn Error>> {
+    unsafe {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return Err("Unable to connect to the X server".into());
+        }
+
+        let screen = xlib::XDefaultScreen(display);
+        let size = (
+            xlib::XDisplayWidth(display, screen) as u32,
+            xlib::XDisplayHeight(display, screen) as u32,
+        );
+
+        xlib::XCloseDisplay(display);
+        Ok(size)
+    }
+}
+
+/// Refresh rate of the captured monitors in Hz, the lowest one if there are several
+///
+/// The rate is calculated from the mode of the CRTC that shows the monitor, which is found by its
+/// position.
+pub fn refresh_rate(target: &CaptureTarget) -> Result<f64, Box<dyn Error>> {
+    let monitor_ids = match target {
+        CaptureTarget::Monitor(monitor_id) => vec![*monitor_id],
+        CaptureTarget::Monitors(monitor_ids) => monitor_ids.clone(),
+        CaptureTarget::Window(_)
+        | CaptureTarget::Screen
+        | CaptureTarget::TestPattern
+        | CaptureTarget::Portal => {
+            return Err("Only the refresh rate of monitors can be matched".into())
+        }
+    };
+    let monitors = list_monitors()?;
+
+    unsafe {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return Err("Unable to connect to the X server".into());
+        }
+
+        let resources =
+            xrandr::XRRGetScreenResourcesCurrent(display, xlib::XDefaultRootWindow(display));
+        let mut rates = Vec::new();
+        if !resources.is_null() {
+            let crtcs = std::slice::from_raw_parts((*resources).crtcs, (*resources).ncrtc as usize);
+            let modes = std::slice::from_raw_parts((*resources).modes, (*resources).nmode as usize);
+            for monitor in monitor_ids.iter().filter_map(|&id| monitors.get(id)) {
+                for &crtc in crtcs {
+                    let info = xrandr::XRRGetCrtcInfo(display, resources, crtc);
+                    if info.is_null() {
+                        continue;
+                    }
+                    if (*info).x == monitor.x && (*info).y == monitor.y {
+                        let mode = modes.iter().find(|mode| mode.id == (*info).mode);
+                        rates.extend(mode.and_then(mode_refresh_rate));
+                    }
+                    xrandr::XRRFreeCrtcInfo(info);
+                }
+            }
+            xrandr::XRRFreeScreenResources(resources);
+        }
+
+        xlib::XCloseDisplay(display);
+        rates
+            .into_iter()
+            .min_by(f64::total_cmp)
+            .ok_or_else(|| "Unable to determine the refresh rate of the monitor".into())
+    }
+}
+
+/// Refresh rate of a display mode in Hz
+fn mode_refresh_rate(mode: &xrandr::XRRModeInfo) -> Option<f64> {
+    let mut lines = f64::from(mode.vTotal);
+    if mode.modeFlags & xrandr::RR_DoubleScan as libc::c_ulong != 0 {
+        lines *= 2.0;
+    }
+    if mode.modeFlags & xrandr::RR_Interlace as libc::c_ulong != 0 {
+        lines /= 2.0;
+    }
+
+    let pixels = f64::from(mode.hTotal) * lines;
+    if pixels == 0.0 {
+        return None;
+    }
+    Some(mode.dotClock as f64 / pixels)
+}
+
+/// Make sure every monitor ID refers to an active monitor
+///
+/// x11cap panics on unknown IDs, so this has to be checked before a capturer is created. Without a
+/// connection to the X server nothing is checked, opening the source reports that error instead.
+fn check_monitor_ids(monitor_ids: &[usize]) -> Result<(), String> {
+    let monitors = match list_monitors() {
+        Ok(monitors) => monitors,
+        Err(_) => return Ok(()),
+    };
+
+    match monitor_ids.iter().find(|&&id| id >= monitors.len()) {
+        Some(id) => Err(format!(
+            "Monitor {} does not exist, valid monitor IDs are:{}",
+            id,
+            monitor_listing(&monitors)
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check the monitors and the capture source, trying again with increasing delays if they are not
+/// available yet
+///
+/// Right after login the X server may still be starting or not know about all monitors yet.
+/// Without retries only the monitor IDs are checked, opening the source is left to the caller.
+pub fn wait_for_capture(
+    target: &CaptureTarget,
+    monitor_ids: &[usize],
+    retries: u32,
+) -> Result<(), Box<dyn Error>> {
+    const FIRST_DELAY: Duration = Duration::from_millis(500);
+    const MAX_DELAY: Duration = Duration::from_secs(8);
+
+    if retries == 0 {
+        return Ok(check_monitor_ids(monitor_ids)?);
+    }
+
+    // Windows may disappear while they are looked at
+    ignore_x_errors();
+    let mut delay = FIRST_DELAY;
+    let mut attempt = 0;
+    loop {
+        let result = check_capture_session(target)
+            .and_then(|_| Ok(check_monitor_ids(monitor_ids)?))
+            .and_then(|_| open_source(target.clone()).map(drop));
+        match result {
+            Ok(()) => return Ok(()),
+            Err(error) if attempt == retries => {
+                return Err(format!("{} (gave up after {} retries)", error, retries).into())
+            }
+            Err(error) => {
+                attempt += 1;
+                warn!(
+                    "{}, trying again in {:.1}s ({} of {})",
+                    error,
+                    delay.as_secs_f64(),
+                    attempt,
+                    retries
+                );
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_DELAY);
+            }
+        }
+    }
+}
+
+/// One indented line per monitor with its ID, name and geometry
+fn monitor_listing(monitors: &[Monitor]) -> String {
+    monitors
+        .iter()
+        .enumerate()
+        .map(|(id, monitor)| {
+            format!(
+                "\n  {} {} {}x{}+{}+{}",
+                id, monitor.name, monitor.width, monitor.height, monitor.x, monitor.y
+            )
+        })
+        .collect()
+}
+
+/// Turn the monitor selectors into IDs, looking up the names with XRandR
+///
+/// The X server is only asked if any monitor was given by name.
+pub fn resolve_monitor_ids(selectors: &[MonitorSelector]) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut monitors = None;
+    selectors
+        .iter()
+        .map(|selector| match selector {
+            MonitorSelector::Id(id) => Ok(*id),
+            MonitorSelector::Name(name) => {
+                let monitors = match &mut monitors {
+                    Some(monitors) => monitors,
+                    None => monitors.insert(list_monitors()?),
+                };
+                monitors
+                    .iter()
+                    .position(|monitor| monitor.name == *name)
+                    .ok_or_else(|| {
+                        format!(
+                            "No monitor is named {}, available monitors are:{}",
+                            name,
+                            monitor_listing(monitors)
+                        )
+                        .into()
+                    })
+            }
+        })
+        .collect()
+}
+
+/// Find the visible window whose title contains `pattern`
+///
+/// The whole window tree is searched, since not every window manager lists its clients in
+/// `_NET_CLIENT_LIST`. If several windows match they are listed in the error, so the user can pick
+/// one with `--window-id`.
+pub fn find_window_by_title(pattern: &str) -> Result<xlib::Window, Box<dyn Error>> {
+    let mut matches = Vec::new();
+    unsafe {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return Err("Unable to connect to the X server".into());
+        }
+
+        let mut pending = vec![xlib::XDefaultRootWindow(display)];
+        while let Some(window) = pending.pop() {
+            let (mut root, mut parent) = (0, 0);
+            let mut children = ptr::null_mut();
+            let mut child_count = 0;
+            if xlib::XQueryTree(
+                display,
+                window,
+                &mut root,
+                &mut parent,
+                &mut children,
+                &mut child_count,
+            ) != 0
+                && !children.is_null()
+            {
+                pending
+                    .extend_from_slice(std::slice::from_raw_parts(children, child_count as usize));
+                xlib::XFree(children as *mut _);
+            }
+
+            let mut attributes = mem::zeroed::<xlib::XWindowAttributes>();
+            if xlib::XGetWindowAttributes(display, window, &mut attributes) == 0
+                || attributes.map_state != xlib::IsViewable
+            {
+                continue;
+            }
+
+            if let Some(title) = window_name(display, window) {
+                if title.contains(pattern) {
+                    matches.push((window, title));
+                }
+            }
+        }
+
+        xlib::XCloseDisplay(display);
+    }
+
+    matches.sort_unstable();
+    match matches.as_slice() {
+        [] => Err(format!("No window with \"{}\" in its title was found", pattern).into()),
+        [(window, _)] => Ok(*window),
+        _ => {
+            let mut message = format!(
+                "Several windows have \"{}\" in their title, pick one with --window-id:",
+                pattern
+            );
+            for (window, title) in &matches {
+                message.push_str(&format!("\n  0x{:x} {}", window, title));
+            }
+            Err(message.into())
+        }
+    }
+}
+
+/// Read the title of a window, preferring the UTF-8 `_NET_WM_NAME` over the legacy `WM_NAME`
+unsafe fn window_name(display: *mut xlib::Display, window: xlib::Window) -> Option<String> {
+    let net_wm_name = CString::new("_NET_WM_NAME").unwrap();
+    let utf8_string = CString::new("UTF8_STRING").unwrap();
+    let net_wm_name = xlib::XInternAtom(display, net_wm_name.as_ptr(), xlib::False);
+    let utf8_string = xlib::XInternAtom(display, utf8_string.as_ptr(), xlib::False);
+
+    let mut actual_type = 0;
+    let mut actual_format = 0;
+    let mut item_count = 0;
+    let mut bytes_after = 0;
+    let mut data = ptr::null_mut();
+    let result = xlib::XGetWindowProperty(
+        display,
+        window,
+        net_wm_name,
+        0,
+        1024,
+        xlib::False,
+        utf8_string,
+        &mut actual_type,
+        &mut actual_format,
+        &mut item_count,
+        &mut bytes_after,
+        &mut data,
+    );
+    if result == c_int::from(xlib::Success) && !data.is_null() {
+        let name = (actual_type == utf8_string && actual_format == 8).then(|| {
+            String::from_utf8_lossy(std::slice::from_raw_parts(data, item_count as usize))
+                .into_owned()
+        });
+        xlib::XFree(data as *mut _);
+        if name.is_some() {
+            return name;
+        }
+    }
+
+    let mut name = ptr::null_mut();
+    if xlib::XFetchName(display, window, &mut name) != 0 && !name.is_null() {
+        let title = CStr::from_ptr(name).to_string_lossy().into_owned();
+        xlib::XFree(name as *mut _);
+        return Some(title);
+    }
+
+    None
+}
+
+/// Position of the mouse cursor on the root window
+pub fn cursor_position(xconn: &x11::XConnection) -> Option<(i32, i32)> {
+    unsafe {
+        let root = (xconn.xlib.XDefaultRootWindow)(xconn.display);
+        let (mut root_return, mut child) = (0, 0);
+        let (mut x, mut y, mut window_x, mut window_y) = (0, 0, 0, 0);
+        let mut mask = 0;
+        let same_screen = (xconn.xlib.XQueryPointer)(
+            xconn.display,
+            root,
+            &mut root_return,
+            &mut child,
+            &mut x,
+            &mut y,
+            &mut window_x,
+            &mut window_y,
+            &mut mask,
+        );
+        if same_screen == 0 {
+            None
+        } else {
+            Some((x, y))
+        }
+    }
+}
+
+/// Keep Xlib from exiting the process when an X error occurs
+///
+/// Without an event loop there is no winit error handler installed. The capture code checks for
+/// failures itself, e.g. when the captured window was closed, so the errors can be ignored.
+pub fn ignore_x_errors() {
+    unsafe extern "C" fn handle_error(
+        _display: *mut xlib::Display,
+        _event: *mut xlib::XErrorEvent,
+    ) -> c_int {
+        0
+    }
+
+    unsafe {
+        xlib::XSetErrorHandler(Some(handle_error));
+    }
+}
+
+/// Whether the desktop is a Wayland session, where capturing has to go through the screen cast
+/// portal
+pub fn wayland_session() -> bool {
+    env::var("XDG_SESSION_TYPE").is_ok_and(|session| session == "wayland")
+        || env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// Make sure we can talk to an X server, which is where all windows are shown
+///
+/// On Wayland that is XWayland, which only knows about windows of applications that use X11
+/// themselves.
+pub fn check_session() -> Result<(), Box<dyn Error>> {
+    match (wayland_session(), env::var_os("DISPLAY").is_some()) {
+        (true, false) => Err(
+            "Wayland sessions without XWayland are not supported, an X server is required".into(),
+        ),
+        (false, false) => Err("No X server found, make sure DISPLAY is set".into()),
+        (_, true) => Ok(()),
+    }
+}
+
+/// Make sure the target can be captured in this session
+///
+/// The test pattern always can, and the screen cast portal reports by itself why it can't be
+/// used.
+pub fn check_capture_session(target: &CaptureTarget) -> Result<(), Box<dyn Error>> {
+    match target {
+        CaptureTarget::TestPattern | CaptureTarget::Portal => Ok(()),
+        _ => {
+            check_session()?;
+            if wayland_session() {
+                warn!("Running on XWayland, only windows of X11 applications can be captured");
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! The checks `--doctor` runs of the X server, the monitors and OpenGL
+
+use std::env;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::ptr;
+
+use ::x11::xlib;
+use glium::glutin::event_loop::EventLoop;
+use glium::glutin::platform::unix::{EventLoopExtUnix, WindowExtUnix};
+
+use crate::config::Settings;
+use crate::desktop::{check_session, list_monitors};
+use crate::render::create_offscreen_window;
+
+/// Check everything sharing a window relies on and print a line for each, as `--doctor` does
+///
+/// The window is created the same way as the shared windows are, just without capturing or
+/// drawing anything. Returns whether all checks passed.
+pub fn doctor() -> bool {
+    let mut passed = true;
+    let mut report = |check: &str, result: Result<String, Box<dyn Error>>| match result {
+        Ok(details) => println!("PASS  {}: {}", check, details),
+        Err(error) => {
+            println!("FAIL  {}: {}", check, error);
+            passed = false;
+        }
+    };
+
+    report(
+        "X11 session",
+        check_session().map(|()| format!("DISPLAY is {}", env::var("DISPLAY").unwrap_or_default())),
+    );
+    let connection = unsafe {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            Err("Unable to connect to the X server".into())
+        } else {
+            let vendor = CStr::from_ptr(xlib::XServerVendor(display))
+                .to_string_lossy()
+                .into_owned();
+            xlib::XCloseDisplay(display);
+            Ok(format!("connected to {}", vendor))
+        }
+    };
+    let connected = connection.is_ok();
+    report("Xlib connection", connection);
+    report(
+        "Monitors",
+        list_monitors().and_then(|monitors| match monitors.len() {
+            0 => Err("No active monitors were found".into()),
+            count => Ok(format!("{} found", count)),
+        }),
+    );
+
+    // winit can't even be set up without an X server
+    if !connected {
+        println!("SKIP  OpenGL window: needs the X server");
+        println!("SKIP  WM_STATE: needs the X server");
+        return false;
+    }
+
+    let window = EventLoop::new_x11()
+        .map_err(|error| format!("Unable to connect to the X server: {}", error).into())
+        .and_then(|el| {
+            let display = create_offscreen_window(
+                &el,
+                &Settings::default(),
+                "Screen splitter doctor",
+                1,
+                1,
+                None,
+            )?;
+            Ok((el, display))
+        });
+    match window {
+        Ok((_el, display)) => {
+            report(
+                "OpenGL window",
+                Ok(format!(
+                    "OpenGL {} on {}",
+                    display.get_opengl_version_string(),
+                    display.get_opengl_renderer_string()
+                )),
+            );
+            let window_id = display.gl_window().window().xlib_window();
+            report(
+                "WM_STATE",
+                window_id
+                    .ok_or_else(|| "The window is not an X11 window".into())
+                    .and_then(|window_id| unsafe { wm_state(window_id) })
+                    .and_then(|state| match state {
+                        1 => Ok("set to NormalState".to_string()),
+                        state => Err(format!("set to {} instead of NormalState", state).into()),
+                    }),
+            );
+        }
+        Err(error) => {
+            report("OpenGL window", Err(error));
+            println!("SKIP  WM_STATE: needs the window");
+        }
+    }
+
+    passed
+}
+
+/// Read the state from the WM_STATE property of a window, through a connection of its own
+unsafe fn wm_state(window: xlib::Window) -> Result<u64, Box<dyn Error>> {
+    let display = xlib::XOpenDisplay(ptr::null());
+    if display.is_null() {
+        return Err("Unable to connect to the X server".into());
+    }
+    let wm_state = CString::new("WM_STATE").unwrap();
+    let wm_state = xlib::XInternAtom(display, wm_state.as_ptr(), xlib::False);
+
+    let mut actual_type = 0;
+    let mut actual_format = 0;
+    let mut item_count = 0;
+    let mut bytes_after = 0;
+    let mut data = ptr::null_mut();
+    let result = xlib::XGetWindowProperty(
+        display,
+        window,
+        wm_state,
+        0,
+        2,
+        xlib::False,
+        wm_state,
+        &mut actual_type,
+        &mut actual_format,
+        &mut item_count,
+        &mut bytes_after,
+        &mut data,
+    );
+    // Properties with a format of 32 are handed out as longs
+    let state = (result == c_int::from(xlib::Success)
+        && !data.is_null()
+        && actual_format == 32
+        && item_count > 0)
+        .then(|| *(data as *const std::os::raw::c_ulong) as u64);
+    if !data.is_null() {
+        xlib::XFree(data as *mut _);
+    }
+    xlib::XCloseDisplay(display);
+    state.ok_or_else(|| "WM_STATE is not set".into())
+}
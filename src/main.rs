@@ -1,276 +1,483 @@
 #[macro_use]
 extern crate glium;
 
-use std::ffi::CString;
-use std::mem;
-use std::ops::Add;
-use std::time::{Duration, Instant};
-
-use clap::{App, Arg};
-use glium::glutin;
-use glium::glutin::dpi::{PhysicalPosition, PhysicalSize, Position};
-use glium::glutin::event::{Event, WindowEvent};
-use glium::glutin::event_loop::{ControlFlow, EventLoop};
-use glium::glutin::platform::unix::x11;
-use glium::glutin::platform::unix::{
-    EventLoopWindowTargetExtUnix, WindowBuilderExtUnix, WindowExtUnix,
+mod capture;
+mod cli;
+mod config;
+mod cpu;
+mod desktop;
+mod doctor;
+mod output;
+mod pacing;
+#[cfg(feature = "wayland")]
+mod portal;
+mod processing;
+mod render;
+mod window;
+
+use std::error::Error;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::Parser;
+use log::{info, warn};
+
+use crate::capture::{crop_source, open_source, Frame, FrameError};
+use crate::cli::Cli;
+use crate::config::{
+    append_instance_name, list_profiles, profile_path, rotated_size, scaled_size, CaptureTarget,
+    ConfigFile, Rect, Settings, CAPTURED_PACKING, MAX_TARGET_FPS,
 };
-use glium::vertex::VertexBufferAny;
-use glium::Surface;
-use x11cap::{Bgr8, CaptureSource, Capturer};
-
-struct Settings {
-    window_title: String,
-    target_fps: u32,
-    offscreen: bool,
-}
+use crate::desktop::{
+    check_capture_session, find_window_by_title, ignore_x_errors, list_monitors, refresh_rate,
+    resolve_monitor_ids, wait_for_capture, wayland_session,
+};
+use crate::doctor::doctor;
+use crate::output::{stream_http, stream_raw, stream_to_v4l2, take_screenshot};
+use crate::window::{display_capture_window, select_region};
 
 fn main() {
-    let matches = App::new("Screen splitter")
-        .version("0.1")
-        .about("Allows the user to share a single monitor in a video call")
-        .arg(
-            Arg::with_name("monitor-id")
-                .index(1)
-                .help("The ID of the monitor to mirror")
-                .required(true)
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("fps")
-                .long("fps")
-                .help("Target frames per second")
-                .default_value("30")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("onscreen")
-                .long("onscreen")
-                .help("Show the capture window on screen")
-                .takes_value(false),
-        )
-        .get_matches();
-
-    let monitor_id = match matches.value_of("monitor-id").unwrap().parse::<usize>() {
-        Ok(parsed_id) => parsed_id,
-        Err(_) => {
-            eprintln!("Monitor ID must be an integer");
-            return;
-        }
+    let cli = Cli::parse();
+
+    let default_level = if cli.verbose {
+        "debug"
+    } else if cli.quiet {
+        "error"
+    } else {
+        "warn"
     };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_level))
+        .init();
+
+    if cli.list_profiles {
+        match list_profiles() {
+            Ok(profiles) => {
+                for profile in profiles {
+                    println!("{}", profile);
+                }
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let target_fps = match matches.value_of("fps").unwrap().parse::<u32>() {
-        Ok(parsed_id) => parsed_id,
-        Err(_) => {
-            eprintln!("Target frames per second must be integer");
-            return;
+    if cli.doctor {
+        if !doctor() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if cli.list_monitors {
+        match list_monitors() {
+            Ok(monitors) => {
+                for (id, monitor) in monitors.iter().enumerate() {
+                    println!(
+                        "{} {} {}x{}+{}+{}",
+                        id, monitor.name, monitor.width, monitor.height, monitor.x, monitor.y
+                    );
+                }
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let ConfigFile {
+        source,
+        settings: mut config,
+    } = match cli.config_path().and_then(|path| match path {
+        Some(path) => ConfigFile::load(&path),
+        None => Ok(ConfigFile::default()),
+    }) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
         }
     };
 
-    let onscreen = matches.is_present("onscreen");
+    // XWayland can't see the windows of Wayland applications, only the portal can capture them
+    let portal =
+        cli.portal || (cfg!(feature = "wayland") && wayland_session() && !cli.test_pattern);
+    if portal && !cli.portal {
+        warn!("Running on Wayland, the screen cast portal asks what to share instead");
+    }
 
-    let source: CaptureSource = CaptureSource::Monitor(monitor_id);
+    let window_id = match &cli.window_title {
+        Some(_) if portal => None,
+        Some(pattern) => {
+            // Windows may disappear while the tree is searched
+            ignore_x_errors();
+            match find_window_by_title(pattern) {
+                Ok(window_id) => Some(window_id),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => cli.window_id,
+    };
 
-    display_capture_window(
-        Settings {
-            window_title: format!("Monitor {}", monitor_id),
-            target_fps,
-            offscreen: !onscreen,
+    // Arguments given on the command line take precedence over the configuration file
+    let monitor_ids = match resolve_monitor_ids(&cli.monitor_id) {
+        Ok(monitor_ids) => monitor_ids,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    let target = match (window_id, monitor_ids.as_slice(), source) {
+        _ if portal => CaptureTarget::Portal,
+        _ if cli.test_pattern => CaptureTarget::TestPattern,
+        _ if cli.screen => CaptureTarget::Screen,
+        _ if cli.all_monitors => match list_monitors() {
+            Ok(monitors) if monitors.len() == 1 => CaptureTarget::Monitor(0),
+            Ok(monitors) if !monitors.is_empty() => {
+                CaptureTarget::Monitors((0..monitors.len()).collect())
+            }
+            Ok(_) => {
+                eprintln!("No monitors were found");
+                std::process::exit(1);
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
         },
-        source,
-    );
-}
+        (Some(window_id), _, _) => CaptureTarget::Window(window_id),
+        (None, &[monitor_id], _) => CaptureTarget::Monitor(monitor_id),
+        (None, &[_, _, ..], _) => CaptureTarget::Monitors(monitor_ids.clone()),
+        (None, &[], Some(source)) => source,
+        (None, &[], None) => {
+            eprintln!("No monitor or window to capture was given");
+            std::process::exit(1);
+        }
+    };
 
-/// Create a window and mirror the image of the capture source
-fn display_capture_window(config: Settings, source: CaptureSource) {
-    let mut capturer = Capturer::new(source).expect("Unable to create screen capturer");
-    let geo = capturer.get_geometry();
-    let target_duration = Duration::new(0, 1_000_000_000u32 / config.target_fps);
+    let screenshot = cli.screenshot.clone();
+    let v4l2 = cli.v4l2.clone();
+    let http_stream = cli.http_stream.clone();
+    let raw_stdout = cli.raw_stdout;
+    let save_profile = cli.save_profile.clone();
+    let select = cli.select;
+    let autocrop = cli.autocrop;
+    let print_config = cli.print_config;
+    cli.apply(&mut config);
+
+    // The configuration file is not checked by clap, so everything is validated once merged
+    if config.target_fps > MAX_TARGET_FPS {
+        if !config.quiet {
+            eprintln!(
+                "Target frames per second of {} is too high, using {} instead",
+                config.target_fps, MAX_TARGET_FPS
+            );
+        }
+        config.target_fps = MAX_TARGET_FPS;
+    }
 
-    let el = glutin::event_loop::EventLoop::new();
-    let display = create_offscreen_window(&el, config, geo.width as i32, geo.height as i32);
+    if let Err(error) = config.validate() {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+    #[cfg(feature = "wayland")]
+    portal::show_cursor(config.show_cursor);
+
+    config.window_title = match &target {
+        CaptureTarget::Monitor(_) => format!("Monitor {}", target.id()),
+        CaptureTarget::Monitors(_) => format!("Monitors {}", target.id()),
+        CaptureTarget::Window(_) => format!("Window {}", target.id()),
+        CaptureTarget::Screen => "Screen".to_string(),
+        CaptureTarget::TestPattern => "Test pattern".to_string(),
+        CaptureTarget::Portal => "Screen cast".to_string(),
+    };
+    config.window_title =
+        append_instance_name(&config.window_title, config.instance_name.as_deref());
+
+    let mut monitor_ids = match &target {
+        CaptureTarget::Monitor(monitor_id) => vec![*monitor_id],
+        CaptureTarget::Monitors(monitor_ids) => monitor_ids.clone(),
+        CaptureTarget::Window(_)
+        | CaptureTarget::Screen
+        | CaptureTarget::TestPattern
+        | CaptureTarget::Portal => Vec::new(),
+    };
+    monitor_ids.extend(config.pip.map(|pip| pip.monitor));
+    monitor_ids.extend(config.fullscreen);
+    if let Err(error) = wait_for_capture(&target, &monitor_ids, config.startup_retries) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
 
-    #[derive(Copy, Clone)]
-    struct Vertex {
-        position: [f32; 2],
+    if config.fps_match_display {
+        match refresh_rate(&target) {
+            Ok(rate) => {
+                info!("Matching the refresh rate of {:.2} Hz", rate);
+                config.target_fps = (rate.round() as u32).clamp(1, MAX_TARGET_FPS);
+            }
+            Err(error) => warn!("{}, using {} fps instead", error, config.target_fps),
+        }
     }
 
-    implement_vertex!(Vertex, position);
-
-    let vb: VertexBufferAny = glium::VertexBuffer::new(
-        &display,
-        &[
-            Vertex {
-                position: [-1.0, 1.0],
-            },
-            Vertex {
-                position: [1.0, 1.0],
-            },
-            Vertex {
-                position: [-1.0, -1.0],
-            },
-            Vertex {
-                position: [1.0, -1.0],
-            },
-        ],
-    )
-    .unwrap()
-    .into();
-
-    let ib = glium::index::NoIndices(glium::index::PrimitiveType::TriangleStrip);
-
-    let program = glium::Program::from_source(
-        &display,
-        // Vertex shader
-        //
-        // We use the vertex shader to flip the image which would otherwise be upside down
-        r"
-                #version 330
-
-                in vec2 position;
-                out vec2 v_tex_coords;
-
-                void main() {
-                    v_tex_coords = position * vec2(0.5, -0.5) + vec2(0.5);
-                    gl_Position = vec4(position, 0.0, 1.0);
+    if select {
+        match select_region(&config, target.clone()) {
+            Ok(region) => {
+                if !config.quiet {
+                    eprintln!(
+                        "Selected region {},{},{},{}",
+                        region.x, region.y, region.width, region.height
+                    );
                 }
-        ",
-        // Fragment shader
-        //
-        // Since the image we get from X11 is BGR and we need RGB (blue and red are flipped)
-        // we correct this in the fragment shader. Doing this on the CPU would take too long
-        r"
-                #version 330
-
-                in vec2 v_tex_coords;
-                uniform sampler2D tex;
-
-                void main() {
-                    vec4 textureColor = texture(tex, v_tex_coords);
-                    gl_FragColor = vec4(textureColor.b, textureColor.g, textureColor.r, 1);
+                config.crop = Some(region);
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if autocrop {
+        match detect_content(&config, target.clone()) {
+            Ok(region) => {
+                if !config.quiet {
+                    eprintln!(
+                        "Cropping to the content at {},{},{},{}",
+                        region.x, region.y, region.width, region.height
+                    );
                 }
-        ",
-        None,
-    )
-    .expect("Error compiling shaders");
-
-    let mut next_iteration = Instant::now();
-    el.run(move |event, _, control_flow| {
-        let early_wakeup = next_iteration > Instant::now();
-
-        match event {
-            Event::LoopDestroyed => return,
-            Event::NewEvents(_) if !early_wakeup => {
-                let start_time = Instant::now();
-
-                // Capture the screen
-                let captured_frame = capturer.capture_frame().expect("Failed to capture frame");
-                let (width, height) = captured_frame.get_dimensions();
-                let pixel_data = unsafe {
-                    let slice = captured_frame.as_slice();
-                    std::slice::from_raw_parts(
-                        slice.as_ptr() as *const u8,
-                        slice.len() * mem::size_of::<Bgr8>(),
-                    )
-                };
-
-                // Create a texture containing the image data
-                let data =
-                    glium::texture::RawImage2d::from_raw_rgba(pixel_data.to_vec(), (width, height));
-                let dest_texture =
-                    glium::texture::srgb_texture2d::SrgbTexture2d::new(&display, data)
-                        .expect("Unable to create texture");
-
-                // Draw and display the frame
-                let mut target = display.draw();
-                let uniforms = uniform! { tex: &dest_texture };
-                target
-                    .draw(&vb, &ib, &program, &uniforms, &Default::default())
-                    .expect("Unable to execute shader");
-                target.finish().expect("Buffer swap failed");
-
-                // Calculate the tome of the next wakeup
-                let duration = start_time.elapsed();
-                next_iteration = if target_duration >= duration {
-                    let time_to_next_draw = target_duration - duration;
-                    Instant::now().add(time_to_next_draw)
-                } else {
-                    Instant::now()
-                };
-                *control_flow = ControlFlow::WaitUntil(next_iteration);
+                config.crop = Some(region);
             }
-            Event::NewEvents(_) if early_wakeup => {
-                // Wait again if there was an early wakeup
-                *control_flow = ControlFlow::WaitUntil(next_iteration);
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
             }
-            Event::WindowEvent { event, .. } => match event {
-                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                _ => (),
-            },
-            _ => (),
         }
+    }
+
+    if let Some(name) = save_profile {
+        let profile = ConfigFile {
+            source: Some(target.clone()),
+            settings: config.clone(),
+        };
+        match profile_path(&name).and_then(|path| profile.save(&path).map(|_| path)) {
+            Ok(path) if !config.quiet => {
+                eprintln!("Saved profile {} to {}", name, path.display())
+            }
+            Ok(_) => (),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // A fullscreen window is always on screen and never stretches the image
+    if config.fullscreen.is_some() {
+        config.offscreen = false;
+        config.keep_aspect = true;
+    }
+
+    if print_config {
+        if let Err(error) = print_resolved_config(&config, &target) {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    handle_termination_signals();
+
+    let result = if let Some(path) = screenshot {
+        take_screenshot(config, target, &path)
+    } else if let Some(device_path) = v4l2 {
+        stream_to_v4l2(config, target, &device_path)
+    } else if let Some(address) = http_stream {
+        stream_http(config, target, &address)
+    } else if raw_stdout {
+        stream_raw(config, target)
+    } else {
+        display_capture_window(config, target)
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+/// Print everything that determines what would be shared as JSON
+fn print_resolved_config(config: &Settings, target: &CaptureTarget) -> Result<(), Box<dyn Error>> {
+    check_capture_session(target)?;
+    ignore_x_errors();
+
+    let source = open_source(target.clone())?;
+    let (target_width, target_height) = source.size();
+    let source = crop_source(config, source)?;
+    let (width, height) = source.size();
+    let (window_width, window_height) = rotated_size((width, height), config.rotation);
+
+    let monitor_ids = match target {
+        CaptureTarget::Monitor(monitor_id) => vec![*monitor_id],
+        CaptureTarget::Monitors(monitor_ids) => monitor_ids.clone(),
+        CaptureTarget::Window(_)
+        | CaptureTarget::Screen
+        | CaptureTarget::TestPattern
+        | CaptureTarget::Portal => Vec::new(),
+    };
+    // The test pattern does not need an X server to ask for the monitors
+    let monitors = if monitor_ids.is_empty() {
+        Vec::new()
+    } else {
+        list_monitors()?
+    };
+    let monitors: Vec<_> = monitor_ids
+        .iter()
+        .filter_map(|&id| monitors.get(id).map(|monitor| (id, monitor)))
+        .map(|(id, monitor)| {
+            serde_json::json!({
+                "id": id,
+                "name": monitor.name,
+                "x": monitor.x,
+                "y": monitor.y,
+                "width": monitor.width,
+                "height": monitor.height,
+            })
+        })
+        .collect();
+
+    let resolved = serde_json::json!({
+        "source": target,
+        "monitors": monitors,
+        "geometry": {
+            "target_width": target_width,
+            "target_height": target_height,
+            "origin": source.origin(),
+            "width": width,
+            "height": height,
+            "window_width": scaled_size(window_width, config.scale),
+            "window_height": scaled_size(window_height, config.scale),
+        },
+        "settings": config,
     });
+    println!("{}", serde_json::to_string_pretty(&resolved)?);
+
+    Ok(())
 }
 
-/// This function creates a off screen window.
-///
-/// As it turns out this is **A LOT** harder than you might imagine. Usually window managers ignore
-/// requests to place windows outside the screen area due to the assumption that the request was
-/// made in error. However, we actually really want the window to be not visible by the user.
-///
-/// To achieve this we set the override-redirect option when creating the window. This option
-/// means that the window is not managed by the window manager. It also has the side effect that
-/// the window is not decorated and no task bar entry is created. However, it also causes the
-/// WM_STATE property not to be set by the window manager.
+/// Find the region of the capture target that is not covered by black borders
 ///
-/// Since chrome uses the WM_STATE property to determine which windows are displayed in the window
-/// selection dialog we need to set this property manually. Setting this property turned out to be
-/// really hard, but thankfully winit allows us to do this after jumping through some hoops.
-fn create_offscreen_window(
-    el: &EventLoop<()>,
-    config: Settings,
-    width: i32,
-    height: i32,
-) -> glium::Display {
-    // Build a new window. Make sure to set the override_redirect option so the window is not
-    // managed by the window manager.
-    let wb = glutin::window::WindowBuilder::new()
-        .with_title(config.window_title)
-        .with_inner_size(PhysicalSize::new(width, height))
-        .with_override_redirect(config.offscreen);
-
-    let cb = glutin::ContextBuilder::new();
-    let display = glium::Display::new(wb, cb, &el).unwrap();
-
-    {
-        let gl_window = display.gl_window();
-        let window = gl_window.window();
-
-        if config.offscreen {
-            // Move the window outside the visible screen area
-            window.set_outer_position(Position::Physical(PhysicalPosition::new(
-                width * -1,
-                height * -1,
-            )));
+/// Only the first frame is looked at, so the crop region stays the same for the whole session. If
+/// a crop region is configured already, the borders are only looked for inside of it.
+fn detect_content(config: &Settings, target: CaptureTarget) -> Result<Rect, Box<dyn Error>> {
+    check_capture_session(&target)?;
+    ignore_x_errors();
+
+    let mut source = crop_source(config, open_source(target)?)?;
+    let frame = match source.capture_frame() {
+        Ok(frame) => frame,
+        Err(FrameError::SourceClosed) => return Err("The captured window was closed".into()),
+        Err(FrameError::Failed(reason)) => {
+            return Err(format!("Failed to capture frame: {}", reason).into())
         }
+    };
+
+    let content = content_bounds(&frame, config.autocrop_threshold)
+        .ok_or("The captured image is completely black, there is nothing to crop")?;
+    Ok(match config.crop {
+        Some(crop) => Rect {
+            x: crop.x + content.x,
+            y: crop.y + content.y,
+            ..content
+        },
+        None => content,
+    })
+}
+
+/// Smallest rectangle containing every pixel with a color channel brighter than `threshold`
+fn content_bounds(frame: &Frame, threshold: u8) -> Option<Rect> {
+    let (width, _) = frame.dimensions();
+    let mut bounds: Option<((u32, u32), (u32, u32))> = None;
+
+    // Any padding comes after the channels, whose order doesn't matter here
+    let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+    for (i, pixel) in frame.as_bytes().chunks_exact(bytes_per_pixel).enumerate() {
+        if pixel[..3].iter().all(|&channel| channel <= threshold) {
+            continue;
+        }
+
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        bounds = Some(match bounds {
+            Some(((left, top), (right, bottom))) => (
+                (left.min(x), top.min(y)),
+                (right.max(x + 1), bottom.max(y + 1)),
+            ),
+            None => ((x, y), (x + 1, y + 1)),
+        });
+    }
+
+    bounds.map(|(top_left, bottom_right)| Rect::spanning(top_left, bottom_right))
+}
 
-        // Set the WM_STATE property so the window is shown in the chrome window selection dialog
-        let xlib = el.xlib_xconnection().unwrap();
-        let window_id = window.xlib_window().unwrap();
-        let wm_state_atom = xlib.get_atom(CString::new("WM_STATE").unwrap().as_c_str());
-        xlib.change_property(
-            window_id,
-            wm_state_atom,
-            wm_state_atom,
-            x11::util::PropMode::Replace,
-            &[
-                1 as x11::util::Cardinal, // NormalState
-                0 as x11::util::Cardinal, // None
-            ],
-        )
-        .flush()
-        .unwrap();
+/// Set once the process received SIGINT or SIGTERM
+static TERMINATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Let the capture loops exit normally on SIGINT and SIGTERM
+///
+/// The default handlers kill the process right away. The offscreen windows are override-redirect,
+/// so the window manager does not clean them up and they may linger until the X server notices
+/// the closed connection. Exiting the event loop destroys them properly instead.
+fn handle_termination_signals() {
+    extern "C" fn handle_signal(_signal: c_int) {
+        TERMINATION_REQUESTED.store(true, Ordering::SeqCst);
+    }
+
+    let handler = handle_signal as extern "C" fn(c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
     }
+}
 
-    return display;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A black frame with the pixels inside `content` set to gray
+    fn frame_with_content(width: u32, height: u32, content: Rect) -> Frame {
+        let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+        let mut data = vec![0; CAPTURED_PACKING.byte_len((width * height) as usize)];
+        for y in content.y..content.y + content.height {
+            for x in content.x..content.x + content.width {
+                let start = (y * width + x) as usize * bytes_per_pixel;
+                data[start..start + 3].fill(100);
+            }
+        }
+        Frame::Composite {
+            data: data.into(),
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn content_bounds_skip_black_borders() {
+        let content = Rect {
+            x: 3,
+            y: 1,
+            width: 4,
+            height: 2,
+        };
+        let frame = frame_with_content(10, 5, content);
+        assert_eq!(content_bounds(&frame, 16), Some(content));
+        // Everything up to the threshold counts as black
+        assert_eq!(content_bounds(&frame, 100), None);
+    }
 }
@@ -0,0 +1,165 @@
+//! Serving the frames as an MJPEG stream over HTTP
+
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use image::codecs::jpeg::JpegEncoder;
+use log::{debug, warn};
+
+/// Quality of the images in the HTTP stream, between 1 and 100
+const JPEG_QUALITY: u8 = 80;
+
+/// Separates the images in the HTTP stream
+const MJPEG_BOUNDARY: &str = "frame";
+
+/// How long clients may take to send their request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the most recent frame as a `multipart/x-mixed-replace` stream of JPEG images
+///
+/// Every client gets its own thread, which sends each published frame once. Slow clients simply
+/// skip the frames they were too slow for.
+pub struct MjpegServer {
+    shared: Arc<SharedJpeg>,
+    /// Address the server listens on, with the actual port if port 0 was asked for
+    pub address: SocketAddr,
+}
+
+/// The most recent JPEG image, together with the number of frames published so far
+#[derive(Default)]
+struct SharedJpeg {
+    frame: Mutex<(u64, Arc<Vec<u8>>)>,
+    published: Condvar,
+    clients: AtomicUsize,
+    /// Tells the clients that no more frames are coming
+    stopped: AtomicBool,
+}
+
+impl MjpegServer {
+    pub fn start(address: &str) -> Result<MjpegServer, Box<dyn Error>> {
+        let listener = TcpListener::bind(address)
+            .map_err(|error| format!("Unable to listen on {}: {}", address, error))?;
+        let shared = Arc::new(SharedJpeg::default());
+        let server = MjpegServer {
+            shared: Arc::clone(&shared),
+            address: listener.local_addr()?,
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        warn!("Unable to accept a connection: {}", error);
+                        continue;
+                    }
+                };
+
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || {
+                    shared.clients.fetch_add(1, Ordering::SeqCst);
+                    if let Err(error) = serve_client(stream, &shared) {
+                        debug!("Stream client disconnected: {}", error);
+                    }
+                    shared.clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        Ok(server)
+    }
+
+    pub fn has_clients(&self) -> bool {
+        self.shared.clients.load(Ordering::SeqCst) > 0
+    }
+
+    /// Encode the image and send it to all clients
+    pub fn publish(&self, image: &image::RgbImage) -> Result<(), Box<dyn Error>> {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(image)?;
+
+        let mut frame = self.shared.frame.lock().unwrap();
+        *frame = (frame.0 + 1, Arc::new(jpeg));
+        self.shared.published.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for MjpegServer {
+    fn drop(&mut self) {
+        // Holding the lock makes sure no client is between checking the flag and waiting
+        let _frame = self.shared.frame.lock().unwrap();
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        self.shared.published.notify_all();
+    }
+}
+
+/// Answer the request of a client with the stream, until the client disconnects
+fn serve_client(mut stream: TcpStream, shared: &SharedJpeg) -> io::Result<()> {
+    // There is only one thing to serve, so the request itself does not matter
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    for line in BufReader::new(&stream).lines() {
+        if line?.is_empty() {
+            break;
+        }
+    }
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\n\
+         Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\r\n",
+        MJPEG_BOUNDARY
+    )?;
+
+    let mut sent = 0;
+    loop {
+        let jpeg = {
+            let mut frame = shared.frame.lock().unwrap();
+            while frame.0 == sent {
+                if shared.stopped.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                frame = shared.published.wait(frame).unwrap();
+            }
+            sent = frame.0;
+            Arc::clone(&frame.1)
+        };
+
+        write!(
+            stream,
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            MJPEG_BOUNDARY,
+            jpeg.len()
+        )?;
+        stream.write_all(&jpeg)?;
+        stream.write_all(b"\r\n")?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_clients_are_let_go_once_the_server_stops() {
+        use std::io::Read;
+
+        let server = MjpegServer::start("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(server.address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        drop(server);
+
+        // The connection is closed instead of waiting for the next frame
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK"));
+    }
+}
@@ -0,0 +1,346 @@
+//! Outputs that share the captured frames without a window
+
+mod http;
+pub mod record;
+mod v4l2;
+
+use std::error::Error;
+use std::ffi::CString;
+use std::fs::{self};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::capture::{
+    frame_hash, open_cropped_source, Frame, FrameError, FrameSource, SourceRecovery,
+    MAX_CONSECUTIVE_CAPTURE_FAILURES,
+};
+use crate::config::{CaptureTarget, Settings, SourceLostAction, VideoFormat};
+use crate::cpu::{
+    color_lookup_table, local_time, process_rgb24, processed_size, rgb24_to_nv12, rgb24_to_yuyv,
+    rgb_image,
+};
+use crate::desktop::{check_capture_session, ignore_x_errors};
+use crate::output::http::MjpegServer;
+use crate::output::record::{record_frame, Recorder};
+use crate::output::v4l2::V4l2Output;
+use crate::pacing::FramePacer;
+use crate::processing::processors;
+use crate::TERMINATION_REQUESTED;
+
+/// Capture frames and serve them as an MJPEG stream over HTTP instead of showing them in a window
+///
+/// Any browser can show the stream, so the shared area can be watched on another device.
+pub fn stream_http(
+    config: Settings,
+    target: CaptureTarget,
+    address: &str,
+) -> Result<(), Box<dyn Error>> {
+    check_capture_session(&target)?;
+    ignore_x_errors();
+
+    let source = open_cropped_source(&config, target.clone())?;
+    let server = MjpegServer::start(address)?;
+    if !config.quiet {
+        eprintln!("Serving the stream on http://{}", server.address);
+    }
+    let lookup_table = color_lookup_table(&config);
+    stream_frames(&config, &target, source, |frame| {
+        // Encoding is by far the most expensive part, so it is skipped while nobody watches
+        if server.has_clients() {
+            server.publish(&rgb_image(frame, &config, &lookup_table)?)?;
+        }
+        Ok(())
+    })
+}
+
+/// Capture frames and write them to stdout as raw RGB24 data instead of showing them in a window
+///
+/// The size of the frames is printed to stderr, so that e.g. ffmpeg can be told how to read them.
+pub fn stream_raw(config: Settings, target: CaptureTarget) -> Result<(), Box<dyn Error>> {
+    check_capture_session(&target)?;
+    ignore_x_errors();
+
+    let source = open_cropped_source(&config, target.clone())?;
+    let (width, height) = processed_size(source.size(), &config);
+    if !config.quiet {
+        eprintln!(
+            "Writing {}x{} rgb24 frames at {} fps to stdout",
+            width, height, config.target_fps
+        );
+    }
+
+    let lookup_table = color_lookup_table(&config);
+    let mut stdout = io::stdout().lock();
+    stream_frames(&config, &target, source, |frame| {
+        stdout.write_all(&rgb_image(frame, &config, &lookup_table)?)?;
+        stdout.flush()?;
+        Ok(())
+    })
+}
+
+/// Capture frames at the target frame rate and pass them to `output`, until stopped
+///
+/// This is the frame loop of all outputs that don't need a window. The frames are recorded as well
+/// if requested.
+fn stream_frames(
+    config: &Settings,
+    target: &CaptureTarget,
+    mut source: Box<dyn FrameSource>,
+    mut output: impl FnMut(&Frame) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let (width, height) = source.size();
+    let mut recorder = match &config.record {
+        Some(path) => Some(Recorder::start(path, width, height, config)?),
+        None => None,
+    };
+
+    let mut pacer = FramePacer::new(config.target_fps);
+    let mut consecutive_failures = 0;
+    let mut lost_source: Option<SourceRecovery> = None;
+    let mut processors = processors(config);
+    let idle_timeout = Duration::from_secs(config.idle_timeout.into());
+    let mut last_frame_hash = None;
+    let mut last_change = Instant::now();
+    let mut idle = false;
+    // Repeated until the next frame is due for capturing
+    let mut held_frame = None;
+    while !TERMINATION_REQUESTED.load(Ordering::SeqCst) {
+        pacer.sleep();
+        let start_time = pacer.start_frame();
+
+        let capture_due = (pacer.frame_count - 1).is_multiple_of(config.capture_every);
+        let capture_result = if let Some(recovery) = &mut lost_source {
+            recovery.try_reopen(config, target, source.size()).map(
+                |(new_source, captured_frame)| {
+                    source = new_source;
+                    lost_source = None;
+                    Ok(captured_frame)
+                },
+            )
+        } else if capture_due || held_frame.is_none() {
+            Some(source.capture_frame())
+        } else {
+            None
+        };
+        let fresh = capture_result.is_some();
+        let frame = match capture_result {
+            None => held_frame.as_ref(),
+            Some(Ok(captured_frame)) => {
+                consecutive_failures = 0;
+                let (width, height) = captured_frame.dimensions();
+                for processor in &mut processors {
+                    processor.process(captured_frame.as_bytes(), width, height);
+                }
+                held_frame = Some(captured_frame);
+                held_frame.as_ref()
+            }
+            Some(Err(FrameError::SourceClosed))
+                if config.on_source_lost == SourceLostAction::Exit =>
+            {
+                if !config.quiet {
+                    eprintln!("The captured window was closed");
+                }
+                break;
+            }
+            Some(Err(FrameError::SourceClosed)) => {
+                let (recovery, placeholder) = SourceRecovery::start(config, source.size());
+                lost_source = Some(recovery);
+                held_frame = placeholder.or(held_frame);
+                held_frame.as_ref()
+            }
+            Some(Err(FrameError::Failed(reason))) => {
+                consecutive_failures += 1;
+                warn!(
+                    "Failed to capture frame ({} of {} in a row): {}",
+                    consecutive_failures, MAX_CONSECUTIVE_CAPTURE_FAILURES, reason
+                );
+
+                if consecutive_failures < MAX_CONSECUTIVE_CAPTURE_FAILURES {
+                    None
+                } else if config.on_source_lost == SourceLostAction::Exit {
+                    break;
+                } else {
+                    consecutive_failures = 0;
+                    let (recovery, placeholder) = SourceRecovery::start(config, source.size());
+                    lost_source = Some(recovery);
+                    held_frame = placeholder.or(held_frame);
+                    held_frame.as_ref()
+                }
+            }
+        };
+
+        if let Some(captured_frame) = frame {
+            // The readers keep the last frame, so nothing is lost by not repeating it
+            if config.idle_timeout > 0 {
+                let hash = frame_hash(captured_frame.as_bytes());
+                if last_frame_hash != Some(hash) {
+                    last_frame_hash = Some(hash);
+                    last_change = Instant::now();
+                }
+                let was_idle = idle;
+                idle = last_change.elapsed() >= idle_timeout;
+                if idle && !was_idle {
+                    info!(
+                        "Nothing changed for {} s, pausing the output",
+                        config.idle_timeout
+                    );
+                } else if was_idle && !idle {
+                    info!("The image changed, resuming the output");
+                }
+            }
+            if !idle {
+                output(captured_frame)?;
+            }
+
+            // The recording repeats its last frame by itself
+            if let Some(recorder) = recorder.as_mut().filter(|_| fresh) {
+                recorder.update(captured_frame.as_bytes(), config);
+            }
+        }
+
+        record_frame(&mut recorder);
+        pacer.finish_frame(start_time);
+        if pacer.reached(config.max_frames) {
+            break;
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+
+    if config.stats || !config.quiet {
+        pacer.print_statistics(config.stats);
+    }
+
+    if consecutive_failures >= MAX_CONSECUTIVE_CAPTURE_FAILURES {
+        return Err(format!(
+            "Giving up after {} frames in a row failed to be captured",
+            consecutive_failures
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Capture frames and write them to a v4l2loopback device instead of showing them in a window
+///
+/// This way the image can be picked as a camera in any application, not just in those that can
+/// share X11 windows.
+pub fn stream_to_v4l2(
+    config: Settings,
+    target: CaptureTarget,
+    device_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    check_capture_session(&target)?;
+    ignore_x_errors();
+
+    let source = open_cropped_source(&config, target.clone())?;
+    let (width, height) = processed_size(source.size(), &config);
+    let mut device = V4l2Output::open(device_path, width, height, config.v4l2_format)?;
+    let lookup_table = color_lookup_table(&config);
+    let mut buffer = Vec::new();
+    let mut converted = Vec::new();
+    stream_frames(&config, &target, source, |frame| {
+        let (frame_width, frame_height) = frame.dimensions();
+        process_rgb24(
+            frame.as_bytes(),
+            frame_width,
+            frame_height,
+            &config,
+            &lookup_table,
+            &mut buffer,
+        )?;
+        match config.v4l2_format {
+            VideoFormat::Rgb24 => device.write_frame(&buffer),
+            VideoFormat::Yuyv => {
+                rgb24_to_yuyv(&buffer, width, height, &mut converted);
+                device.write_frame(&converted)
+            }
+            VideoFormat::Nv12 => {
+                rgb24_to_nv12(&buffer, width, height, &mut converted);
+                device.write_frame(&converted)
+            }
+        }
+    })
+}
+
+/// Capture a single frame and save it as a PNG image
+pub fn take_screenshot(
+    config: Settings,
+    target: CaptureTarget,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    check_capture_session(&target)?;
+    ignore_x_errors();
+
+    let mut source = open_cropped_source(&config, target)?;
+    let captured_frame = match source.capture_frame() {
+        Ok(captured_frame) => captured_frame,
+        Err(FrameError::SourceClosed) => return Err("The captured window was closed".into()),
+        Err(FrameError::Failed(reason)) => {
+            return Err(format!("Failed to capture frame: {}", reason).into())
+        }
+    };
+
+    rgb_image(&captured_frame, &config, &color_lookup_table(&config))?
+        .save_with_format(path, image::ImageFormat::Png)
+        .map_err(|error| format!("Unable to save {}: {}", path.display(), error).into())
+}
+
+/// Save a snapshot of a shared window as a PNG image in the directory, named after the current
+/// time and the number of the window if there are several
+///
+/// Snapshots taken within the same second are numbered, so none of them is overwritten. Returns
+/// the path of the image.
+pub fn save_snapshot(
+    image: &image::RgbImage,
+    directory: &Path,
+    window: Option<usize>,
+) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(directory)
+        .map_err(|error| format!("Unable to create {}: {}", directory.display(), error))?;
+
+    let mut name = local_time(&CString::new("screensplitter-%Y%m%d-%H%M%S")?);
+    if let Some(window) = window {
+        name.push_str(&format!("-window{}", window));
+    }
+    let mut path = directory.join(format!("{}.png", name));
+    let mut number = 1;
+    while path.exists() {
+        number += 1;
+        path = directory.join(format!("{}-{}.png", name, number));
+    }
+
+    image
+        .save_with_format(&path, image::ImageFormat::Png)
+        .map_err(|error| format!("Unable to save {}: {}", path.display(), error))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn snapshots_are_never_overwritten() {
+        let directory = env::temp_dir().join(format!("screensplitter-test-{}", std::process::id()));
+        let image = image::RgbImage::new(2, 2);
+        let first = save_snapshot(&image, &directory, None).unwrap();
+        let second = save_snapshot(&image, &directory, None).unwrap();
+        let window = save_snapshot(&image, &directory, Some(2)).unwrap();
+        let saved = image::open(&first).map(|saved| saved.to_rgb8());
+        let _ = fs::remove_dir_all(&directory);
+
+        assert_ne!(first, second);
+        assert!(window.to_string_lossy().contains("-window2"));
+        assert_eq!(saved.unwrap(), image);
+    }
+}
@@ -0,0 +1,127 @@
+//! Recording the shared frames to a video file with ffmpeg
+
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use log::{info, warn};
+
+use crate::config::Settings;
+use crate::cpu::{color_lookup_table, process_rgb24, processed_size, ColorTable};
+
+/// Records the captured frames to a video file by piping them into ffmpeg
+pub struct Recorder {
+    ffmpeg: Child,
+    width: u32,
+    height: u32,
+    lookup_table: ColorTable,
+    /// The most recent frame, converted to the format ffmpeg reads
+    buffer: Vec<u8>,
+}
+
+impl Recorder {
+    /// Start ffmpeg, which reads raw frames from its standard input
+    ///
+    /// The frames are captured with the given size, and processed like those of all outputs
+    /// without a window. The frame rate of the recording is the target frame rate, so every
+    /// iteration of the capture loop has to write exactly one frame.
+    pub fn start(
+        path: &Path,
+        width: u32,
+        height: u32,
+        config: &Settings,
+    ) -> Result<Recorder, Box<dyn Error>> {
+        // The encoder needs even dimensions
+        let even = |size: u32| (size & !1).max(2);
+        let (output_width, output_height) = processed_size((width, height), config);
+
+        let ffmpeg = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-y"])
+            .args(["-f", "rawvideo", "-pixel_format", "rgb24"])
+            .arg("-video_size")
+            .arg(format!("{}x{}", output_width, output_height))
+            .arg("-framerate")
+            .arg(config.target_fps.to_string())
+            .args(["-i", "-"])
+            .arg("-vf")
+            .arg(format!(
+                "scale={}:{}",
+                even(output_width),
+                even(output_height)
+            ))
+            .args(["-pix_fmt", "yuv420p"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|error| format!("Unable to start ffmpeg for recording: {}", error))?;
+        info!(
+            "Recording to {} with ffmpeg (pid {})",
+            path.display(),
+            ffmpeg.id()
+        );
+
+        Ok(Recorder {
+            ffmpeg,
+            width,
+            height,
+            lookup_table: color_lookup_table(config),
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Replace the frame that is written to the recording
+    pub fn update(&mut self, pixels: &[u8], config: &Settings) {
+        let processed = process_rgb24(
+            pixels,
+            self.width,
+            self.height,
+            config,
+            &self.lookup_table,
+            &mut self.buffer,
+        );
+        if let Err(error) = processed {
+            warn!("Unable to record the frame: {}", error);
+            self.buffer.clear();
+        }
+    }
+
+    /// Write the most recent frame to the recording
+    ///
+    /// If no new frame was captured since the last call, e.g. because capturing failed, the
+    /// previous one is repeated to keep the timing of the recording intact.
+    pub fn write_frame(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.ffmpeg
+            .stdin
+            .as_mut()
+            .expect("ffmpeg was started without standard input")
+            .write_all(&self.buffer)
+            .map_err(|error| format!("Unable to write frame to ffmpeg: {}", error).into())
+    }
+
+    /// Close the input of ffmpeg and wait for it to finalize the file
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        drop(self.ffmpeg.stdin.take());
+        let status = self.ffmpeg.wait()?;
+        if !status.success() {
+            return Err(format!("ffmpeg failed to finish the recording: {}", status).into());
+        }
+        Ok(())
+    }
+}
+
+/// Feed the most recent frame to the recorder, stopping the recording if that fails
+pub fn record_frame(recorder: &mut Option<Recorder>) {
+    if let Some(active) = recorder {
+        if let Err(error) = active.write_frame() {
+            warn!("{}, stopping the recording", error);
+            if let Some(failed) = recorder.take() {
+                let _ = failed.finish();
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Writing the frames to a v4l2loopback device, which applications see as a webcam
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use crate::config::VideoFormat;
+
+/// Format description passed to the `VIDIOC_S_FMT` ioctl, mirroring `struct v4l2_format`
+#[repr(C)]
+struct V4l2Format {
+    buffer_type: u32,
+    pix: V4l2PixFormat,
+}
+
+/// Mirrors `struct v4l2_pix_format`
+///
+/// In the kernel this is part of a union which is 200 bytes large and contains pointers, hence
+/// the padding and alignment.
+#[repr(C, align(8))]
+struct V4l2PixFormat {
+    width: u32,
+    height: u32,
+    pixel_format: u32,
+    field: u32,
+    bytes_per_line: u32,
+    size_image: u32,
+    colorspace: u32,
+    private: u32,
+    flags: u32,
+    ycbcr_encoding: u32,
+    quantization: u32,
+    transfer_function: u32,
+    _reserved: [u8; 152],
+}
+
+const V4L2_BUF_TYPE_VIDEO_OUTPUT: u32 = 2;
+const V4L2_FIELD_NONE: u32 = 1;
+const V4L2_COLORSPACE_SRGB: u32 = 8;
+const V4L2_COLORSPACE_REC709: u32 = 3;
+const V4L2_YCBCR_ENC_709: u32 = 2;
+const V4L2_QUANTIZATION_LIM_RANGE: u32 = 2;
+/// The `RGB3` four character code
+const V4L2_PIX_FMT_RGB24: u32 = u32::from_le_bytes(*b"RGB3");
+const V4L2_PIX_FMT_YUYV: u32 = u32::from_le_bytes(*b"YUYV");
+const V4L2_PIX_FMT_NV12: u32 = u32::from_le_bytes(*b"NV12");
+/// `_IOWR('V', 5, struct v4l2_format)`
+const VIDIOC_S_FMT: libc::c_ulong = (3 << 30)
+    | ((mem::size_of::<V4l2Format>() as libc::c_ulong) << 16)
+    | ((b'V' as libc::c_ulong) << 8)
+    | 5;
+
+/// A v4l2loopback device we write frames to
+pub struct V4l2Output {
+    device: File,
+}
+
+impl V4l2Output {
+    /// Open the device and configure it for frames of the given size and format
+    pub fn open(
+        path: &str,
+        width: u32,
+        height: u32,
+        video_format: VideoFormat,
+    ) -> Result<V4l2Output, Box<dyn Error>> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|error| format!("Unable to open {}: {}", path, error))?;
+
+        // The YUV formats share their colors between neighboring pixels
+        let (pixel_format, width, height, bytes_per_line, size_image) = match video_format {
+            VideoFormat::Rgb24 => (
+                V4L2_PIX_FMT_RGB24,
+                width,
+                height,
+                width * 3,
+                width * height * 3,
+            ),
+            VideoFormat::Yuyv => {
+                let width = width & !1;
+                (
+                    V4L2_PIX_FMT_YUYV,
+                    width,
+                    height,
+                    width * 2,
+                    width * height * 2,
+                )
+            }
+            VideoFormat::Nv12 => {
+                let (width, height) = (width & !1, height & !1);
+                (
+                    V4L2_PIX_FMT_NV12,
+                    width,
+                    height,
+                    width,
+                    width * height * 3 / 2,
+                )
+            }
+        };
+        let yuv = video_format != VideoFormat::Rgb24;
+        if width == 0 || height == 0 {
+            return Err("The captured area is too small for the video format".into());
+        }
+
+        let mut format = V4l2Format {
+            buffer_type: V4L2_BUF_TYPE_VIDEO_OUTPUT,
+            pix: V4l2PixFormat {
+                width,
+                height,
+                pixel_format,
+                field: V4L2_FIELD_NONE,
+                bytes_per_line,
+                size_image,
+                colorspace: if yuv {
+                    V4L2_COLORSPACE_REC709
+                } else {
+                    V4L2_COLORSPACE_SRGB
+                },
+                private: 0,
+                flags: 0,
+                ycbcr_encoding: if yuv { V4L2_YCBCR_ENC_709 } else { 0 },
+                quantization: if yuv { V4L2_QUANTIZATION_LIM_RANGE } else { 0 },
+                transfer_function: 0,
+                _reserved: [0; 152],
+            },
+        };
+
+        if unsafe { libc::ioctl(device.as_raw_fd(), VIDIOC_S_FMT, &mut format) } < 0 {
+            return Err(format!(
+                "Unable to set the video format of {}: {}",
+                path,
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+
+        Ok(V4l2Output { device })
+    }
+
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.device
+            .write_all(data)
+            .map_err(|error| format!("Unable to write frame to video device: {}", error).into())
+    }
+}
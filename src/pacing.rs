@@ -0,0 +1,282 @@
+//! Timing of the frame loop and the statistics collected about it
+
+use std::collections::VecDeque;
+use std::ops::AddAssign;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+/// How long before a frame is due we stop sleeping and busy wait instead
+///
+/// Waking up from `ControlFlow::WaitUntil` regularly happens a few milliseconds late, which
+/// noticeably lowers the achieved frame rate.
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+/// Decides when the next frame is due and keeps track of the achieved frame rate
+pub struct FramePacer {
+    target_duration: Duration,
+    next_iteration: Instant,
+    first_frame: Option<Instant>,
+    last_frame: Option<Instant>,
+    pub frame_count: u32,
+    /// Time between the starts of the most recent frames
+    recent_intervals: VecDeque<Duration>,
+    /// Shortest and longest time between the starts of two frames
+    interval_range: Option<(Duration, Duration)>,
+    /// Number of frames that took longer than the target frame duration
+    late_frames: u32,
+    /// Number of frames in a row that took longer than the target frame duration
+    late_streak: u32,
+    /// Whether the user was already told that the target frame rate can't be reached
+    overrun_reported: bool,
+}
+
+/// Number of frames the rolling average of the frame rate is calculated over
+const RECENT_FRAME_COUNT: usize = 30;
+
+/// Number of frames between two log messages with timing statistics
+const STATS_LOG_INTERVAL: u32 = 300;
+
+impl FramePacer {
+    pub fn new(target_fps: u32) -> FramePacer {
+        FramePacer {
+            target_duration: Duration::from_secs(1) / target_fps.max(1),
+            next_iteration: Instant::now(),
+            first_frame: None,
+            last_frame: None,
+            frame_count: 0,
+            recent_intervals: VecDeque::with_capacity(RECENT_FRAME_COUNT),
+            interval_range: None,
+            late_frames: 0,
+            late_streak: 0,
+            overrun_reported: false,
+        }
+    }
+
+    /// Change the target frame rate, starting with the next frame
+    pub fn set_target_fps(&mut self, target_fps: u32) {
+        self.target_duration = Duration::from_secs(1) / target_fps.max(1);
+        // Whether the new target can be reached is yet to be seen
+        self.late_streak = 0;
+        self.overrun_reported = false;
+    }
+
+    /// Time at which we should wake up to start the next frame
+    ///
+    /// This is shortly before the frame is actually due, the remaining time is spent busy waiting
+    /// in `is_due`.
+    pub fn wake_up_time(&self) -> Instant {
+        self.next_iteration - SPIN_MARGIN
+    }
+
+    /// Check whether the next frame is due, busy waiting for it if it is due shortly
+    pub fn is_due(&self) -> bool {
+        if self.next_iteration > Instant::now() + SPIN_MARGIN {
+            return false;
+        }
+
+        while Instant::now() < self.next_iteration {
+            std::hint::spin_loop();
+        }
+        true
+    }
+
+    /// Block the thread until the next frame is due
+    pub fn sleep(&self) {
+        if let Some(duration) = self.wake_up_time().checked_duration_since(Instant::now()) {
+            thread::sleep(duration);
+        }
+        self.is_due();
+    }
+
+    /// Record the start of a new frame and return its start time
+    pub fn start_frame(&mut self) -> Instant {
+        let start_time = Instant::now();
+        if let Some(last_frame) = self.last_frame {
+            if self.recent_intervals.len() == RECENT_FRAME_COUNT {
+                self.recent_intervals.pop_front();
+            }
+            let interval = start_time - last_frame;
+            self.recent_intervals.push_back(interval);
+            self.interval_range = Some(match self.interval_range {
+                Some((min, max)) => (min.min(interval), max.max(interval)),
+                None => (interval, interval),
+            });
+        }
+        self.first_frame.get_or_insert(start_time);
+        self.last_frame = Some(start_time);
+        self.frame_count += 1;
+        start_time
+    }
+
+    /// Schedule the next frame once the one started at `start_time` is done
+    pub fn finish_frame(&mut self, start_time: Instant) {
+        let now = Instant::now();
+        let duration = now - start_time;
+        self.next_iteration = next_wakeup(start_time, self.target_duration, now);
+        if duration <= self.target_duration {
+            self.late_streak = 0;
+        } else {
+            self.late_frames += 1;
+            self.late_streak += 1;
+            debug!(
+                "Frame {} took {:.2} ms, {:.2} ms longer than the target duration",
+                self.frame_count,
+                duration.as_secs_f64() * 1000.0,
+                (duration - self.target_duration).as_secs_f64() * 1000.0
+            );
+        }
+
+        // Single late frames happen, but if they keep coming the target is out of reach
+        if self.late_streak as usize >= RECENT_FRAME_COUNT && !self.overrun_reported {
+            self.overrun_reported = true;
+            warn!(
+                "The last {} frames all took longer than {:.2} ms, the target frame rate is too \
+                 high for the captured area",
+                self.late_streak,
+                self.target_duration.as_secs_f64() * 1000.0
+            );
+        }
+
+        if self.frame_count.is_multiple_of(STATS_LOG_INTERVAL) {
+            if let Some(fps) = self.recent_fps() {
+                debug!(
+                    "Frame {}: {:.1} fps over the last {} frames, last frame took {:.2} ms",
+                    self.frame_count,
+                    fps,
+                    self.recent_intervals.len(),
+                    duration.as_secs_f64() * 1000.0
+                );
+            }
+        }
+    }
+
+    /// Whether `max_frames` frames were started, which is never the case for 0
+    pub fn reached(&self, max_frames: u32) -> bool {
+        max_frames != 0 && self.frame_count >= max_frames
+    }
+
+    /// Frame rate achieved over the most recent frames
+    pub fn recent_fps(&self) -> Option<f64> {
+        if self.recent_intervals.is_empty() {
+            return None;
+        }
+
+        let total: Duration = self.recent_intervals.iter().sum();
+        Some(self.recent_intervals.len() as f64 / total.as_secs_f64())
+    }
+
+    /// Average time between the start of two frames
+    fn average_interval(&self) -> Option<Duration> {
+        match (self.first_frame, self.last_frame) {
+            (Some(first), Some(last)) if self.frame_count > 1 => {
+                Some((last - first) / (self.frame_count - 1))
+            }
+            _ => None,
+        }
+    }
+
+    /// Print the achieved frame rate, with the interval range and late frames if `detailed`
+    pub fn print_statistics(&self, detailed: bool) {
+        let interval = match self.average_interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        eprintln!(
+            "Average frame interval: {:.2} ms ({:.1} fps)",
+            interval.as_secs_f64() * 1000.0,
+            1.0 / interval.as_secs_f64()
+        );
+
+        if let (true, Some((min, max))) = (detailed, self.interval_range) {
+            eprintln!(
+                "Frame interval range: {:.2} ms to {:.2} ms",
+                min.as_secs_f64() * 1000.0,
+                max.as_secs_f64() * 1000.0
+            );
+            eprintln!(
+                "Frames over the target duration of {:.2} ms: {} of {}",
+                self.target_duration.as_secs_f64() * 1000.0,
+                self.late_frames,
+                self.frame_count
+            );
+        }
+    }
+}
+
+/// Time the frame after the one started at `start` is due, given that it is `now`
+///
+/// Frames are started `target` apart. A frame that took longer than that is followed by the next
+/// one right away, the lost time is not made up for by starting the frames after it earlier.
+fn next_wakeup(start: Instant, target: Duration, now: Instant) -> Instant {
+    (start + target).max(now)
+}
+
+/// Time spent in the stages of the frame loop
+#[derive(Copy, Clone, Default)]
+pub struct StageTimes {
+    pub capture: Duration,
+    pub upload: Duration,
+    pub draw: Duration,
+}
+
+impl AddAssign for StageTimes {
+    fn add_assign(&mut self, other: StageTimes) {
+        self.capture += other.capture;
+        self.upload += other.upload;
+        self.draw += other.draw;
+    }
+}
+
+impl StageTimes {
+    /// Print the average time per frame of every stage in milliseconds, on a single line
+    pub fn print_average(&self, frame_count: u32) {
+        if frame_count == 0 {
+            return;
+        }
+
+        let average = |total: Duration| total.as_secs_f64() * 1000.0 / f64::from(frame_count);
+        println!(
+            "frames={} capture_ms={:.3} upload_ms={:.3} draw_ms={:.3}",
+            frame_count,
+            average(self.capture),
+            average(self.upload),
+            average(self.draw)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn next_wakeup_under_budget() {
+        let start = Instant::now();
+        let now = start + Duration::from_millis(5);
+        assert_eq!(next_wakeup(start, TARGET, now), start + TARGET);
+    }
+
+    #[test]
+    fn next_wakeup_on_budget() {
+        let start = Instant::now();
+        assert_eq!(next_wakeup(start, TARGET, start + TARGET), start + TARGET);
+    }
+
+    #[test]
+    fn next_wakeup_over_budget() {
+        let start = Instant::now();
+        let now = start + Duration::from_millis(35);
+        assert_eq!(next_wakeup(start, TARGET, now), now);
+    }
+
+    #[test]
+    fn next_wakeup_without_work() {
+        let start = Instant::now();
+        assert_eq!(next_wakeup(start, TARGET, start), start + TARGET);
+    }
+}
@@ -0,0 +1,416 @@
+//! Capturing through the screen cast portal, which is the only way to capture on Wayland
+//!
+//! The portal asks the user which monitor or window to share and hands out its frames as a
+//! PipeWire stream. The stream is received by GStreamer's `pipewiresrc`, so `gst-launch-1.0` and
+//! the PipeWire plugin for GStreamer have to be installed.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::io::Read;
+use std::mem;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+
+use crate::capture::{Frame, FrameBuffers, FrameError, FrameSource};
+use crate::config::{copy_region, Rect, CAPTURED_PACKING};
+
+const PORTAL_DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+
+/// Source types of the portal that can be shared, monitors and windows
+const SOURCE_TYPES: u32 = 1 | 2;
+
+/// Cursor modes of the portal, the cursor is either left out or drawn into the frames
+const CURSOR_HIDDEN: u32 = 1;
+const CURSOR_EMBEDDED: u32 = 2;
+
+/// How long GStreamer may take to deliver the first frame once the user picked what to share
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The screen cast all portal sources share, so the user is only asked once what to share
+static SCREENCAST: Mutex<Option<Arc<Screencast>>> = Mutex::new(None);
+
+/// Whether the portal draws the cursor into the frames, see `show_cursor`
+static CURSOR_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Have the portal draw the cursor into the frames of screen casts started from now on
+///
+/// The cursor can't be drawn by us like on X11, as the position of the shared monitor or window
+/// is not known.
+pub fn show_cursor(shown: bool) {
+    CURSOR_SHOWN.store(shown, Ordering::Relaxed);
+}
+
+/// A screen cast started through the portal, with GStreamer receiving its frames
+struct Screencast {
+    /// The portal closes the session together with the connection
+    _connection: Connection,
+    gstreamer: Child,
+    /// Size of the frames of the stream, which is fixed once the screen cast started
+    size: (u32, u32),
+    received: Arc<ReceivedFrames>,
+}
+
+/// The most recent frame GStreamer wrote, shared with the thread reading them
+#[derive(Default)]
+struct ReceivedFrames {
+    latest: Mutex<LatestFrame>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct LatestFrame {
+    /// Pixels of the frame packed as described by `CAPTURED_PACKING`, with the rows following each
+    /// other without padding
+    data: Vec<u8>,
+    /// Number of frames received so far
+    count: u64,
+    /// Whether GStreamer stopped, e.g. because the user stopped sharing
+    ended: bool,
+}
+
+impl Screencast {
+    /// Ask the portal for a screen cast and start receiving its frames
+    ///
+    /// This shows the dialog of the portal, and only returns once the first frame arrived.
+    fn start() -> Result<Screencast, Box<dyn Error>> {
+        let connection = Connection::session()
+            .map_err(|error| format!("Unable to connect to the D-Bus session bus: {}", error))?;
+        let portal = Proxy::new(
+            &connection,
+            PORTAL_DESTINATION,
+            PORTAL_PATH,
+            "org.freedesktop.portal.ScreenCast",
+        )?;
+
+        let mut options = HashMap::new();
+        options.insert("session_handle_token", Value::from("screensplitter"));
+        let results = request(&connection, "CreateSession", options, |options| {
+            portal.call_method("CreateSession", &(options,))
+        })
+        .map_err(|error| format!("Unable to use the screen cast portal: {}", error))?;
+        // The handle is a string, but some versions of the portal send an object path instead
+        let session = result(&results, "session_handle")?;
+        let session = match OwnedObjectPath::try_from(session.try_clone()?) {
+            Ok(session) => session,
+            Err(_) => OwnedObjectPath::try_from(String::try_from(session)?)?,
+        };
+
+        let available_types = portal
+            .get_property::<u32>("AvailableSourceTypes")
+            .unwrap_or(SOURCE_TYPES);
+        let mut options = HashMap::new();
+        options.insert("types", Value::from(SOURCE_TYPES & available_types));
+        options.insert("multiple", Value::from(false));
+        // Older versions of the portal don't know about cursor modes and always embed the cursor
+        if let Ok(cursor_modes) = portal.get_property::<u32>("AvailableCursorModes") {
+            let cursor_mode = if CURSOR_SHOWN.load(Ordering::Relaxed) {
+                CURSOR_EMBEDDED
+            } else {
+                CURSOR_HIDDEN
+            };
+            if cursor_modes & cursor_mode != 0 {
+                options.insert("cursor_mode", Value::from(cursor_mode));
+            }
+        }
+        request(&connection, "SelectSources", options, |options| {
+            portal.call_method("SelectSources", &(&session, options))
+        })?;
+
+        let results = request(&connection, "Start", HashMap::new(), |options| {
+            portal.call_method("Start", &(&session, "", options))
+        })?;
+        let streams: Vec<(u32, HashMap<String, OwnedValue>)> =
+            result(&results, "streams")?.try_into()?;
+        let (node, properties) = streams
+            .into_iter()
+            .next()
+            .ok_or("Nothing was picked to share")?;
+        let (width, height): (i32, i32) = result(&properties, "size")?.try_into()?;
+        let size = (width.max(1) as u32, height.max(1) as u32);
+        info!(
+            "Sharing PipeWire node {} with a size of {}x{}",
+            node, size.0, size.1
+        );
+
+        let remote: zbus::zvariant::OwnedFd = portal.call(
+            "OpenPipeWireRemote",
+            &(&session, HashMap::<&str, Value>::new()),
+        )?;
+        let (gstreamer, stdout) = start_gstreamer(remote.into(), node, size)?;
+
+        let received = Arc::new(ReceivedFrames::default());
+        let frame_len = size.0 as usize * size.1 as usize * 4;
+        let thread_received = Arc::clone(&received);
+        thread::Builder::new()
+            .name("portal".to_string())
+            .spawn(move || receive_frames(stdout, frame_len, &thread_received))?;
+
+        let screencast = Screencast {
+            _connection: connection,
+            gstreamer,
+            size,
+            received,
+        };
+        let latest = screencast.received.latest.lock().unwrap();
+        let (latest, _) = screencast
+            .received
+            .changed
+            .wait_timeout_while(latest, FIRST_FRAME_TIMEOUT, |latest| {
+                latest.count == 0 && !latest.ended
+            })
+            .unwrap();
+        if latest.count == 0 {
+            return Err(
+                "GStreamer did not receive any frames of the screen cast, make sure the \
+                        PipeWire plugin for GStreamer is installed"
+                    .into(),
+            );
+        }
+        drop(latest);
+
+        Ok(screencast)
+    }
+
+    fn ended(&self) -> bool {
+        self.received.latest.lock().unwrap().ended
+    }
+}
+
+impl Drop for Screencast {
+    fn drop(&mut self) {
+        let _ = self.gstreamer.kill();
+        let _ = self.gstreamer.wait();
+    }
+}
+
+/// Make a request to the portal and wait for its results
+///
+/// The portal answers with a signal on a request object, whose path is derived from the handle
+/// token in the options. It is subscribed to before making the call, so the answer can't be
+/// missed.
+fn request<'a>(
+    connection: &Connection,
+    method: &str,
+    mut options: HashMap<&'a str, Value<'a>>,
+    call: impl FnOnce(HashMap<&'a str, Value<'a>>) -> zbus::Result<zbus::Message>,
+) -> Result<HashMap<String, OwnedValue>, Box<dyn Error>> {
+    let sender = connection
+        .unique_name()
+        .ok_or("The D-Bus connection has no name")?
+        .trim_start_matches(':')
+        .replace('.', "_");
+    let token = format!("screensplitter_{}", method);
+    let path = format!("{}/request/{}/{}", PORTAL_PATH, sender, token);
+    let request = Proxy::new(
+        connection,
+        PORTAL_DESTINATION,
+        path,
+        "org.freedesktop.portal.Request",
+    )?;
+    let mut responses = request.receive_signal("Response")?;
+
+    options.insert("handle_token", Value::from(token));
+    let handle: OwnedObjectPath = call(options)?.body().deserialize()?;
+    if handle.as_str() != request.path().as_str() {
+        // Old versions of the portal ignore the token
+        return Err(format!("The portal answers {} on an unexpected object", method).into());
+    }
+
+    let response = responses
+        .next()
+        .ok_or_else(|| format!("The portal did not answer {}", method))?;
+    let (status, results): (u32, HashMap<String, OwnedValue>) = response.body().deserialize()?;
+    match status {
+        0 => Ok(results),
+        1 => Err("Sharing was cancelled".into()),
+        _ => Err(format!("The portal failed to {}", method).into()),
+    }
+}
+
+/// One of the results of a request to the portal
+fn result(results: &HashMap<String, OwnedValue>, key: &str) -> Result<OwnedValue, Box<dyn Error>> {
+    let value = results
+        .get(key)
+        .ok_or_else(|| format!("The portal did not report the {}", key.replace('_', " ")))?;
+    Ok(value.try_clone()?)
+}
+
+/// Start GStreamer, which writes the frames of the PipeWire node to its standard output
+///
+/// The frames are scaled to `size` if the shared window changes its size, as all frames have to
+/// be of the same size.
+fn start_gstreamer(
+    remote: OwnedFd,
+    node: u32,
+    (width, height): (u32, u32),
+) -> Result<(Child, ChildStdout), Box<dyn Error>> {
+    // The PipeWire remote becomes the first file descriptor after the standard ones
+    const REMOTE_FD: i32 = 3;
+
+    let remote_fd = remote.as_raw_fd();
+    let mut command = Command::new("gst-launch-1.0");
+    command
+        .arg("-q")
+        .arg("pipewiresrc")
+        .arg(format!("fd={}", REMOTE_FD))
+        .arg(format!("path={}", node))
+        .arg("always-copy=true")
+        .args(["!", "videoconvert", "!", "videoscale", "!"])
+        .arg(format!(
+            "video/x-raw,format=BGRx,width={},height={}",
+            width, height
+        ))
+        .args(["!", "fdsink", "fd=1", "sync=false"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 leaves a descriptor that already has the right number alone, including its
+            // close-on-exec flag
+            let result = if remote_fd == REMOTE_FD {
+                libc::fcntl(REMOTE_FD, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(remote_fd, REMOTE_FD)
+            };
+            if result == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let mut gstreamer = command.spawn().map_err(|error| {
+        format!(
+            "Unable to start gst-launch-1.0 for the screen cast: {}",
+            error
+        )
+    })?;
+    // The child has its own copy of the remote now
+    drop(remote);
+    let stdout = gstreamer
+        .stdout
+        .take()
+        .expect("GStreamer was started without standard output");
+    Ok((gstreamer, stdout))
+}
+
+/// Keep the most recent frame GStreamer wrote, until it stops
+///
+/// GStreamer always writes BGRx, which has the padding dropped if the captured pixels are packed.
+fn receive_frames(mut stdout: ChildStdout, frame_len: usize, received: &ReceivedFrames) {
+    let bytes_per_pixel = CAPTURED_PACKING.bytes_per_pixel();
+    let mut buffer = vec![0; frame_len];
+    let mut frame = Vec::new();
+    while stdout.read_exact(&mut buffer).is_ok() {
+        frame.clear();
+        for pixel in buffer.chunks_exact(4) {
+            frame.extend_from_slice(&pixel[..bytes_per_pixel]);
+        }
+
+        let mut latest = received.latest.lock().unwrap();
+        mem::swap(&mut latest.data, &mut frame);
+        latest.count += 1;
+        received.changed.notify_all();
+    }
+
+    info!("The screen cast ended");
+    received.latest.lock().unwrap().ended = true;
+    received.changed.notify_all();
+}
+
+/// Open a source showing the frames of the screen cast
+///
+/// The screen cast is started the first time, later sources get the frames of the same one until
+/// it ends.
+pub fn open() -> Result<PortalSource, Box<dyn Error>> {
+    let mut running = SCREENCAST.lock().unwrap();
+    let screencast = match &*running {
+        Some(screencast) if !screencast.ended() => Arc::clone(screencast),
+        _ => {
+            let screencast = Arc::new(Screencast::start()?);
+            *running = Some(Arc::clone(&screencast));
+            screencast
+        }
+    };
+
+    let (width, height) = screencast.size;
+    Ok(PortalSource {
+        screencast,
+        region: Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        },
+        shown: 0,
+        buffers: FrameBuffers::default(),
+    })
+}
+
+/// The monitor or window the user picked in the dialog of the screen cast portal
+pub struct PortalSource {
+    screencast: Arc<Screencast>,
+    /// Region of the frames that is captured
+    region: Rect,
+    /// Number of the most recent frame that was captured
+    shown: u64,
+    buffers: FrameBuffers,
+}
+
+impl FrameSource for PortalSource {
+    fn size(&self) -> (u32, u32) {
+        (self.region.width, self.region.height)
+    }
+
+    fn crop(&mut self, crop: Rect) -> Result<(), Box<dyn Error>> {
+        self.region = Rect {
+            x: self.region.x + crop.x,
+            y: self.region.y + crop.y,
+            ..crop
+        };
+        Ok(())
+    }
+
+    /// Where the shared monitor or window is is not known, the portal draws the cursor if asked to
+    fn origin(&self) -> Option<(i32, i32)> {
+        None
+    }
+
+    /// The portal only sends frames when something changed, so there often is no new one
+    fn latest_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        let count = self.screencast.received.latest.lock().unwrap().count;
+        if count == self.shown {
+            return None;
+        }
+        Some(self.capture_frame())
+    }
+
+    /// The most recent frame of the screen cast, which may be the same one as the last time
+    fn capture_frame(&mut self) -> Result<Frame, FrameError> {
+        let latest = self.screencast.received.latest.lock().unwrap();
+        if latest.ended {
+            return Err(FrameError::SourceClosed);
+        }
+        self.shown = latest.count;
+
+        let mut data = self.buffers.take();
+        copy_region(&latest.data, self.screencast.size.0, self.region, &mut data);
+
+        Ok(Frame::Composite {
+            data,
+            width: self.region.width,
+            height: self.region.height,
+        })
+    }
+}
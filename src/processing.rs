@@ -0,0 +1,136 @@
+//! Custom processing of the captured frames, next to sharing them
+
+use log::info;
+
+use crate::config::{Settings, CAPTURED_PACKING};
+
+/// Something that looks at every captured frame, e.g. to detect motion or recognize text
+///
+/// The pixels are packed as described by `CAPTURED_PACKING`, in the channel order of the capture
+/// source, see `PixelFormat`, with the rows following each other without padding.
+pub trait FrameProcessor {
+    fn process(&mut self, pixels: &[u8], width: u32, height: u32);
+}
+
+/// The processors enabled in the settings
+pub fn processors(config: &Settings) -> Vec<Box<dyn FrameProcessor>> {
+    let mut processors: Vec<Box<dyn FrameProcessor>> = Vec::new();
+    if config.detect_motion {
+        processors.push(Box::new(MotionDetector::new(config.motion_threshold)));
+    }
+    processors
+}
+
+/// Number of cells per row and column the frame is divided into to detect motion
+const MOTION_CELLS: usize = 16;
+
+/// Difference of the average brightness of a cell, out of 255, that counts as motion
+const CELL_THRESHOLD: f32 = 8.0;
+
+/// Logs when something starts and stops moving in the captured image
+///
+/// The frame is divided into a coarse grid to ignore noise. Motion means that the average
+/// brightness changed in more than `threshold` of the cells since the previous frame.
+pub struct MotionDetector {
+    threshold: f32,
+    previous: Option<Vec<f32>>,
+    moving: bool,
+}
+
+impl MotionDetector {
+    pub fn new(threshold: f32) -> MotionDetector {
+        MotionDetector {
+            threshold,
+            previous: None,
+            moving: false,
+        }
+    }
+
+    /// Average brightness of the cells of a frame, row by row
+    fn cell_averages(pixels: &[u8], width: u32, height: u32) -> Vec<f32> {
+        const PIXEL_SIZE: usize = CAPTURED_PACKING.bytes_per_pixel();
+        let (width, height) = (width as usize, height as usize);
+        let mut sums = vec![0.0; MOTION_CELLS * MOTION_CELLS];
+        let mut counts = vec![0u32; MOTION_CELLS * MOTION_CELLS];
+
+        for (y, row) in pixels
+            .chunks_exact(width * PIXEL_SIZE)
+            .take(height)
+            .enumerate()
+        {
+            let cell_row = y * MOTION_CELLS / height;
+            for (x, pixel) in row.chunks_exact(PIXEL_SIZE).enumerate() {
+                let cell = cell_row * MOTION_CELLS + x * MOTION_CELLS / width;
+                sums[cell] +=
+                    (f32::from(pixel[0]) + f32::from(pixel[1]) + f32::from(pixel[2])) / 3.0;
+                counts[cell] += 1;
+            }
+        }
+
+        sums.iter()
+            .zip(&counts)
+            .map(|(&sum, &count)| if count > 0 { sum / count as f32 } else { 0.0 })
+            .collect()
+    }
+
+    /// Fraction of the cells that changed since the previous frame, 0 for the first one
+    fn changed_fraction(&mut self, pixels: &[u8], width: u32, height: u32) -> f32 {
+        let averages = MotionDetector::cell_averages(pixels, width, height);
+        let changed = match &self.previous {
+            Some(previous) => previous
+                .iter()
+                .zip(&averages)
+                .filter(|(before, after)| (*before - *after).abs() > CELL_THRESHOLD)
+                .count(),
+            None => 0,
+        };
+        self.previous = Some(averages);
+        changed as f32 / (MOTION_CELLS * MOTION_CELLS) as f32
+    }
+}
+
+impl FrameProcessor for MotionDetector {
+    fn process(&mut self, pixels: &[u8], width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let changed = self.changed_fraction(pixels, width, height);
+        let moving = changed > self.threshold;
+        if moving && !self.moving {
+            info!("Motion detected in {:.0}% of the image", changed * 100.0);
+        } else if !moving && self.moving {
+            info!("Motion stopped");
+        }
+        self.moving = moving;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32, value: u8) -> Vec<u8> {
+        vec![value; CAPTURED_PACKING.byte_len((width * height) as usize)]
+    }
+
+    #[test]
+    fn unchanged_frames_have_no_motion() {
+        let mut detector = MotionDetector::new(0.0);
+        let pixels = frame(64, 32, 100);
+        assert_eq!(detector.changed_fraction(&pixels, 64, 32), 0.0);
+        assert_eq!(detector.changed_fraction(&pixels, 64, 32), 0.0);
+    }
+
+    #[test]
+    fn changed_half_is_detected() {
+        let mut detector = MotionDetector::new(0.25);
+        let mut pixels = frame(64, 32, 0);
+        detector.process(&pixels, 64, 32);
+
+        // Brighten the top half of the frame
+        let half = pixels.len() / 2;
+        pixels[..half].fill(255);
+        assert_eq!(detector.changed_fraction(&pixels, 64, 32), 0.5);
+    }
+}
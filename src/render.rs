@@ -0,0 +1,1822 @@
+//! Everything that draws the captured frames with OpenGL: the shaders, the overlays and the
+//! windows they are drawn into
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ffi::CString;
+use std::time::{Duration, Instant};
+
+use ::x11::xlib;
+use glium::backend::glutin::DisplayCreationError;
+use glium::backend::Facade;
+use glium::draw_parameters::TimeElapsedQuery;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::glutin;
+use glium::glutin::dpi::{PhysicalPosition, PhysicalSize, Position};
+use glium::glutin::event_loop::EventLoopWindowTarget;
+use glium::glutin::monitor::MonitorHandle;
+use glium::glutin::platform::unix::x11;
+use glium::glutin::platform::unix::{
+    EventLoopWindowTargetExtUnix, WindowBuilderExtUnix, WindowExtUnix,
+};
+use glium::glutin::window::{Fullscreen, Window, WindowBuilder, WindowId};
+use glium::texture::srgb_texture2d::SrgbTexture2d;
+use glium::texture::{RawImage2d, Texture2d};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, UniformValue, Uniforms};
+use glium::vertex::VertexBufferAny;
+use glium::{Surface, SwapBuffersError};
+use log::{debug, info, warn};
+
+use crate::capture::Frame;
+use crate::config::{
+    capped_size, copy_region, downscale, rotated_size, scaled_size, srgb_to_linear, Corner,
+    OffscreenPlacement, PictureInPicture, PixelFormat, Rect, Settings, Watermark, CAPTURED_PACKING,
+};
+use crate::pacing::StageTimes;
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+
+implement_vertex!(Vertex, position);
+
+/// Start of every shader, replaced if a specific version of OpenGL is asked for
+const GLSL_VERSION_DIRECTIVE: &str = "#version 330";
+
+/// Compile a program, adjusting the shaders to the version of OpenGL asked for
+pub fn compile_program(
+    display: &impl Facade,
+    config: &Settings,
+    vertex_shader: &str,
+    fragment_shader: &str,
+) -> glium::Program {
+    let adjust = |source: &str| match config.gl_version {
+        Some(version) => source.replacen(
+            GLSL_VERSION_DIRECTIVE,
+            &format!("#version {}", version.glsl_version()),
+            1,
+        ),
+        None => source.to_string(),
+    };
+    glium::Program::from_source(
+        display,
+        &adjust(vertex_shader),
+        &adjust(fragment_shader),
+        None,
+    )
+    .expect("Error compiling shaders")
+}
+
+/// Context settings shared by all windows, with the OpenGL version and profile asked for
+pub fn context_builder(config: &Settings) -> glutin::ContextBuilder<'static, glutin::NotCurrent> {
+    let cb = glutin::ContextBuilder::new();
+    let cb = match config.gl_version {
+        Some(version) => cb.with_gl(glutin::GlRequest::Specific(
+            glutin::Api::OpenGl,
+            (version.major, version.minor),
+        )),
+        None => cb,
+    };
+    match config.gl_profile {
+        Some(profile) => cb.with_gl_profile(profile.glutin()),
+        None => cb,
+    }
+}
+
+/// Vertex shader
+///
+/// We use the vertex shader to flip the image which would otherwise be upside down. It also
+/// rotates the image by `quarter_turns` clockwise and mirrors the result if requested, which is
+/// much cheaper than doing it on the CPU. Zooming in maps the window to the part of the texture
+/// around `zoom_center`.
+const VERTEX_SHADER: &str = r"
+    #version 330
+
+    in vec2 position;
+    out vec2 v_tex_coords;
+    out vec2 v_screen_coords;
+    uniform int quarter_turns;
+    uniform bool flip_horizontal;
+    uniform bool flip_vertical;
+    uniform float zoom;
+    uniform vec2 zoom_center;
+
+    void main() {
+        v_screen_coords = position * vec2(0.5, -0.5) + vec2(0.5);
+
+        vec2 tex_coords = v_screen_coords;
+        if (flip_horizontal) {
+            tex_coords.x = 1.0 - tex_coords.x;
+        }
+        if (flip_vertical) {
+            tex_coords.y = 1.0 - tex_coords.y;
+        }
+
+        if (quarter_turns == 1) {
+            tex_coords = vec2(tex_coords.y, 1.0 - tex_coords.x);
+        } else if (quarter_turns == 2) {
+            tex_coords = 1.0 - tex_coords;
+        } else if (quarter_turns == 3) {
+            tex_coords = vec2(1.0 - tex_coords.y, tex_coords.x);
+        }
+
+        v_tex_coords = zoom_center + (tex_coords - 0.5) / zoom;
+        gl_Position = vec4(position, 0.0, 1.0);
+    }
+";
+
+/// Fragment shader
+///
+/// Since the image we usually get from X11 is BGR and we need RGB (blue and red are flipped) we
+/// correct this in the fragment shader. Doing this on the CPU would take too long.
+///
+/// The color adjustments are done here as well and are selected with uniforms, so changing them
+/// does not require compiling a new program. Blurred and masked regions are given in texture
+/// coordinates as left, top, right and bottom, at most `MAX_REGIONS` of each.
+const FRAGMENT_SHADER: &str = r"
+    #version 330
+
+    in vec2 v_tex_coords;
+    in vec2 v_screen_coords;
+    uniform sampler2D tex;
+    uniform bool swap_red_blue;
+    uniform int effect;
+    uniform vec3 white_balance;
+    uniform float brightness;
+    uniform float contrast;
+    uniform float gamma;
+    uniform float sharpen;
+    uniform bool edges;
+    uniform float edge_threshold;
+    uniform bool edges_overlay;
+    uniform vec4 blur_regions[16];
+    uniform int blur_count;
+    uniform vec4 mask_regions[16];
+    uniform int mask_count;
+    uniform vec3 mask_color;
+    uniform bool show_cursor;
+    uniform bool cursor_highlight;
+    uniform float time;
+    uniform vec2 cursor_position;
+    uniform sampler2D cursor;
+    uniform bool show_pip;
+    uniform sampler2D pip;
+    uniform vec4 pip_rect;
+    uniform vec2 pip_border;
+    uniform bool dither;
+    uniform bool chroma_key;
+    uniform vec3 key_color;
+    uniform float key_tolerance;
+
+    // 4x4 Bayer matrix, the order in which the pixels of a block are pushed to the next value
+    const float BAYER[16] = float[16](
+        0.0, 8.0, 2.0, 10.0,
+        12.0, 4.0, 14.0, 6.0,
+        3.0, 11.0, 1.0, 9.0,
+        15.0, 7.0, 13.0, 5.0
+    );
+
+    bool inside(vec4 region) {
+        return all(greaterThanEqual(v_tex_coords, region.xy))
+            && all(lessThan(v_tex_coords, region.zw));
+    }
+
+    // Average of the texels around the given position, in steps of two to cover a wider area
+    vec4 box_blur(vec2 coords) {
+        vec2 spacing = 2.0 / vec2(textureSize(tex, 0));
+        vec4 sum = vec4(0.0);
+        for (int x = -4; x <= 4; x++) {
+            for (int y = -4; y <= 4; y++) {
+                sum += texture(tex, coords + vec2(x, y) * spacing);
+            }
+        }
+        return sum / 81.0;
+    }
+
+    // Unsharp mask, pushes the texel away from the average of its eight neighbors
+    vec4 unsharp_mask(vec2 coords) {
+        vec2 spacing = 1.0 / vec2(textureSize(tex, 0));
+        vec4 center = texture(tex, coords);
+        vec4 sum = vec4(0.0);
+        for (int x = -1; x <= 1; x++) {
+            for (int y = -1; y <= 1; y++) {
+                sum += texture(tex, coords + vec2(x, y) * spacing);
+            }
+        }
+        vec4 neighbors = (sum - center) / 8.0;
+        return clamp(center + sharpen * (center - neighbors), 0.0, 1.0);
+    }
+
+    // Luma of the texel at an offset in texels from the given position
+    float luma(vec2 coords, vec2 offset) {
+        vec3 color = texture(tex, coords + offset / vec2(textureSize(tex, 0))).rgb;
+        return dot(swap_red_blue ? color.bgr : color, vec3(0.2126, 0.7152, 0.0722));
+    }
+
+    // Length of the luma gradient found with the Sobel operator, 1 for black next to white
+    float edge_strength(vec2 coords) {
+        float top_left = luma(coords, vec2(-1.0, -1.0));
+        float top = luma(coords, vec2(0.0, -1.0));
+        float top_right = luma(coords, vec2(1.0, -1.0));
+        float left = luma(coords, vec2(-1.0, 0.0));
+        float right = luma(coords, vec2(1.0, 0.0));
+        float bottom_left = luma(coords, vec2(-1.0, 1.0));
+        float bottom = luma(coords, vec2(0.0, 1.0));
+        float bottom_right = luma(coords, vec2(1.0, 1.0));
+
+        float x = top_right + 2.0 * right + bottom_right - top_left - 2.0 * left - bottom_left;
+        float y = bottom_left + 2.0 * bottom + bottom_right - top_left - 2.0 * top - top_right;
+        return length(vec2(x, y)) / 4.0;
+    }
+
+    // Exact sRGB encoding of a linear color
+    vec3 to_srgb(vec3 linear) {
+        vec3 curve = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+        return mix(linear * 12.92, curve, step(0.0031308, linear));
+    }
+
+    // Ordered dithering, offsets the color by up to half a step of the 8 bit framebuffer. The
+    // framebuffer stores sRGB, so the offset is added to the approximately encoded color.
+    vec3 dithered(vec3 color) {
+        ivec2 cell = ivec2(gl_FragCoord.xy) % 4;
+        float offset = (BAYER[cell.y * 4 + cell.x] + 0.5) / 16.0 - 0.5;
+        vec3 encoded = pow(clamp(color, 0.0, 1.0), vec3(1.0 / 2.2));
+        return pow(clamp(encoded + offset / 255.0, 0.0, 1.0), vec3(2.2));
+    }
+
+    void main() {
+        // Blurred regions are never sharpened, that would bring back some of the hidden details
+        bool blurred = false;
+        for (int i = 0; i < blur_count; i++) {
+            blurred = blurred || inside(blur_regions[i]);
+        }
+        vec4 textureColor;
+        if (blurred) {
+            textureColor = box_blur(v_tex_coords);
+        } else if (sharpen > 0.0) {
+            textureColor = unsharp_mask(v_tex_coords);
+        } else {
+            textureColor = texture(tex, v_tex_coords);
+        }
+        vec3 color = swap_red_blue ? textureColor.bgr : textureColor.rgb;
+
+        // The key is compared with the captured color, as it is seen on the screen. Half a step
+        // of 8 bits is allowed for the rounding of the decoded texture. Everything drawn on top
+        // of the image is opaque again.
+        vec3 key_difference = abs(to_srgb(color) - key_color);
+        bool keyed = chroma_key
+            && max(key_difference.r, max(key_difference.g, key_difference.b))
+                <= key_tolerance + 0.5 / 255.0;
+        float alpha = keyed ? 0.0 : 1.0;
+
+        color *= white_balance;
+        color = (color - 0.5) * contrast + 0.5 + brightness;
+        color = pow(clamp(color, 0.0, 1.0), vec3(1.0 / gamma));
+
+        // Values of the effect enum
+        if (effect == 1) {
+            color = 1.0 - color;
+        } else if (effect == 2) {
+            color = clamp(color, 0.0, 1.0) * mat3(
+                0.393, 0.769, 0.189,
+                0.349, 0.686, 0.168,
+                0.272, 0.534, 0.131
+            );
+        } else if (effect == 3) {
+            // Rec. 709 luma weights
+            color = vec3(dot(color, vec3(0.2126, 0.7152, 0.0722)));
+        }
+
+        // White lines, either on black or on top of the image. Blurred regions stay blurred.
+        if (edges && !blurred) {
+            bool on_edge = edge_strength(v_tex_coords) > edge_threshold;
+            if (on_edge) {
+                color = vec3(1.0);
+            } else if (!edges_overlay) {
+                color = vec3(0.0);
+            }
+        }
+
+        // Masks are not affected by the color adjustments, so they have exactly the chosen color
+        for (int i = 0; i < mask_count; i++) {
+            if (inside(mask_regions[i])) {
+                color = mask_color;
+                alpha = 1.0;
+                break;
+            }
+        }
+
+        // A translucent ring around the hot spot of the cursor, its radius pulsing once per second
+        if (cursor_highlight) {
+            float radius = 24.0 + 4.0 * sin(time * 6.2831853);
+            float distance = length((v_tex_coords - cursor_position) * vec2(textureSize(tex, 0)));
+            if (abs(distance - radius) < 3.0) {
+                color = mix(color, vec3(1.0, 0.8, 0.0), 0.6);
+                alpha = 1.0;
+            }
+        }
+
+        // The cursor is drawn at its size in captured pixels, with its hot spot in the top left
+        if (show_cursor) {
+            vec2 sprite_coords = (v_tex_coords - cursor_position) * vec2(textureSize(tex, 0))
+                / vec2(textureSize(cursor, 0));
+            bool on_sprite = all(greaterThanEqual(sprite_coords, vec2(0.0)))
+                && all(lessThan(sprite_coords, vec2(1.0)));
+            if (on_sprite) {
+                vec4 sprite = texture(cursor, sprite_coords);
+                color = mix(color, sprite.rgb, sprite.a);
+                alpha = max(alpha, sprite.a);
+            }
+        }
+
+        // The inset is placed in window coordinates, so it is not rotated or flipped
+        if (show_pip) {
+            vec2 inset_coords = (v_screen_coords - pip_rect.xy) / (pip_rect.zw - pip_rect.xy);
+            bool on_border = all(greaterThanEqual(v_screen_coords, pip_rect.xy - pip_border))
+                && all(lessThan(v_screen_coords, pip_rect.zw + pip_border));
+            bool on_inset = all(greaterThanEqual(inset_coords, vec2(0.0)))
+                && all(lessThan(inset_coords, vec2(1.0)));
+            if (on_inset) {
+                vec4 inset = texture(pip, inset_coords);
+                color = swap_red_blue ? inset.bgr : inset.rgb;
+                alpha = 1.0;
+            } else if (on_border) {
+                color = vec3(1.0);
+                alpha = 1.0;
+            }
+        }
+
+        if (dither) {
+            color = dithered(color);
+        }
+
+        // Compositors expect premultiplied alpha
+        gl_FragColor = vec4(color * alpha, alpha);
+    }
+";
+
+/// Fragment shader that shows the texture as it is, to find out whether the colors need swapping
+///
+/// Only the geometry of the vertex shader still applies to it.
+const PASSTHROUGH_FRAGMENT_SHADER: &str = r"
+    #version 330
+
+    in vec2 v_tex_coords;
+    uniform sampler2D tex;
+
+    void main() {
+        gl_FragColor = vec4(texture(tex, v_tex_coords).rgb, 1.0);
+    }
+";
+
+/// Sprite drawn at the position of the mouse cursor, `X` is black and `.` is white
+const CURSOR_SPRITE: [&str; 16] = [
+    "X          ",
+    "XX         ",
+    "X.X        ",
+    "X..X       ",
+    "X...X      ",
+    "X....X     ",
+    "X.....X    ",
+    "X......X   ",
+    "X.......X  ",
+    "X........X ",
+    "X.....XXXXX",
+    "X..X..X    ",
+    "X.X X..X   ",
+    "XX  X..X   ",
+    "X    X..X  ",
+    "     XXXX  ",
+];
+
+/// RGBA image of the cursor sprite
+fn cursor_sprite() -> RawImage2d<'static, u8> {
+    let data = CURSOR_SPRITE
+        .iter()
+        .flat_map(|row| row.chars())
+        .flat_map(|pixel| match pixel {
+            'X' => [0, 0, 0, 255],
+            '.' => [255, 255, 255, 255],
+            _ => [0, 0, 0, 0],
+        })
+        .collect();
+    let size = (CURSOR_SPRITE[0].len() as u32, CURSOR_SPRITE.len() as u32);
+    RawImage2d::from_raw_rgba(data, size)
+}
+
+/// Size of the `blur_regions` and `mask_regions` arrays in the fragment shader
+pub const MAX_REGIONS: usize = 16;
+
+/// Radius of the box blur applied to blurred regions, in pixels
+pub const BLUR_RADIUS: usize = 8;
+
+/// Uniforms extended by arrays of `vec4`, which the `uniform!` macro can't express
+struct ArrayUniforms<U> {
+    uniforms: U,
+    arrays: Vec<(&'static str, Vec<[f32; 4]>)>,
+}
+
+impl<U: Uniforms> Uniforms for ArrayUniforms<U> {
+    fn visit_values<'a, F: FnMut(&str, UniformValue<'a>)>(&'a self, mut output: F) {
+        self.uniforms.visit_values(&mut output);
+        for (name, values) in &self.arrays {
+            for (index, &value) in values.iter().enumerate() {
+                output(&format!("{}[{}]", name, index), UniformValue::Vec4(value));
+            }
+        }
+    }
+}
+
+/// Vertex shader for overlays
+///
+/// The overlay is drawn into `rect`, given as left, bottom, right and top in normalized device
+/// coordinates.
+pub const OVERLAY_VERTEX_SHADER: &str = r"
+    #version 330
+
+    in vec2 position;
+    out vec2 v_tex_coords;
+    uniform vec4 rect;
+
+    void main() {
+        v_tex_coords = position * vec2(0.5, -0.5) + vec2(0.5);
+        gl_Position = vec4(mix(rect.xy, rect.zw, position * 0.5 + 0.5), 0.0, 1.0);
+    }
+";
+
+/// Fragment shader for overlays, which are already in the right color format
+const OVERLAY_FRAGMENT_SHADER: &str = r"
+    #version 330
+
+    in vec2 v_tex_coords;
+    uniform sampler2D tex;
+
+    void main() {
+        gl_FragColor = texture(tex, v_tex_coords);
+    }
+";
+
+/// Fragment shader of the region selector, which darkens everything outside of the selection
+///
+/// The selection is given in texture coordinates as x, y, width and height.
+pub const SELECTION_FRAGMENT_SHADER: &str = r"
+    #version 330
+
+    in vec2 v_tex_coords;
+    uniform sampler2D tex;
+    uniform bool swap_red_blue;
+    uniform vec4 selection;
+
+    void main() {
+        vec4 textureColor = texture(tex, v_tex_coords);
+        vec3 color = swap_red_blue ? textureColor.bgr : textureColor.rgb;
+
+        // Distance to the edges of the selection in pixels, negative outside of it
+        vec2 offset = v_tex_coords - selection.xy;
+        vec2 distance = min(offset, selection.zw - offset) * vec2(textureSize(tex, 0));
+        float edge = min(distance.x, distance.y);
+        if (edge < 0.0) {
+            color *= 0.4;
+        } else if (edge < 2.0) {
+            color = vec3(1.0);
+        }
+
+        gl_FragColor = vec4(color, 1.0);
+    }
+";
+
+/// Text drawn on top of the mirrored image
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overlay {
+    pub text: String,
+    pub corner: Corner,
+}
+
+/// Distance of overlays to the edges of the window, in pixels
+pub const OVERLAY_MARGIN: u32 = 8;
+
+/// Size of a pixel of the overlay font, in screen pixels
+const FONT_SCALE: u32 = 2;
+
+/// Rows of the 5x7 pixel glyph for a character, the highest of the 5 bits is the leftmost pixel
+///
+/// Only upper case letters, digits and some punctuation are available. Lower case letters are
+/// drawn in upper case, everything else as a question mark.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        '0' => [
+            0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110,
+        ],
+        '1' => [
+            0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+        '2' => [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111,
+        ],
+        '3' => [
+            0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110,
+        ],
+        '4' => [
+            0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010,
+        ],
+        '5' => [
+            0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110,
+        ],
+        '6' => [
+            0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110,
+        ],
+        '7' => [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000,
+        ],
+        '8' => [
+            0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110,
+        ],
+        '9' => [
+            0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100,
+        ],
+        'A' => [
+            0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+        'B' => [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110,
+        ],
+        'C' => [
+            0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110,
+        ],
+        'D' => [
+            0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100,
+        ],
+        'E' => [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111,
+        ],
+        'F' => [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+        'G' => [
+            0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111,
+        ],
+        'H' => [
+            0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+        'I' => [
+            0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+        'J' => [
+            0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100,
+        ],
+        'K' => [
+            0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001,
+        ],
+        'L' => [
+            0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111,
+        ],
+        'M' => [
+            0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001,
+        ],
+        'N' => [
+            0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001,
+        ],
+        'O' => [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+        'P' => [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+        'Q' => [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101,
+        ],
+        'R' => [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001,
+        ],
+        'S' => [
+            0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110,
+        ],
+        'T' => [
+            0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100,
+        ],
+        'U' => [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+        'V' => [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100,
+        ],
+        'W' => [
+            0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010,
+        ],
+        'X' => [
+            0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001,
+        ],
+        'Y' => [
+            0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100,
+        ],
+        'Z' => [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111,
+        ],
+        ' ' => [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000,
+        ],
+        ':' => [
+            0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000,
+        ],
+        '.' => [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100,
+        ],
+        ',' => [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000,
+        ],
+        '-' => [
+            0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000,
+        ],
+        '+' => [
+            0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000,
+        ],
+        '/' => [
+            0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000,
+        ],
+        '%' => [
+            0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011,
+        ],
+        '(' => [
+            0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010,
+        ],
+        ')' => [
+            0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000,
+        ],
+        '_' => [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111,
+        ],
+        _ => [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100,
+        ],
+    }
+}
+
+/// Render text as white letters on a translucent black box
+///
+/// Returns RGBA pixel data, starting with the top row, together with its width and height.
+pub fn render_text(text: &str) -> (Vec<u8>, u32, u32) {
+    const GLYPH_WIDTH: u32 = 5;
+    const GLYPH_HEIGHT: u32 = 7;
+    const PADDING: u32 = 2;
+
+    let characters: Vec<char> = text.chars().collect();
+    let columns = characters.len() as u32 * (GLYPH_WIDTH + 1) - 1 + 2 * PADDING;
+    let rows = GLYPH_HEIGHT + 2 * PADDING;
+    let (width, height) = (columns * FONT_SCALE, rows * FONT_SCALE);
+
+    let mut data = vec![0; (width * height * 4) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let (column, row) = (x / FONT_SCALE, y / FONT_SCALE);
+            let inside_text = column >= PADDING
+                && (PADDING..PADDING + GLYPH_HEIGHT).contains(&row)
+                && (column - PADDING) % (GLYPH_WIDTH + 1) < GLYPH_WIDTH;
+            let lit = inside_text
+                && characters
+                    .get(((column - PADDING) / (GLYPH_WIDTH + 1)) as usize)
+                    .is_some_and(|&c| {
+                        let bit = GLYPH_WIDTH - 1 - (column - PADDING) % (GLYPH_WIDTH + 1);
+                        glyph(c)[(row - PADDING) as usize] & (1 << bit) != 0
+                    });
+
+            let pixel = if lit {
+                [255, 255, 255, 255]
+            } else {
+                [0, 0, 0, 160]
+            };
+            let start = ((y * width + x) * 4) as usize;
+            data[start..start + 4].copy_from_slice(&pixel);
+        }
+    }
+
+    (data, width, height)
+}
+
+/// Position of an overlay of the given size in a corner of a framebuffer
+///
+/// `offset` moves the overlay towards the middle, so several overlays can share a corner. Returns
+/// left, bottom, right and top in normalized device coordinates.
+fn overlay_rect(
+    (width, height): (u32, u32),
+    framebuffer: (u32, u32),
+    corner: Corner,
+    offset: u32,
+) -> [f32; 4] {
+    let to_x = |pixels: u32| pixels as f32 / framebuffer.0 as f32 * 2.0 - 1.0;
+    let to_y = |pixels: u32| 1.0 - pixels as f32 / framebuffer.1 as f32 * 2.0;
+
+    let (left, top) = corner.place((width, height), framebuffer, OVERLAY_MARGIN);
+    let top = match corner {
+        Corner::TopLeft | Corner::TopRight => top + offset,
+        Corner::BottomLeft | Corner::BottomRight => top.saturating_sub(offset),
+    };
+    [
+        to_x(left),
+        to_y(top + height),
+        to_x(left + width),
+        to_y(top),
+    ]
+}
+
+/// A window mirroring one region of the captured frame
+pub struct Tile {
+    display: glium::Display,
+    pub title: String,
+    vertex_buffer: VertexBufferAny,
+    program: glium::Program,
+    overlay_program: glium::Program,
+    texture: SrgbTexture2d,
+    /// Rendered overlays, only updated when they change
+    overlays: Vec<(Overlay, Texture2d)>,
+    watermarks: Vec<(Corner, Texture2d)>,
+    cursor: SrgbTexture2d,
+    /// Latest frame of the picture-in-picture source
+    pip: SrgbTexture2d,
+    pub region: Rect,
+    /// Reused buffer for the pixels of the region if they have to be copied out of the frame
+    buffer: Vec<u8>,
+    max_texture_dim: Option<u32>,
+    /// Reused buffer for the pixels of the region after scaling them down to the size limit
+    scaled_buffer: Vec<u8>,
+    /// Start of the animation of the cursor highlight
+    created: Instant,
+    /// Set when the OpenGL context was lost, after which the window has to be recreated
+    pub context_lost: bool,
+    gpu_profiler: Option<GpuProfiler>,
+    /// Whether the window is hidden, unlike the preview shown next to offscreen windows
+    pub offscreen: bool,
+}
+
+impl Tile {
+    /// Upload the resources needed to draw the region into the window's GL context
+    fn new(
+        display: glium::Display,
+        config: &Settings,
+        title: String,
+        region: Rect,
+        watermarks: &[WatermarkImage],
+    ) -> Result<Tile, Box<dyn Error>> {
+        let vertex_buffer = quad_vertex_buffer(&display)?;
+        let fragment_shader = if config.color_correction {
+            FRAGMENT_SHADER
+        } else {
+            PASSTHROUGH_FRAGMENT_SHADER
+        };
+        let program = compile_program(&display, config, VERTEX_SHADER, fragment_shader);
+        let overlay_program = compile_program(
+            &display,
+            config,
+            OVERLAY_VERTEX_SHADER,
+            OVERLAY_FRAGMENT_SHADER,
+        );
+
+        let (width, height) = capped_size((region.width, region.height), config.max_texture_dim);
+        let texture =
+            SrgbTexture2d::empty(&display, width, height).expect("Unable to create texture");
+        let cursor =
+            SrgbTexture2d::new(&display, cursor_sprite()).expect("Unable to create texture");
+        let pip = SrgbTexture2d::empty(&display, 1, 1).expect("Unable to create texture");
+        let watermarks = watermarks
+            .iter()
+            .map(|watermark| {
+                let image = RawImage2d::from_raw_rgba(
+                    watermark.image.as_raw().clone(),
+                    watermark.image.dimensions(),
+                );
+                let texture = Texture2d::new(&display, image).expect("Unable to create texture");
+                (watermark.corner, texture)
+            })
+            .collect();
+
+        let gpu_profiler = if config.profile_gpu {
+            GpuProfiler::new(&display)
+        } else {
+            None
+        };
+
+        Ok(Tile {
+            display,
+            title,
+            vertex_buffer,
+            program,
+            overlay_program,
+            texture,
+            overlays: Vec::new(),
+            watermarks,
+            cursor,
+            pip,
+            region,
+            buffer: Vec::new(),
+            max_texture_dim: config.max_texture_dim,
+            scaled_buffer: Vec::new(),
+            created: Instant::now(),
+            context_lost: false,
+            gpu_profiler,
+            offscreen: config.offscreen,
+        })
+    }
+
+    /// Draw the image with everything on top of it into the target, which has the size of the
+    /// window's framebuffer
+    fn render<S: Surface>(
+        &self,
+        target: &mut S,
+        config: &Settings,
+        cursor: Option<(i32, i32)>,
+        query: Option<&TimeElapsedQuery>,
+    ) -> Result<(), glium::DrawError> {
+        let framebuffer = target.get_dimensions();
+        let viewport = if config.keep_aspect {
+            // The framebuffer is sRGB, so the color has to be given in linear RGB
+            let [red, green, blue] = config.border_color.to_linear();
+            target.clear_color(red, green, blue, 1.0);
+            let size = (self.region.width, self.region.height);
+            Some(letterbox(rotated_size(size, config.rotation), framebuffer))
+        } else {
+            None
+        };
+        let drawn_area = viewport.map_or(framebuffer, |viewport| (viewport.width, viewport.height));
+        let (pip_rect, pip_border) = match config.pip {
+            Some(pip) => inset_rect(&pip, config, self.pip.dimensions(), drawn_area),
+            None => ([0.0; 4], [0.0; 2]),
+        };
+        let textures = ImageTextures {
+            image: &self.texture,
+            cursor: &self.cursor,
+            pip: &self.pip,
+        };
+        let time = self.created.elapsed().as_secs_f32();
+        let uniforms = image_uniforms(
+            config,
+            textures,
+            self.region,
+            cursor,
+            (pip_rect, pip_border),
+            time,
+        );
+        target.draw(
+            &self.vertex_buffer,
+            glium::index::NoIndices(glium::index::PrimitiveType::TriangleStrip),
+            &self.program,
+            &uniforms,
+            &glium::DrawParameters {
+                viewport,
+                time_elapsed_query: query,
+                ..Default::default()
+            },
+        )?;
+
+        // Watermarks come first, so the text overlays are stacked next to them
+        let overlays = self
+            .watermarks
+            .iter()
+            .map(|(corner, texture)| (*corner, texture))
+            .chain(
+                self.overlays
+                    .iter()
+                    .map(|(overlay, texture)| (overlay.corner, texture)),
+            );
+        let mut offsets: Vec<(Corner, u32)> = Vec::new();
+        for (corner, texture) in overlays {
+            // Stack the overlays that share a corner
+            let offset = match offsets.iter_mut().find(|(other, _)| *other == corner) {
+                Some((_, offset)) => offset,
+                None => {
+                    offsets.push((corner, 0));
+                    &mut offsets.last_mut().unwrap().1
+                }
+            };
+            let rect = overlay_rect(texture.dimensions(), framebuffer, corner, *offset);
+            *offset += texture.height() + OVERLAY_MARGIN;
+
+            let uniforms = uniform! {
+                tex: texture
+                    .sampled()
+                    .magnify_filter(MagnifySamplerFilter::Nearest)
+                    .minify_filter(MinifySamplerFilter::Nearest),
+                rect: rect,
+            };
+            target.draw(
+                &self.vertex_buffer,
+                glium::index::NoIndices(glium::index::PrimitiveType::TriangleStrip),
+                &self.overlay_program,
+                &uniforms,
+                &glium::DrawParameters {
+                    blend: glium::Blend::alpha_blending(),
+                    ..Default::default()
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// What the window shows at the moment, at the size of its framebuffer
+    ///
+    /// The window itself can't be read back once it was swapped, so the image is drawn once more,
+    /// into a texture.
+    pub fn snapshot(
+        &self,
+        config: &Settings,
+        cursor: Option<(i32, i32)>,
+    ) -> Result<image::RgbImage, Box<dyn Error>> {
+        let (width, height) = self.display.get_framebuffer_dimensions();
+        let output = SrgbTexture2d::empty(&self.display, width, height)?;
+        let mut framebuffer = SimpleFrameBuffer::new(&self.display, &output)?;
+        framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+        self.render(&mut framebuffer, config, cursor, None)?;
+
+        // OpenGL stores the bottom row first
+        let rows: Vec<Vec<(u8, u8, u8, u8)>> = output.read();
+        let pixels = rows
+            .iter()
+            .rev()
+            .flatten()
+            .flat_map(|&(red, green, blue, _)| [red, green, blue])
+            .collect();
+        Ok(image::RgbImage::from_raw(width, height, pixels).expect("Snapshot of the wrong size"))
+    }
+
+    /// Replace the contents of the texture with the region of the captured frame
+    ///
+    /// The texture is only reallocated if the size of the region changed, allocating a new one
+    /// every frame causes a lot of overhead in the driver. Likewise the pixel data is only copied
+    /// if the rows of the region are not already laid out contiguously in the frame.
+    fn upload(&mut self, pixels: &[u8], frame_width: u32) {
+        let data = if self.region.x == 0 && self.region.width == frame_width {
+            let row_length = CAPTURED_PACKING.byte_len(frame_width as usize);
+            let start = self.region.y as usize * row_length;
+            &pixels[start..start + self.region.height as usize * row_length]
+        } else {
+            copy_region(pixels, frame_width, self.region, &mut self.buffer);
+            &self.buffer[..]
+        };
+
+        // Regions beyond the texture size limit are shrunk on the CPU, the GPU never sees them
+        let region_size = (self.region.width, self.region.height);
+        let (width, height) = capped_size(region_size, self.max_texture_dim);
+        let data = if (width, height) != region_size {
+            downscale(data, region_size, (width, height), &mut self.scaled_buffer);
+            &self.scaled_buffer[..]
+        } else {
+            data
+        };
+
+        // The pixels are uploaded as they are, any padding byte taking the place of alpha.
+        // Whether red and blue are swapped is up to the fragment shader.
+        let image = RawImage2d {
+            data: Cow::Borrowed(data),
+            width,
+            height,
+            format: CAPTURED_PACKING.client_format(),
+        };
+        debug_assert_eq!(
+            image.data.len(),
+            (image.width * image.height) as usize * image.format.get_size()
+        );
+
+        if self.texture.width() != image.width || self.texture.height() != image.height {
+            self.texture =
+                SrgbTexture2d::new(&self.display, image).expect("Unable to create texture");
+            return;
+        }
+
+        let rect = glium::Rect {
+            left: 0,
+            bottom: 0,
+            width: image.width,
+            height: image.height,
+        };
+        self.texture.write(rect, image);
+    }
+
+    /// Replace the contents of the picture-in-picture texture with a frame
+    fn upload_pip(&mut self, frame: &Frame) {
+        let (width, height) = frame.dimensions();
+        let image = RawImage2d {
+            data: Cow::Borrowed(frame.as_bytes()),
+            width,
+            height,
+            format: CAPTURED_PACKING.client_format(),
+        };
+        debug_assert_eq!(
+            image.data.len(),
+            (width * height) as usize * image.format.get_size()
+        );
+
+        if self.pip.width() != width || self.pip.height() != height {
+            self.pip = SrgbTexture2d::new(&self.display, image).expect("Unable to create texture");
+            return;
+        }
+
+        let rect = glium::Rect {
+            left: 0,
+            bottom: 0,
+            width,
+            height,
+        };
+        self.pip.write(rect, image);
+    }
+
+    pub fn window_id(&self) -> WindowId {
+        self.display.gl_window().window().id()
+    }
+
+    /// Show a different region of the captured area, resizing the window to match
+    ///
+    /// The texture is reallocated by the next upload.
+    pub fn resize(&mut self, region: Rect, config: &Settings) {
+        self.region = region;
+
+        let size = window_size(region, config);
+        let gl_window = self.display.gl_window();
+        let window = gl_window.window();
+        window.set_inner_size(size);
+        if self.offscreen {
+            hide_offscreen_window(
+                window,
+                config.offscreen_placement,
+                size.width as i32,
+                size.height as i32,
+            );
+        }
+    }
+
+    /// Render the overlays into textures, unless they did not change since the last call
+    fn update_overlays(&mut self, overlays: &[Overlay]) {
+        let unchanged = self.overlays.len() == overlays.len()
+            && self
+                .overlays
+                .iter()
+                .zip(overlays)
+                .all(|((rendered, _), overlay)| rendered == overlay);
+        if unchanged {
+            return;
+        }
+
+        self.overlays = overlays
+            .iter()
+            .map(|overlay| {
+                let (data, width, height) = render_text(&overlay.text);
+                let image = RawImage2d::from_raw_rgba(data, (width, height));
+                let texture =
+                    Texture2d::new(&self.display, image).expect("Unable to create texture");
+                (overlay.clone(), texture)
+            })
+            .collect();
+    }
+}
+
+/// Create a small window the window manager knows about, as a way to control offscreen windows
+///
+/// Offscreen windows are not managed, so they can neither be closed nor get the keyboard focus.
+/// The keys work while this window has the focus instead, and its title lists them. Closing it
+/// ends the program like closing an onscreen window does.
+pub fn create_control_window(
+    el: &EventLoopWindowTarget<()>,
+    config: &Settings,
+) -> Result<Window, Box<dyn Error>> {
+    // Panning does nothing unless zoomed in
+    let keys = config
+        .key_bindings()
+        .filter(|(action, keys)| !keys.0.is_empty() && (config.zoom > 1.0 || !action.pans()))
+        .map(|(action, keys)| {
+            let names: Vec<String> = keys.0.iter().map(|&key| String::from(key)).collect();
+            format!("{}: {}", action.name(), names.join("/"))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let window = WindowBuilder::new()
+        .with_title(format!(
+            "{} - focus this window for the keys ({}) or close it to stop sharing",
+            config.window_title, keys
+        ))
+        .with_inner_size(PhysicalSize::new(480, 40))
+        .with_resizable(false)
+        .build(el)
+        .map_err(|error| format!("Unable to create the control window: {}", error))?;
+    Ok(window)
+}
+
+/// Create the window for one region of the captured area, with everything needed to draw it
+pub fn create_tile(
+    el: &EventLoopWindowTarget<()>,
+    config: &Settings,
+    title: String,
+    region: Rect,
+    fullscreen: Option<MonitorHandle>,
+    watermarks: &[WatermarkImage],
+) -> Result<Tile, Box<dyn Error>> {
+    // The window may differ in size from the captured area, in which case the GPU scales the
+    // image while drawing it
+    let size = window_size(region, config);
+    let display = create_offscreen_window(
+        el,
+        config,
+        &title,
+        size.width as i32,
+        size.height as i32,
+        fullscreen,
+    )?;
+    Tile::new(display, config, title, region, watermarks)
+}
+
+/// Size of the window showing a region of the captured area
+///
+/// The size is given in physical pixels and does not depend on the scale factor of the monitor,
+/// so without `--scale` every captured pixel covers exactly one pixel of the window.
+pub fn window_size(region: Rect, config: &Settings) -> PhysicalSize<u32> {
+    let (width, height) = rotated_size((region.width, region.height), config.rotation);
+    PhysicalSize::new(
+        scaled_size(width, config.scale),
+        scaled_size(height, config.scale),
+    )
+}
+
+/// Decoded image of a watermark, with its opacity already applied to the alpha channel
+pub struct WatermarkImage {
+    image: image::RgbaImage,
+    corner: Corner,
+}
+
+/// Load the images of the watermarks, so they only have to be decoded once
+pub fn load_watermarks(watermarks: &[Watermark]) -> Result<Vec<WatermarkImage>, Box<dyn Error>> {
+    watermarks
+        .iter()
+        .map(|watermark| {
+            let mut image = image::open(&watermark.path)
+                .map_err(|error| {
+                    format!(
+                        "Unable to load the watermark {}: {}",
+                        watermark.path.display(),
+                        error
+                    )
+                })?
+                .into_rgba8();
+            for pixel in image.pixels_mut() {
+                pixel[3] = (f32::from(pixel[3]) * watermark.opacity).round() as u8;
+            }
+            Ok(WatermarkImage {
+                image,
+                corner: watermark.corner,
+            })
+        })
+        .collect()
+}
+
+/// Textures the image of a tile is drawn from
+struct ImageTextures<'a> {
+    image: &'a SrgbTexture2d,
+    cursor: &'a SrgbTexture2d,
+    pip: &'a SrgbTexture2d,
+}
+
+/// Uniforms of `VERTEX_SHADER` and `FRAGMENT_SHADER` for drawing a region of the captured area
+///
+/// The cursor position is relative to the captured area, the inset is given as returned by
+/// `inset_rect`.
+fn image_uniforms<'a>(
+    config: &'a Settings,
+    textures: ImageTextures<'a>,
+    region: Rect,
+    cursor: Option<(i32, i32)>,
+    (pip_rect, pip_border): ([f32; 4], [f32; 2]),
+    time: f32,
+) -> impl Uniforms + 'a {
+    let zoom_center = config.zoom_center(region.width, region.height);
+    let uniforms = uniform! {
+        tex: textures.image
+            .sampled()
+            .magnify_filter(config.filter.magnify())
+            .minify_filter(config.filter.minify()),
+        swap_red_blue: config.pixel_format == PixelFormat::Bgr,
+        effect: config.effect as i32,
+        white_balance: config.color_temp_factors().map(srgb_to_linear),
+        brightness: config.brightness,
+        contrast: config.contrast,
+        gamma: config.gamma,
+        sharpen: config.sharpen,
+        edges: config.edges,
+        edge_threshold: config.edge_threshold,
+        edges_overlay: config.edges_overlay,
+        quarter_turns: (config.rotation / 90) as i32,
+        flip_horizontal: config.flip_horizontal,
+        flip_vertical: config.flip_vertical,
+        zoom: config.zoom,
+        zoom_center: [
+            zoom_center.0 / region.width as f32,
+            zoom_center.1 / region.height as f32,
+        ],
+        blur_count: config.blur.len() as i32,
+        mask_count: config.masks.len() as i32,
+        mask_color: config.mask_color.to_linear(),
+        show_cursor: config.show_cursor && cursor.is_some(),
+        cursor_highlight: config.cursor_highlight && cursor.is_some(),
+        time: time,
+        cursor_position: cursor.map_or([0.0; 2], |(x, y)| {
+            [
+                (x - region.x as i32) as f32 / region.width as f32,
+                (y - region.y as i32) as f32 / region.height as f32,
+            ]
+        }),
+        cursor: textures.cursor
+            .sampled()
+            .magnify_filter(MagnifySamplerFilter::Nearest)
+            .minify_filter(MinifySamplerFilter::Nearest),
+        show_pip: config.pip.is_some(),
+        pip: textures.pip
+            .sampled()
+            .magnify_filter(config.filter.magnify())
+            .minify_filter(config.filter.minify()),
+        pip_rect: pip_rect,
+        pip_border: pip_border,
+        dither: config.dither,
+        chroma_key: config.chroma_key.is_some(),
+        key_color: config.chroma_key.map_or([0.0; 3], |key| {
+            [key.color.red, key.color.green, key.color.blue].map(|channel| f32::from(channel) / 255.0)
+        }),
+        key_tolerance: config.chroma_key.map_or(0.0, |key| key.tolerance),
+    };
+    ArrayUniforms {
+        uniforms,
+        arrays: vec![
+            ("blur_regions", texture_regions(&config.blur, region)),
+            ("mask_regions", texture_regions(&config.masks, region)),
+        ],
+    }
+}
+
+/// Upload the captured frame and draw it into the window of every tile, with the overlays on top
+///
+/// Without a frame the previously uploaded one is drawn again. The cursor position is relative to
+/// the captured area. Returns the time spent uploading and drawing.
+pub fn draw_frame(
+    tiles: &mut [Tile],
+    config: &Settings,
+    captured_frame: Option<&Frame>,
+    overlays: &[Overlay],
+    cursor: Option<(i32, i32)>,
+    pip_frame: Option<&Frame>,
+) -> StageTimes {
+    let mut times = StageTimes::default();
+    for tile in tiles {
+        // Update the texture with the image data of the tile
+        let upload_start = Instant::now();
+        if let Some(captured_frame) = captured_frame {
+            let (width, _) = captured_frame.dimensions();
+            tile.upload(captured_frame.as_bytes(), width);
+        }
+        if let Some(pip_frame) = pip_frame {
+            tile.upload_pip(pip_frame);
+        }
+        tile.update_overlays(overlays);
+        times.upload += upload_start.elapsed();
+
+        // Draw and display the frame
+        let draw_start = Instant::now();
+        let mut target = tile.display.draw();
+        let query = tile
+            .gpu_profiler
+            .as_ref()
+            .and_then(|_| TimeElapsedQuery::new(&tile.display).ok());
+        tile.render(&mut target, config, cursor, query.as_ref())
+            .expect("Unable to execute shader");
+        if let Some(profiler) = &mut tile.gpu_profiler {
+            profiler.collect(query, &tile.title);
+        }
+
+        // A lost context is recovered from by the frame loop
+        match target.finish() {
+            Ok(()) => tile.context_lost = tile.display.is_context_lost(),
+            Err(SwapBuffersError::ContextLost) => tile.context_lost = true,
+            Err(error) => panic!("Buffer swap failed: {}", error),
+        }
+        times.draw += draw_start.elapsed();
+    }
+
+    times
+}
+
+/// Number of frames the GPU time is averaged over before it is printed
+const GPU_REPORT_INTERVAL: u32 = 300;
+
+/// Measures how long the GPU takes to draw the image of a tile, see `Settings::profile_gpu`
+///
+/// The results of the timer queries are only read once they are available, usually a frame or
+/// two later, so the CPU never waits for the GPU.
+struct GpuProfiler {
+    /// Queries of the frames whose results are not available yet, oldest first
+    pending: VecDeque<TimeElapsedQuery>,
+    total: Duration,
+    frames: u32,
+}
+
+impl GpuProfiler {
+    /// A profiler for the context of the display, unless it doesn't support timer queries
+    fn new(display: &glium::Display) -> Option<GpuProfiler> {
+        match TimeElapsedQuery::new(display) {
+            Ok(_) => Some(GpuProfiler {
+                pending: VecDeque::new(),
+                total: Duration::ZERO,
+                frames: 0,
+            }),
+            Err(error) => {
+                warn!("{}, ignoring --profile-gpu", error);
+                None
+            }
+        }
+    }
+
+    /// Queue the query of the latest frame and add up the results that became available
+    fn collect(&mut self, query: Option<TimeElapsedQuery>, title: &str) {
+        self.pending.extend(query);
+        while self.pending.front().is_some_and(|query| query.is_ready()) {
+            let nanoseconds = self.pending.pop_front().unwrap().get();
+            self.total += Duration::from_nanos(nanoseconds.into());
+            self.frames += 1;
+
+            if self.frames == GPU_REPORT_INTERVAL {
+                eprintln!(
+                    "GPU time of \"{}\": {:.3} ms per frame over the last {} frames",
+                    title,
+                    self.total.as_secs_f64() * 1000.0 / f64::from(self.frames),
+                    self.frames
+                );
+                self.total = Duration::ZERO;
+                self.frames = 0;
+            }
+        }
+    }
+}
+
+/// Position of an offscreen window of the given size, outside of all monitors apart from the single
+/// pixel left visible by `OffscreenPlacement::Corner`
+///
+/// Without any monitors to go by, the window is moved to negative coordinates instead.
+fn offscreen_position(
+    monitors: impl Iterator<Item = MonitorHandle>,
+    placement: OffscreenPlacement,
+    width: i32,
+    height: i32,
+) -> PhysicalPosition<i32> {
+    // Top, right and bottom edge of the area covered by all monitors
+    let bounds = monitors
+        .map(|monitor| {
+            let (position, size) = (monitor.position(), monitor.size());
+            (
+                position.y,
+                position.x + size.width as i32,
+                position.y + size.height as i32,
+            )
+        })
+        .fold(
+            None,
+            |union: Option<(i32, i32, i32)>, (top, right, bottom)| {
+                Some(union.map_or((top, right, bottom), |(t, r, b)| {
+                    (t.min(top), r.max(right), b.max(bottom))
+                }))
+            },
+        );
+
+    match (placement, bounds) {
+        (OffscreenPlacement::Outside, Some((top, right, _))) => PhysicalPosition::new(right, top),
+        (OffscreenPlacement::Corner, Some((_, right, bottom))) => {
+            PhysicalPosition::new(right - 1, bottom - 1)
+        }
+        _ => PhysicalPosition::new(-width, -height),
+    }
+}
+
+/// Hide an offscreen window by moving or minimizing it, depending on the placement
+fn hide_offscreen_window(window: &Window, placement: OffscreenPlacement, width: i32, height: i32) {
+    if placement == OffscreenPlacement::Minimized {
+        debug!("Minimizing offscreen window");
+        window.set_minimized(true);
+        return;
+    }
+
+    // Move the window outside the visible screen area
+    let position = offscreen_position(window.available_monitors(), placement, width, height);
+    debug!("Moving offscreen window to {},{}", position.x, position.y);
+    window.set_outer_position(Position::Physical(position));
+}
+
+/// Two triangles covering the whole viewport, drawn as a triangle strip
+pub fn quad_vertex_buffer(display: &impl Facade) -> Result<VertexBufferAny, Box<dyn Error>> {
+    let vertex_buffer = glium::VertexBuffer::new(
+        display,
+        &[
+            Vertex {
+                position: [-1.0, 1.0],
+            },
+            Vertex {
+                position: [1.0, 1.0],
+            },
+            Vertex {
+                position: [-1.0, -1.0],
+            },
+            Vertex {
+                position: [1.0, -1.0],
+            },
+        ],
+    )
+    .map_err(|error| format!("Unable to create the vertex buffer: {}", error))?;
+    Ok(vertex_buffer.into())
+}
+
+/// Convert regions of the shared area into texture coordinates of a tile
+///
+/// The result contains left, top, right and bottom of each region. It may lie outside the tile.
+fn texture_regions(regions: &[Rect], tile: Rect) -> Vec<[f32; 4]> {
+    let to_u = |x: u32| (f64::from(x) - f64::from(tile.x)) as f32 / tile.width as f32;
+    let to_v = |y: u32| (f64::from(y) - f64::from(tile.y)) as f32 / tile.height as f32;
+
+    regions
+        .iter()
+        .map(|region| {
+            [
+                to_u(region.x),
+                to_v(region.y),
+                to_u(region.x.saturating_add(region.width)),
+                to_v(region.y.saturating_add(region.height)),
+            ]
+        })
+        .collect()
+}
+
+/// Position of the picture-in-picture inset and the width of its border
+///
+/// Both are relative to the area the image is drawn into, with the origin in the top left.
+fn inset_rect(
+    pip: &PictureInPicture,
+    config: &Settings,
+    (inset_width, inset_height): (u32, u32),
+    (width, height): (u32, u32),
+) -> ([f32; 4], [f32; 2]) {
+    let scaled_width = (f64::from(width) * pip.size).round() as u32;
+    let scaled_height =
+        (f64::from(scaled_width) * f64::from(inset_height) / f64::from(inset_width)).round() as u32;
+    let (left, top) = pip.corner.place(
+        (scaled_width, scaled_height),
+        (width, height),
+        config.pip_margin,
+    );
+
+    let (width, height) = (width as f32, height as f32);
+    (
+        [
+            left as f32 / width,
+            top as f32 / height,
+            (left + scaled_width) as f32 / width,
+            (top + scaled_height) as f32 / height,
+        ],
+        [
+            config.pip_border as f32 / width,
+            config.pip_border as f32 / height,
+        ],
+    )
+}
+
+/// Largest area of the framebuffer with the aspect ratio of the content, centered
+fn letterbox((width, height): (u32, u32), framebuffer: (u32, u32)) -> glium::Rect {
+    let (fb_width, fb_height) = (u64::from(framebuffer.0), u64::from(framebuffer.1));
+
+    // Compare the aspect ratios without dividing, so there are no rounding issues
+    let (view_width, view_height) = if fb_width * u64::from(height) > fb_height * u64::from(width) {
+        (fb_height * u64::from(width) / u64::from(height), fb_height)
+    } else {
+        (fb_width, fb_width * u64::from(height) / u64::from(width))
+    };
+
+    glium::Rect {
+        left: ((fb_width - view_width) / 2) as u32,
+        bottom: ((fb_height - view_height) / 2) as u32,
+        width: view_width as u32,
+        height: view_height as u32,
+    }
+}
+
+/// This function creates a off screen window.
+///
+/// As it turns out this is **A LOT** harder than you might imagine. Usually window managers ignore
+/// requests to place windows outside the screen area due to the assumption that the request was
+/// made in error. However, we actually really want the window to be not visible by the user.
+///
+/// To achieve this we set the override-redirect option when creating the window. This option
+/// means that the window is not managed by the window manager. It also has the side effect that
+/// the window is not decorated and no task bar entry is created. However, it also causes the
+/// WM_STATE property not to be set by the window manager.
+///
+/// Since chrome uses the WM_STATE property to determine which windows are displayed in the window
+/// selection dialog we need to set this property manually. Setting this property turned out to be
+/// really hard, but thankfully winit allows us to do this after jumping through some hoops.
+pub fn create_offscreen_window(
+    el: &EventLoopWindowTarget<()>,
+    config: &Settings,
+    title: &str,
+    width: i32,
+    height: i32,
+    fullscreen: Option<MonitorHandle>,
+) -> Result<glium::Display, Box<dyn Error>> {
+    // Build a new window. Make sure to set the override_redirect option so the window is not
+    // managed by the window manager.
+    let wb = glutin::window::WindowBuilder::new()
+        .with_title(title)
+        .with_inner_size(PhysicalSize::new(width, height))
+        .with_override_redirect(
+            config.offscreen && config.offscreen_placement != OffscreenPlacement::Minimized,
+        )
+        .with_always_on_top(config.always_on_top && !config.offscreen)
+        // Makes winit pick a visual with an alpha channel, which the chroma key writes to
+        .with_transparent(config.chroma_key.is_some())
+        .with_fullscreen(fullscreen.map(Fullscreen::Borderless));
+
+    // Ask for a context that reports being lost, e.g. after a suspend, so it can be recreated
+    let cb = context_builder(config)
+        .with_vsync(config.vsync)
+        .with_multisampling(config.msaa)
+        .with_gl_robustness(glutin::Robustness::TryRobustLoseContextOnReset);
+    // `glium::Display::new` would need the event loop itself, which is borrowed while it runs
+    let display = cb
+        .build_windowed(wb, el)
+        .map_err(DisplayCreationError::from)
+        .and_then(|gl_window| glium::Display::from_gl_window(gl_window).map_err(From::from))
+        .map_err(|error| {
+            format!(
+                "Unable to create an OpenGL context: {}\n\
+                 Without a GPU, setting LIBGL_ALWAYS_SOFTWARE=1 makes Mesa render in software. \
+                 --v4l2 and --screenshot work without OpenGL.",
+                error
+            )
+        })?;
+    info!(
+        "Created {} window \"{}\" with a size of {}x{}",
+        if config.offscreen {
+            "offscreen"
+        } else {
+            "onscreen"
+        },
+        title,
+        width,
+        height
+    );
+
+    {
+        let gl_window = display.gl_window();
+        let window = gl_window.window();
+
+        if config.offscreen {
+            hide_offscreen_window(window, config.offscreen_placement, width, height);
+        }
+
+        // Set the WM_STATE property so the window is shown in the chrome window selection dialog
+        let xlib = el
+            .xlib_xconnection()
+            .ok_or("Unable to set WM_STATE, the window was not created on an X server")?;
+        let window_id = window
+            .xlib_window()
+            .ok_or("Unable to set WM_STATE, the window is not an X11 window")?;
+        change_property(
+            &xlib,
+            window_id,
+            "WM_STATE",
+            "WM_STATE",
+            &[
+                1 as x11::util::Cardinal, // NormalState
+                0 as x11::util::Cardinal, // None
+            ],
+        )?;
+        debug!("Set WM_STATE on window 0x{:x}", window_id);
+
+        // Compositors read the opacity from this property, with 0xffffffff being fully opaque
+        if !config.offscreen && config.opacity < 1.0 {
+            change_property(
+                &xlib,
+                window_id,
+                "_NET_WM_WINDOW_OPACITY",
+                "CARDINAL",
+                &[(f64::from(config.opacity) * f64::from(u32::MAX)) as x11::util::Cardinal],
+            )?;
+        }
+    }
+
+    Ok(display)
+}
+
+/// Replace a property of an X11 window with a list of cardinals, given the names of the property
+/// and its type
+fn change_property(
+    xlib: &x11::XConnection,
+    window: xlib::Window,
+    property: &str,
+    property_type: &str,
+    values: &[x11::util::Cardinal],
+) -> Result<(), Box<dyn Error>> {
+    let property_atom = xlib.get_atom(CString::new(property)?.as_c_str());
+    let type_atom = xlib.get_atom(CString::new(property_type)?.as_c_str());
+    xlib.change_property(
+        window,
+        property_atom,
+        type_atom,
+        x11::util::PropMode::Replace,
+        values,
+    )
+    .flush()
+    .map_err(|error| {
+        format!(
+            "Unable to set {} on window 0x{:x}: {}",
+            property, window, error
+        )
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letterbox_keeps_the_aspect_ratio() {
+        let view = letterbox((1920, 1080), (1000, 1000));
+        assert_eq!(
+            view,
+            glium::Rect {
+                left: 0,
+                bottom: 219,
+                width: 1000,
+                height: 562,
+            }
+        );
+        let view = letterbox((100, 200), (1000, 1000));
+        assert_eq!((view.left, view.width, view.height), (250, 500, 1000));
+    }
+
+    #[test]
+    fn texture_regions_are_relative_to_the_tile() {
+        let tile = Rect {
+            x: 100,
+            y: 0,
+            width: 200,
+            height: 100,
+        };
+        let region = Rect {
+            x: 150,
+            y: 50,
+            width: 100,
+            height: 25,
+        };
+        assert_eq!(texture_regions(&[region], tile), [[0.25, 0.5, 0.75, 0.75]]);
+    }
+
+    #[test]
+    fn window_size_is_rotated_and_scaled() {
+        let config = Settings {
+            rotation: 90,
+            scale: 0.5,
+            ..Settings::default()
+        };
+        let region = Rect {
+            x: 0,
+            y: 0,
+            width: 200,
+            height: 100,
+        };
+        assert_eq!(window_size(region, &config), PhysicalSize::new(50, 100));
+    }
+
+    #[test]
+    fn text_is_rendered_with_padding() {
+        let (data, width, height) = render_text("42");
+        assert_eq!((width, height), (30, 22));
+        assert_eq!(data.len(), (width * height * 4) as usize);
+        // The padding is the translucent background
+        assert_eq!(data[..4], [0, 0, 0, 160]);
+    }
+
+    #[test]
+    fn overlays_are_stacked_towards_the_middle() {
+        let first = overlay_rect((100, 20), (1000, 500), Corner::TopLeft, 0);
+        let second = overlay_rect((100, 20), (1000, 500), Corner::TopLeft, 30);
+        assert!(second[3] < first[3]);
+        assert_eq!(first[0], second[0]);
+    }
+}
+
+#[cfg(all(test, feature = "gl-tests"))]
+mod gl_tests {
+    use super::*;
+
+    use glium::glutin::event_loop::EventLoop;
+    use glium::glutin::platform::unix::EventLoopExtUnix;
+    use glium::HeadlessRenderer;
+
+    use crate::config::{Effect, Filter};
+
+    /// Size of the synthetic frames, in pixels
+    const SIZE: u32 = 2;
+
+    /// A red pixel in the top left corner and black everywhere else, in the layout X11 captures
+    fn synthetic_frame() -> Vec<u8> {
+        let mut pixels = vec![0; CAPTURED_PACKING.byte_len((SIZE * SIZE) as usize)];
+        pixels[..3].copy_from_slice(&[0, 0, 255]);
+        pixels
+    }
+
+    /// Draw the frame like a tile would and return the pixels of the result, top row first
+    fn render(config: &Settings, pixels: Vec<u8>) -> Vec<Vec<(u8, u8, u8, u8)>> {
+        // Tests run on other threads than the main one
+        let event_loop = EventLoop::<()>::new_any_thread();
+        let context = glutin::ContextBuilder::new()
+            .build_headless(&event_loop, PhysicalSize::new(SIZE, SIZE))
+            .expect("Unable to create a headless OpenGL context");
+        let facade = HeadlessRenderer::new(context).expect("Unable to use the OpenGL context");
+
+        let program = compile_program(&facade, config, VERTEX_SHADER, FRAGMENT_SHADER);
+        let image = RawImage2d {
+            data: Cow::Owned(pixels),
+            width: SIZE,
+            height: SIZE,
+            format: CAPTURED_PACKING.client_format(),
+        };
+        let texture = SrgbTexture2d::new(&facade, image).unwrap();
+        let cursor = SrgbTexture2d::new(&facade, cursor_sprite()).unwrap();
+        let pip = SrgbTexture2d::empty(&facade, 1, 1).unwrap();
+        let output = SrgbTexture2d::empty(&facade, SIZE, SIZE).unwrap();
+
+        let region = Rect {
+            x: 0,
+            y: 0,
+            width: SIZE,
+            height: SIZE,
+        };
+        let textures = ImageTextures {
+            image: &texture,
+            cursor: &cursor,
+            pip: &pip,
+        };
+        let uniforms = image_uniforms(config, textures, region, None, ([0.0; 4], [0.0; 2]), 0.0);
+        let mut framebuffer = SimpleFrameBuffer::new(&facade, &output).unwrap();
+        framebuffer
+            .draw(
+                &quad_vertex_buffer(&facade).unwrap(),
+                glium::index::NoIndices(glium::index::PrimitiveType::TriangleStrip),
+                &program,
+                &uniforms,
+                &Default::default(),
+            )
+            .unwrap();
+
+        // OpenGL returns the bottom row first
+        let mut rows: Vec<Vec<(u8, u8, u8, u8)>> = output.read();
+        rows.reverse();
+        rows
+    }
+
+    fn nearest() -> Settings {
+        Settings {
+            filter: Filter::Nearest,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn red_stays_red_after_swapping_channels() {
+        let rows = render(&nearest(), synthetic_frame());
+        assert_eq!(rows[0][0], (255, 0, 0, 255));
+        assert_eq!(rows[1][1], (0, 0, 0, 255));
+    }
+
+    #[test]
+    fn rgb_frames_are_not_swapped() {
+        let config = Settings {
+            pixel_format: PixelFormat::Rgb,
+            ..nearest()
+        };
+        let rows = render(&config, synthetic_frame());
+        assert_eq!(rows[0][0], (0, 0, 255, 255));
+    }
+
+    #[test]
+    fn flips_move_the_pixel() {
+        let config = Settings {
+            flip_horizontal: true,
+            flip_vertical: true,
+            ..nearest()
+        };
+        let rows = render(&config, synthetic_frame());
+        assert_eq!(rows[1][1], (255, 0, 0, 255));
+        assert_eq!(rows[0][0], (0, 0, 0, 255));
+    }
+
+    #[test]
+    fn invert_turns_red_into_cyan() {
+        let config = Settings {
+            effect: Effect::Invert,
+            ..nearest()
+        };
+        let rows = render(&config, synthetic_frame());
+        assert_eq!(rows[0][0], (0, 255, 255, 255));
+        assert_eq!(rows[1][1], (255, 255, 255, 255));
+    }
+}
@@ -0,0 +1,825 @@
+//! The shared windows and the window to select a region of the screen with
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use glium::glutin;
+use glium::glutin::dpi::{PhysicalPosition, PhysicalSize, Position};
+use glium::glutin::event::{
+    ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent,
+};
+use glium::glutin::event_loop::{ControlFlow, EventLoop};
+use glium::glutin::monitor::MonitorHandle;
+use glium::glutin::platform::desktop::EventLoopExtDesktop;
+use glium::glutin::platform::unix::{
+    EventLoopExtUnix, EventLoopWindowTargetExtUnix, WindowBuilderExtUnix,
+};
+use glium::texture::srgb_texture2d::SrgbTexture2d;
+use glium::texture::RawImage2d;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter};
+use glium::Surface;
+use log::{debug, info, warn};
+
+use crate::capture::{
+    crop_source, frame_hash, open_source, queue_frames, refresh_source, FrameError, FrameSource,
+    SourceRecovery, MAX_CONSECUTIVE_CAPTURE_FAILURES,
+};
+use crate::config::{
+    append_instance_name, expand_title, tile_regions, Action, CaptureTarget, Corner, PixelFormat,
+    Point, Rect, Settings, SourceLostAction, CAPTURED_PACKING, MAX_TARGET_FPS,
+};
+use crate::cpu::clock_text;
+use crate::desktop::{check_session, cursor_position, list_monitors};
+use crate::output::record::{record_frame, Recorder};
+use crate::output::save_snapshot;
+use crate::pacing::{FramePacer, StageTimes};
+use crate::processing::processors;
+use crate::render::{
+    compile_program, context_builder, create_control_window, create_tile, draw_frame,
+    load_watermarks, quad_vertex_buffer, window_size, Overlay, Tile, OVERLAY_VERTEX_SHADER,
+    SELECTION_FRAGMENT_SHADER,
+};
+use crate::TERMINATION_REQUESTED;
+
+/// How much the + and - keys change the target frame rate
+const FPS_STEP: u32 = 5;
+
+/// How long the new target frame rate is shown after changing it with the + and - keys
+const FPS_NOTICE_DURATION: Duration = Duration::from_secs(2);
+
+/// Number of times in a row the windows are recreated after their OpenGL context was lost
+///
+/// Drivers may drop the contexts when the system is suspended. If the new windows keep losing
+/// their contexts right away, something else is broken and we give up.
+const MAX_CONTEXT_RECREATIONS: u32 = 5;
+
+/// Regions of the captured frame shown by the tiles, either the monitors or the cells of the grid
+fn source_regions(
+    config: &Settings,
+    source: &dyn FrameSource,
+) -> Result<Vec<Rect>, Box<dyn Error>> {
+    if config.split_monitors {
+        Ok(source.monitor_regions())
+    } else {
+        let (width, height) = source.size();
+        tile_regions(config.grid, width, height)
+    }
+}
+
+/// Title of the window showing one of several monitors on its own
+///
+/// Templates are expanded as if only that monitor was captured.
+fn monitor_window_title(
+    config: &Settings,
+    template: Option<&String>,
+    monitor_id: usize,
+    region: Rect,
+) -> String {
+    let monitor = CaptureTarget::Monitor(monitor_id);
+    let title = match template {
+        Some(template) => expand_title(
+            template,
+            &monitor,
+            region.width,
+            region.height,
+            config.target_fps,
+        ),
+        None => format!("Monitor {}", monitor.id()),
+    };
+    append_instance_name(&title, config.instance_name.as_deref())
+}
+
+/// Create a window and mirror the image of the capture source
+pub fn display_capture_window(
+    mut config: Settings,
+    target: CaptureTarget,
+) -> Result<(), Box<dyn Error>> {
+    check_session()?;
+
+    if config.always_on_top && config.offscreen && !config.preview {
+        warn!("Offscreen windows can't be kept on top, ignoring --always-on-top");
+    }
+
+    if config.opacity < 1.0 && config.offscreen && !config.preview {
+        warn!("Offscreen windows are never visible, ignoring --opacity");
+    }
+
+    // Create the event loop first. This also installs winit's X error handler, which keeps Xlib
+    // from exiting the process when capturing fails, e.g. because the captured window is gone.
+    // Everything we do relies on X11, so make sure winit does not pick Wayland instead.
+    let mut el = EventLoop::new_x11()
+        .map_err(|error| format!("Unable to connect to the X server: {}", error))?;
+
+    let source = open_source(target.clone())?;
+    let mut target_size = source.size();
+    let source = crop_source(&config, source)?;
+    let mut source = queue_frames(&config, source);
+    let (width, height) = source.size();
+    let window_titles = if config.titles.is_empty() {
+        vec![config.window_title.clone()]
+    } else {
+        config
+            .titles
+            .iter()
+            .map(|template| {
+                let title = expand_title(template, &target, width, height, config.target_fps);
+                append_instance_name(&title, config.instance_name.as_deref())
+            })
+            .collect()
+    };
+
+    let regions = source_regions(&config, source.as_ref())?;
+    // Windows showing a single one of several monitors are named after it instead of numbered
+    let split_monitor_ids = match &target {
+        CaptureTarget::Monitors(monitor_ids) if config.split_monitors => monitor_ids.clone(),
+        _ => Vec::new(),
+    };
+    let watermarks = load_watermarks(&config.watermarks)?;
+
+    let fullscreen = match config.fullscreen {
+        Some(monitor_id) => Some(fullscreen_monitor(&el, monitor_id)?),
+        None => None,
+    };
+
+    // Create one window per title and tile. All of them are fed from the same captured frame
+    let tile_count = regions.len();
+    let mut tiles: Vec<Tile> = window_titles
+        .iter()
+        .enumerate()
+        .flat_map(|window_title| {
+            regions
+                .iter()
+                .enumerate()
+                .map(move |tile| (window_title, tile))
+        })
+        .map(|((title_index, window_title), (index, &region))| {
+            let title = match split_monitor_ids.get(index) {
+                Some(&monitor_id) => monitor_window_title(
+                    &config,
+                    config.titles.get(title_index),
+                    monitor_id,
+                    region,
+                ),
+                None if tile_count > 1 => format!("{} - Tile {}", window_title, index + 1),
+                None => window_title.clone(),
+            };
+            create_tile(&el, &config, title, region, fullscreen.clone(), &watermarks)
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    // The preview shows the same as the windows of the first title, in windows that are not hidden
+    if config.preview {
+        let preview_config = Settings {
+            offscreen: false,
+            ..config.clone()
+        };
+        let previews: Vec<(String, Rect)> = tiles[..tile_count]
+            .iter()
+            .map(|tile| (format!("{} - Preview", tile.title), tile.region))
+            .collect();
+        for (title, region) in previews {
+            tiles.push(create_tile(
+                &el,
+                &preview_config,
+                title,
+                region,
+                None,
+                &watermarks,
+            )?);
+        }
+    }
+
+    // Only kept around so it can be closed, which ends the event loop like any closed window. The
+    // preview can be closed instead.
+    let _control_window = if config.offscreen && !config.preview {
+        Some(create_control_window(&el, &config)?)
+    } else {
+        None
+    };
+
+    let mut recorder = match &config.record {
+        Some(path) => Some(Recorder::start(path, width, height, &config)?),
+        None => None,
+    };
+
+    let mut pacer = FramePacer::new(config.target_fps);
+    let mut consecutive_failures = 0;
+    let mut lost_source: Option<SourceRecovery> = None;
+    let mut processors = processors(&config);
+    let mut pip_source = match config.pip {
+        Some(pip) => Some(open_source(CaptureTarget::Monitor(pip.monitor))?),
+        None => None,
+    };
+
+    let mut last_frame_hash = None;
+    let mut last_pip_hash = None;
+    let mut last_overlays = Vec::new();
+    let mut last_cursor = None;
+    let mut last_pan = config.pan;
+    let mut stage_times = StageTimes::default();
+    let mut context_recreations = 0;
+    let mut paused = false;
+    // When the target frame rate was last changed with the keyboard
+    let mut fps_changed: Option<Instant> = None;
+    // The pointer is only looked up to draw the cursor
+    let xconn = if config.show_cursor || config.cursor_highlight {
+        let xconn = el
+            .xlib_xconnection()
+            .ok_or("Unable to show the cursor, the event loop has no X11 connection")?;
+        Some(xconn)
+    } else {
+        None
+    };
+    el.run_return(|event, window_target, control_flow| {
+        match event {
+            Event::LoopDestroyed => (),
+            Event::NewEvents(_) => {
+                if TERMINATION_REQUESTED.load(Ordering::SeqCst) {
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                if !pacer.is_due() {
+                    *control_flow = ControlFlow::WaitUntil(pacer.wake_up_time());
+                    return;
+                }
+                let start_time = pacer.start_frame();
+
+                if config.refresh_geometry && pacer.frame_count.is_multiple_of(config.target_fps) {
+                    match refresh_source(&config, &target, &mut target_size) {
+                        Ok(Some(new_source)) => {
+                            match source_regions(&config, new_source.as_ref()) {
+                                Ok(regions) => {
+                                    // Every title has a window for each of the regions
+                                    for (tile, &region) in
+                                        tiles.iter_mut().zip(regions.iter().cycle())
+                                    {
+                                        tile.resize(region, &config);
+                                    }
+                                    source = queue_frames(&config, new_source);
+                                    if let Some(stopped) = recorder.take() {
+                                        warn!("The captured area changed, stopping the recording");
+                                        let _ = stopped.finish();
+                                    }
+                                }
+                                Err(error) => warn!("{}", error),
+                            }
+                        }
+                        Ok(None) => (),
+                        Err(error) => warn!("Unable to follow the new geometry: {}", error),
+                    }
+                }
+
+                // Capture the screen. Failed captures are skipped unless they keep failing. While
+                // paused or between the frames that are due for capturing nothing is captured, so
+                // the windows keep showing the last frame
+                let capture_start = Instant::now();
+                let capture_due = (pacer.frame_count - 1).is_multiple_of(config.capture_every);
+                let capture_result = if paused || !capture_due {
+                    None
+                } else if let Some(recovery) = &mut lost_source {
+                    recovery.try_reopen(&config, &target, source.size()).map(
+                        |(new_source, captured_frame)| {
+                            source = queue_frames(&config, new_source);
+                            lost_source = None;
+                            Ok(captured_frame)
+                        },
+                    )
+                } else {
+                    source.latest_frame()
+                };
+                stage_times.capture += capture_start.elapsed();
+                let mut placeholder = None;
+                let captured_frame = match capture_result {
+                    None => None,
+                    Some(Ok(captured_frame)) => {
+                        consecutive_failures = 0;
+                        Some(captured_frame)
+                    }
+                    Some(Err(FrameError::SourceClosed))
+                        if config.on_source_lost == SourceLostAction::Exit =>
+                    {
+                        if !config.quiet {
+                            eprintln!("The captured window was closed");
+                        }
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                    Some(Err(FrameError::SourceClosed)) => {
+                        let (recovery, frame) = SourceRecovery::start(&config, source.size());
+                        lost_source = Some(recovery);
+                        placeholder = frame;
+                        None
+                    }
+                    Some(Err(FrameError::Failed(reason))) => {
+                        consecutive_failures += 1;
+                        warn!(
+                            "Failed to capture frame ({} of {} in a row): {}",
+                            consecutive_failures, MAX_CONSECUTIVE_CAPTURE_FAILURES, reason
+                        );
+
+                        if consecutive_failures >= MAX_CONSECUTIVE_CAPTURE_FAILURES {
+                            if config.on_source_lost == SourceLostAction::Exit {
+                                *control_flow = ControlFlow::Exit;
+                                return;
+                            }
+                            consecutive_failures = 0;
+                            let (recovery, frame) = SourceRecovery::start(&config, source.size());
+                            lost_source = Some(recovery);
+                            placeholder = frame;
+                        }
+                        None
+                    }
+                };
+
+                if let Some(captured_frame) = &captured_frame {
+                    let (width, height) = captured_frame.dimensions();
+                    for processor in &mut processors {
+                        processor.process(captured_frame.as_bytes(), width, height);
+                    }
+                }
+
+                // Only upload the frame if it differs from the last one. Static content would
+                // otherwise keep the CPU and GPU busy for nothing.
+                let new_frame = captured_frame.or(placeholder).filter(|captured_frame| {
+                    let hash = frame_hash(captured_frame.as_bytes());
+                    let changed = config.always_redraw || last_frame_hash != Some(hash);
+                    last_frame_hash = Some(hash);
+                    changed
+                });
+
+                // The inset shares the frame loop, so both images are captured at the same time
+                let new_pip_frame = match pip_source
+                    .as_mut()
+                    .filter(|_| !paused)
+                    .map(|source| source.capture_frame())
+                {
+                    Some(Ok(pip_frame)) => {
+                        let hash = frame_hash(pip_frame.as_bytes());
+                        let changed = config.always_redraw || last_pip_hash != Some(hash);
+                        last_pip_hash = Some(hash);
+                        Some(pip_frame).filter(|_| changed)
+                    }
+                    Some(Err(FrameError::Failed(reason))) => {
+                        warn!("Failed to capture picture-in-picture frame: {}", reason);
+                        None
+                    }
+                    Some(Err(FrameError::SourceClosed)) | None => None,
+                };
+
+                let mut overlays = Vec::new();
+                if config.show_fps {
+                    if let Some(fps) = pacer.recent_fps() {
+                        overlays.push(Overlay {
+                            text: format!("{:.0} FPS", fps),
+                            corner: Corner::TopLeft,
+                        });
+                    }
+                }
+                if let Some(time) = clock_text(&config) {
+                    overlays.push(Overlay {
+                        text: time,
+                        corner: config.clock_corner,
+                    });
+                }
+                if fps_changed.is_some_and(|changed| changed.elapsed() < FPS_NOTICE_DURATION) {
+                    overlays.push(Overlay {
+                        text: format!("Target {} FPS", config.target_fps),
+                        corner: Corner::TopLeft,
+                    });
+                }
+
+                let cursor = xconn
+                    .as_ref()
+                    .and_then(|xconn| cursor_position(xconn))
+                    .zip(source.origin())
+                    .map(|((x, y), (origin_x, origin_y))| (x - origin_x, y - origin_y));
+
+                // The highlight is animated, so it needs to be redrawn every frame
+                if new_frame.is_some()
+                    || new_pip_frame.is_some()
+                    || overlays != last_overlays
+                    || cursor != last_cursor
+                    || config.pan != last_pan
+                    || (config.cursor_highlight && cursor.is_some())
+                {
+                    stage_times += draw_frame(
+                        &mut tiles,
+                        &config,
+                        new_frame.as_ref(),
+                        &overlays,
+                        cursor,
+                        new_pip_frame.as_ref(),
+                    );
+                    last_overlays = overlays;
+                    last_cursor = cursor;
+                    last_pan = config.pan;
+                }
+
+                if tiles.iter().any(|tile| tile.context_lost) {
+                    if context_recreations >= MAX_CONTEXT_RECREATIONS {
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                    context_recreations += 1;
+                    warn!(
+                        "The OpenGL context was lost, recreating the windows ({} of {} in a row)",
+                        context_recreations, MAX_CONTEXT_RECREATIONS
+                    );
+
+                    // The windows are replaced, so a running screen share has to be restarted
+                    for tile in tiles.iter_mut().filter(|tile| tile.context_lost) {
+                        let title = tile.title.clone();
+                        let tile_config = Settings {
+                            offscreen: tile.offscreen,
+                            ..config.clone()
+                        };
+                        match create_tile(
+                            window_target,
+                            &tile_config,
+                            title,
+                            tile.region,
+                            fullscreen.clone(),
+                            &watermarks,
+                        ) {
+                            Ok(new_tile) => *tile = new_tile,
+                            Err(error) => warn!("{}", error),
+                        }
+                    }
+
+                    // The new textures are empty, so the next frames have to be uploaded again
+                    last_frame_hash = None;
+                    last_pip_hash = None;
+                } else {
+                    context_recreations = 0;
+                }
+
+                if let (Some(recorder), Some(new_frame)) = (recorder.as_mut(), &new_frame) {
+                    recorder.update(new_frame.as_bytes(), &config);
+                }
+
+                record_frame(&mut recorder);
+
+                pacer.finish_frame(start_time);
+                *control_flow = if pacer.reached(config.max_frames) {
+                    ControlFlow::Exit
+                } else {
+                    ControlFlow::WaitUntil(pacer.wake_up_time())
+                };
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => *control_flow = ControlFlow::Exit,
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => match config.key_action(key) {
+                Some(Action::Quit) => *control_flow = ControlFlow::Exit,
+                Some(Action::Pause) => {
+                    paused = !paused;
+                    info!("{} mirroring", if paused { "Paused" } else { "Resumed" });
+                }
+                Some(Action::Snapshot) => {
+                    // The textures are empty until the first frame is uploaded
+                    if last_frame_hash.is_none() {
+                        warn!("Nothing was captured yet to take a snapshot of");
+                        return;
+                    }
+
+                    // Every shared window gets a snapshot of its own, the previews are left out
+                    let directory = config.snapshot_dir.as_deref().unwrap();
+                    let shared: Vec<&Tile> = tiles
+                        .iter()
+                        .filter(|tile| tile.offscreen == config.offscreen)
+                        .collect();
+                    for (number, tile) in shared.iter().enumerate() {
+                        let window = Some(number + 1).filter(|_| shared.len() > 1);
+                        let saved = tile
+                            .snapshot(&config, last_cursor)
+                            .and_then(|image| save_snapshot(&image, directory, window));
+                        match saved {
+                            Ok(path) => {
+                                if !config.quiet {
+                                    eprintln!("Saved a snapshot to {}", path.display());
+                                }
+                            }
+                            Err(error) => warn!("Unable to take a snapshot: {}", error),
+                        }
+                    }
+                }
+                Some(action @ (Action::Faster | Action::Slower)) => {
+                    // The recording is written at the frame rate it was started with
+                    if recorder.is_some() {
+                        warn!("The target frame rate can't be changed while recording");
+                        return;
+                    }
+
+                    let target_fps = if action == Action::Faster {
+                        config.target_fps + FPS_STEP
+                    } else {
+                        config.target_fps.saturating_sub(FPS_STEP)
+                    };
+                    config.target_fps = target_fps.clamp(1, MAX_TARGET_FPS);
+                    pacer.set_target_fps(config.target_fps);
+                    source.set_target_fps(config.target_fps);
+                    fps_changed = Some(Instant::now());
+                    info!("Changed the target frame rate to {} fps", config.target_fps);
+                }
+                Some(action) if action.pans() && config.zoom > 1.0 => {
+                    // Move by a tenth of the magnified part, which is drawn on the next frame
+                    let direction = match action {
+                        Action::PanLeft => (-1.0, 0.0),
+                        Action::PanRight => (1.0, 0.0),
+                        Action::PanUp => (0.0, -1.0),
+                        _ => (0.0, 1.0),
+                    };
+                    let (width, height) = source.size();
+                    let (x, y) = config.zoom_center(width, height);
+                    let step = width.min(height) as f32 / config.zoom / 10.0;
+                    config.pan = Some(Point {
+                        x: (x + direction.0 * step).max(0.0) as u32,
+                        y: (y + direction.1 * step).max(0.0) as u32,
+                    });
+                }
+                Some(_) | None => (),
+            },
+            Event::WindowEvent {
+                event: WindowEvent::Resized(_),
+                ..
+            } => {
+                // Redraw right away, glium adjusts the viewport to the new size
+                draw_frame(&mut tiles, &config, None, &last_overlays, last_cursor, None);
+            }
+            Event::WindowEvent {
+                window_id,
+                event:
+                    WindowEvent::ScaleFactorChanged {
+                        scale_factor,
+                        new_inner_size,
+                    },
+            } if fullscreen.is_none() => {
+                // winit resizes the window to keep its logical size, which would scale the image.
+                // Keeping the physical size keeps the preview pixel exact on HiDPI monitors.
+                if let Some(tile) = tiles.iter().find(|tile| tile.window_id() == window_id) {
+                    debug!(
+                        "Scale factor of \"{}\" changed to {}",
+                        tile.title, scale_factor
+                    );
+                    *new_inner_size = window_size(tile.region, &config);
+                }
+            }
+            _ => (),
+        }
+    });
+
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+
+    if config.stats || !config.quiet {
+        pacer.print_statistics(config.stats);
+    }
+    if config.benchmark {
+        stage_times.print_average(pacer.frame_count);
+    }
+
+    if consecutive_failures >= MAX_CONSECUTIVE_CAPTURE_FAILURES {
+        return Err(format!(
+            "Giving up after {} frames in a row failed to be captured",
+            consecutive_failures
+        )
+        .into());
+    }
+
+    if context_recreations >= MAX_CONTEXT_RECREATIONS {
+        return Err(format!(
+            "Giving up after the OpenGL context was lost {} times in a row",
+            context_recreations
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Let the user drag a rectangle over a still image of the capture target to pick the crop region
+///
+/// The image is shown in a window covering the captured area, so it looks like a transparent
+/// overlay on top of the screen. A right click or Escape cancels the selection.
+pub fn select_region(config: &Settings, target: CaptureTarget) -> Result<Rect, Box<dyn Error>> {
+    check_session()?;
+
+    let mut el = EventLoop::<()>::new_x11()
+        .map_err(|error| format!("Unable to connect to the X server: {}", error))?;
+    let mut source = open_source(target)?;
+    let (width, height) = source.size();
+    let frame = match source.capture_frame() {
+        Ok(frame) => frame,
+        Err(FrameError::SourceClosed) => return Err("The captured window was closed".into()),
+        Err(FrameError::Failed(reason)) => {
+            return Err(format!("Failed to capture frame: {}", reason).into())
+        }
+    };
+
+    // Without an origin the captured area can't be covered, so a regular window is shown instead
+    let origin = source.origin();
+    let wb = glutin::window::WindowBuilder::new()
+        .with_title("Select the region to share")
+        .with_inner_size(PhysicalSize::new(width, height))
+        .with_override_redirect(origin.is_some());
+    let display = glium::Display::new(wb, context_builder(config), &el)
+        .map_err(|error| format!("Unable to create an OpenGL context: {}", error))?;
+    if let Some((x, y)) = origin {
+        let gl_window = display.gl_window();
+        gl_window
+            .window()
+            .set_outer_position(Position::Physical(PhysicalPosition::new(x, y)));
+    }
+
+    let vertex_buffer = quad_vertex_buffer(&display)?;
+    let program = compile_program(
+        &display,
+        config,
+        OVERLAY_VERTEX_SHADER,
+        SELECTION_FRAGMENT_SHADER,
+    );
+    let image = RawImage2d {
+        data: Cow::Borrowed(frame.as_bytes()),
+        width,
+        height,
+        format: CAPTURED_PACKING.client_format(),
+    };
+    let texture = SrgbTexture2d::new(&display, image)
+        .map_err(|error| format!("Unable to upload the captured image: {}", error))?;
+
+    // Positions are kept in pixels of the captured area, even if the window got resized
+    let mut cursor = (0, 0);
+    let mut drag_start = None;
+    let mut selection = None;
+    let mut failure = None;
+    el.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::Wait;
+        let dragged = match event {
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => {
+                let size = display.gl_window().window().inner_size();
+                let scale = |position: f64, window_size: u32, size: u32| {
+                    (position * f64::from(size) / f64::from(window_size.max(1)))
+                        .round()
+                        .max(0.0)
+                        .min(f64::from(size)) as u32
+                };
+                cursor = (
+                    scale(position.x, size.width, width),
+                    scale(position.y, size.height, height),
+                );
+                drag_start.is_some()
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::MouseInput {
+                        state,
+                        button: MouseButton::Left,
+                        ..
+                    },
+                ..
+            } => {
+                match (state, drag_start) {
+                    (ElementState::Pressed, _) => drag_start = Some(cursor),
+                    (ElementState::Released, Some(start)) => {
+                        drag_start = None;
+                        let region = Rect::spanning(start, cursor);
+                        if region.width > 0 && region.height > 0 {
+                            selection = Some(region);
+                            *control_flow = ControlFlow::Exit;
+                        }
+                    }
+                    (ElementState::Released, None) => (),
+                }
+                true
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::MouseInput {
+                        state: ElementState::Pressed,
+                        button: MouseButton::Right,
+                        ..
+                    },
+                ..
+            }
+            | Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => {
+                *control_flow = ControlFlow::Exit;
+                false
+            }
+            Event::RedrawRequested(_) => {
+                // Until the user starts dragging the whole image is selected
+                let region = drag_start.map_or(
+                    Rect {
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                    },
+                    |start| Rect::spanning(start, cursor),
+                );
+                let uniforms = uniform! {
+                    rect: [-1.0f32, -1.0, 1.0, 1.0],
+                    tex: texture
+                        .sampled()
+                        .magnify_filter(MagnifySamplerFilter::Nearest)
+                        .minify_filter(MinifySamplerFilter::Linear),
+                    swap_red_blue: config.pixel_format == PixelFormat::Bgr,
+                    selection: [
+                        region.x as f32 / width as f32,
+                        region.y as f32 / height as f32,
+                        region.width as f32 / width as f32,
+                        region.height as f32 / height as f32,
+                    ],
+                };
+
+                // The frame has to be finished even if drawing failed
+                let mut target = display.draw();
+                let drawn = target
+                    .draw(
+                        &vertex_buffer,
+                        glium::index::NoIndices(glium::index::PrimitiveType::TriangleStrip),
+                        &program,
+                        &uniforms,
+                        &Default::default(),
+                    )
+                    .map_err(|error| format!("Unable to draw the selection: {}", error));
+                let shown = target
+                    .finish()
+                    .map_err(|error| format!("Unable to show the selection: {}", error));
+                if let Err(error) = drawn.and(shown) {
+                    failure = Some(error);
+                    *control_flow = ControlFlow::Exit;
+                }
+                false
+            }
+            _ => false,
+        };
+
+        if dragged {
+            display.gl_window().window().request_redraw();
+        }
+    });
+
+    if let Some(error) = failure {
+        return Err(error.into());
+    }
+    selection.ok_or_else(|| "No region was selected".into())
+}
+
+/// Find the winit monitor that belongs to the XRandR monitor with the given ID
+///
+/// winit lists the monitors in a different order than XRandR, so they are matched by position.
+fn fullscreen_monitor(
+    el: &EventLoop<()>,
+    monitor_id: usize,
+) -> Result<MonitorHandle, Box<dyn Error>> {
+    let monitor = list_monitors()?
+        .into_iter()
+        .nth(monitor_id)
+        .ok_or_else(|| format!("Monitor {} does not exist", monitor_id))?;
+    let position = PhysicalPosition::new(monitor.x, monitor.y);
+
+    el.available_monitors()
+        .find(|handle| handle.position() == position)
+        .ok_or_else(|| {
+            format!(
+                "Unable to show a fullscreen window on monitor {}",
+                monitor_id
+            )
+            .into()
+        })
+}
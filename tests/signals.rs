@@ -0,0 +1,35 @@
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The offscreen window has to be torn down properly when the process is asked to terminate
+#[test]
+#[ignore = "needs an X server"]
+fn exits_cleanly_on_sigterm() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_screensplitter"))
+        .args(["0", "--fps", "10"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Unable to start screensplitter");
+
+    thread::sleep(Duration::from_secs(1));
+    assert!(
+        child.try_wait().unwrap().is_none(),
+        "screensplitter exited before it was signaled"
+    );
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while child.try_wait().unwrap().is_none() {
+        assert!(Instant::now() < deadline, "screensplitter did not exit");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    // The statistics are only printed once the event loop returned
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Average frame interval"));
+}
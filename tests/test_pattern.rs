@@ -0,0 +1,46 @@
+use std::process::{Command, Output};
+
+/// Write a few frames of the test pattern to stdout, which works without an X server
+fn raw_frames(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_screensplitter"))
+        .args(["--test-pattern", "--raw-stdout", "--fps", "240"])
+        .args(args)
+        .output()
+        .expect("Unable to start screensplitter");
+    assert!(
+        output.status.success(),
+        "screensplitter failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn frames_are_deterministic() {
+    let args = ["--max-frames", "3", "--crop", "0,0,160,90"];
+    let first = raw_frames(&args);
+    assert_eq!(first.stdout.len(), 3 * 160 * 90 * 3);
+    assert_eq!(first.stdout, raw_frames(&args).stdout);
+}
+
+#[test]
+fn box_moves_every_frame() {
+    let output = raw_frames(&["--max-frames", "2", "--crop", "0,300,320,120"]);
+    let (first, second) = output.stdout.split_at(output.stdout.len() / 2);
+    assert_ne!(first, second);
+}
+
+#[test]
+fn bars_start_with_white() {
+    let output = raw_frames(&["--max-frames", "1", "--crop", "0,0,1,1"]);
+    assert_eq!(output.stdout, [255, 255, 255]);
+}
+
+#[test]
+fn rejected_settings_fail() {
+    let output = Command::new(env!("CARGO_BIN_EXE_screensplitter"))
+        .args(["--test-pattern", "--raw-stdout", "--fps", "0"])
+        .output()
+        .expect("Unable to start screensplitter");
+    assert!(!output.status.success());
+}